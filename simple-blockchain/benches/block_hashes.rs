@@ -0,0 +1,24 @@
+// Benchmark of listing the block hashes by reading full blocks against reading only headers
+use criterion::{criterion_group, criterion_main, Criterion};
+
+mod common;
+
+fn block_hashes(c: &mut Criterion) {
+    // 40 blocks paying OUTPUTS_PER_BLOCK outputs, decoded by full reads only
+    let chain = common::BenchChain::new(40);
+    let bc = &chain.utxo.blockchain;
+
+    c.bench_function("iter, full blocks", |b| {
+        b.iter(|| {
+            bc.iter()
+                .map(|block| block.get_hash())
+                .collect::<Vec<String>>()
+        })
+    });
+    c.bench_function("get_block_hashs", |b| {
+        b.iter(|| bc.get_block_hashs().unwrap())
+    });
+}
+
+criterion_group!(benches, block_hashes);
+criterion_main!(benches);
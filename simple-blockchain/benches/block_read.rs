@@ -0,0 +1,19 @@
+// Benchmark of reading a stored block in full against reading only its header
+use criterion::{criterion_group, criterion_main, Criterion};
+
+mod common;
+
+fn block_read(c: &mut Criterion) {
+    // The tip pays OUTPUTS_PER_BLOCK outputs, decoded by full reads only
+    let chain = common::BenchChain::new(1);
+    let bc = &chain.utxo.blockchain;
+    let hash = bc.get_tip_hash();
+
+    c.bench_function("get_block", |b| b.iter(|| bc.get_block(&hash).unwrap()));
+    c.bench_function("get_block_header", |b| {
+        b.iter(|| bc.get_block_header(&hash).unwrap())
+    });
+}
+
+criterion_group!(benches, block_read);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+// Chains shared by the benchmarks, built in temporary directories with the public API
+use simple_blockchain::blockchain::Blockchain;
+use simple_blockchain::params::ChainParams;
+use simple_blockchain::transaction::{coinbase_reward, Transaction};
+use simple_blockchain::utxoset::UTXOSet;
+use simple_blockchain::wallet::Wallets;
+use tempfile::TempDir;
+
+pub const OUTPUTS_PER_BLOCK: usize = 50; // outputs created by the coinbase of each block
+
+// BenchChain struct holds a chain whose blocks each pay OUTPUTS_PER_BLOCK outputs to one address
+pub struct BenchChain {
+    _dir: TempDir,       // directory of the databases, removed when the chain is dropped
+    pub address: String, // address every output is locked to
+    pub utxo: UTXOSet,   // UTXO set built on the chain
+}
+
+impl BenchChain {
+    // Create a chain with the given number of blocks after the genesis block
+    // blocks: the number of blocks to mine
+    pub fn new(blocks: usize) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_with_path(dir.path()).unwrap();
+        let address = wallets.create_wallet();
+        wallets.save_all().unwrap();
+
+        let bc =
+            Blockchain::create_blockchain_at(dir.path(), address.clone(), ChainParams::devnet())
+                .unwrap();
+        let mut utxo = UTXOSet::new(bc).unwrap();
+
+        for _ in 0..blocks {
+            let height = utxo.blockchain.get_best_height().unwrap() as usize + 1;
+            let value = coinbase_reward(height) / OUTPUTS_PER_BLOCK as i32;
+            let outputs = vec![(address.clone(), value); OUTPUTS_PER_BLOCK];
+            let cbtx = Transaction::new_coinbase_multi(&outputs, String::new(), height, 0).unwrap();
+
+            let block = utxo.blockchain.mine_block(vec![cbtx]).unwrap();
+            utxo.update(&block).unwrap();
+        }
+
+        Self {
+            _dir: dir,
+            address,
+            utxo,
+        }
+    }
+}
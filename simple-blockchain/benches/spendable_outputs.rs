@@ -0,0 +1,40 @@
+// Benchmark of the spendable output selection on the shared handle of the UTXO set
+use criterion::{criterion_group, criterion_main, Criterion};
+use simple_blockchain::utils::pub_key_hash_from_address;
+use simple_blockchain::utxoset::CoinSelection;
+
+mod common;
+
+fn spendable_outputs(c: &mut Criterion) {
+    // 60 blocks of 50 outputs, about 3000 unspent outputs
+    let chain = common::BenchChain::new(60);
+    let pub_key_hash = pub_key_hash_from_address(&chain.address).unwrap();
+
+    c.bench_function("find_spendable_outputs, small amount", |b| {
+        b.iter(|| {
+            chain
+                .utxo
+                .find_spendable_outputs(&pub_key_hash, 10)
+                .unwrap()
+        })
+    });
+    c.bench_function("find_spendable_outputs, whole balance", |b| {
+        b.iter(|| {
+            chain
+                .utxo
+                .find_spendable_outputs_with(&pub_key_hash, 0, CoinSelection::All)
+                .unwrap()
+        })
+    });
+    c.bench_function("find_spendable_outputs, largest first", |b| {
+        b.iter(|| {
+            chain
+                .utxo
+                .find_spendable_outputs_with(&pub_key_hash, 1000, CoinSelection::LargestFirst)
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, spendable_outputs);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+// Benchmark of the UTXO set scans comparing output keys with a precomputed public key hash
+use criterion::{criterion_group, criterion_main, Criterion};
+use simple_blockchain::utils::pub_key_hash_from_address;
+
+mod common;
+
+fn utxo_scan(c: &mut Criterion) {
+    // 40 blocks of 50 outputs, about 2000 unspent outputs
+    let chain = common::BenchChain::new(40);
+    let pub_key_hash = pub_key_hash_from_address(&chain.address).unwrap();
+    let other = vec![0; pub_key_hash.len()];
+
+    c.bench_function("find_utxo, every output matches", |b| {
+        b.iter(|| chain.utxo.find_utxo(&pub_key_hash).unwrap())
+    });
+    c.bench_function("find_utxo, no output matches", |b| {
+        b.iter(|| chain.utxo.find_utxo(&other).unwrap())
+    });
+    c.bench_function("get_balance", |b| {
+        b.iter(|| chain.utxo.get_balance(&chain.address).unwrap())
+    });
+}
+
+criterion_group!(benches, utxo_scan);
+criterion_main!(benches);
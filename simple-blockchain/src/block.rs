@@ -1,24 +1,110 @@
-use crate::{errors::Result, transaction::Transaction};
-use anyhow::Ok;
+use crate::utils::address_from_pub_key_hash;
+use crate::{
+    errors::{BlockchainError, Result},
+    transaction::Transaction,
+};
+use anyhow::{anyhow, Ok};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
+use merkle_cbt::merkle_tree::{Merge, MerkleProof};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::SystemTime;
 
-const TARGET_HEXT: usize = 4; // difficulty of the mining
+pub const TARGET_HEXT: usize = 4; // default difficulty of the mining
 
 // Block struct that holds the data of the block
+// The transactions are kept as the last field so that the header can be
+// deserialized from the serialized block without decoding the transactions
 #[derive(Debug, Clone, Serialize, Deserialize)]
 
 pub struct Block {
     timestamp: u128, // Time of the block creation in milliseconds since the Unix Epoch
+    prev_block_hash: String, // Hash of the previous block
+    hash: String,    // Hash of the block
+    height: u32,     // Height of the block in the blockchain
+    nonce: u32,      // Nonce of the block
+    difficulty: usize, // Number of leading zeros the hash of the block was mined with
+    merkle_root: Vec<u8>, // Merkle root of the transactions, committed to by the hash
+    utxo_root: Vec<u8>, // Commitment to the UTXO set the block leaves, empty if it doesn't commit to one
     transactions: Vec<Transaction>, // Transactions that are included in the block
+}
+
+// BlockHeader struct that holds the data of the block except the transactions
+// It shares the serialized layout of the leading fields of Block
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockHeader {
+    timestamp: u128, // Time of the block creation in milliseconds since the Unix Epoch
     prev_block_hash: String, // Hash of the previous block
     hash: String,    // Hash of the block
     height: u32,     // Height of the block in the blockchain
     nonce: u32,      // Nonce of the block
+    difficulty: usize, // Number of leading zeros the hash of the block was mined with
+    merkle_root: Vec<u8>, // Merkle root of the transactions, committed to by the hash
+    utxo_root: Vec<u8>, // Commitment to the UTXO set the block leaves, empty if it doesn't commit to one
+}
+
+impl BlockHeader {
+    // Getters for the block header struct
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_prev_hash(&self) -> String {
+        self.prev_block_hash.clone()
+    }
+
+    pub fn get_hash(&self) -> String {
+        self.hash.clone()
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get_nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    pub fn get_difficulty(&self) -> usize {
+        self.difficulty
+    }
+
+    pub fn get_merkle_root(&self) -> Vec<u8> {
+        self.merkle_root.clone()
+    }
+
+    pub fn get_utxo_root(&self) -> Vec<u8> {
+        self.utxo_root.clone()
+    }
+
+    // =========================================
+
+    // Deserialize only the header from a serialized block
+    // data: the serialized block
+    pub fn from_block_bytes(data: &[u8]) -> Result<Self> {
+        // The transactions follow the header fields and are left undecoded
+        Ok(bincode::deserialize::<BlockHeader>(data)?)
+    }
+
+    // Check if the stored hash matches the header and satisfies the difficulty
+    // The hash commits to the transactions only through the merkle root, so no transaction is needed
+    pub fn verify_pow(&self) -> Result<bool> {
+        let data = hash_content(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            &self.utxo_root,
+            self.timestamp,
+            self.difficulty,
+            self.nonce,
+        )?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        let hash = hasher.result_str();
+
+        Ok(hash == self.hash && leading_zeros(&hash) >= self.difficulty)
+    }
 }
 
 impl Block {
@@ -27,6 +113,14 @@ impl Block {
         &self.transactions
     }
 
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_nonce(&self) -> u32 {
+        self.nonce
+    }
+
     pub(crate) fn get_prev_hash(&self) -> String {
         self.prev_block_hash.clone()
     }
@@ -38,82 +132,173 @@ impl Block {
         self.height
     }
 
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash.clone(),
+            hash: self.hash.clone(),
+            height: self.height,
+            nonce: self.nonce,
+            difficulty: self.difficulty,
+            merkle_root: self.merkle_root.clone(),
+            utxo_root: self.utxo_root.clone(),
+        }
+    }
+
+    pub fn get_merkle_root(&self) -> Vec<u8> {
+        self.merkle_root.clone()
+    }
+
+    pub fn get_utxo_root(&self) -> Vec<u8> {
+        self.utxo_root.clone()
+    }
+
+    // Get the coinbase transaction, which must be the first and only coinbase of the block
+    pub fn coinbase(&self) -> Result<&Transaction> {
+        coinbase_of(&self.transactions)
+    }
+
     // =========================================
 
     /// Create a genesis block
-    pub fn new_genesis_block(cbtx: Transaction) -> Self {
-        Self::new_block(vec![cbtx], String::new(), 0).unwrap()
+    pub fn new_genesis_block(cbtx: Transaction, difficulty: usize) -> Self {
+        Self::new_block(vec![cbtx], String::new(), 0, difficulty).unwrap()
     }
 
     // Create a new block
     // data: Transactions that are included in the block
     // prev_block_hash: Hash of the previous block
     // height: Height of the block in the blockchain
-    pub fn new_block(data: Vec<Transaction>, prev_block_hash: String, height: u32) -> Result<Self> {
+    // difficulty: Number of leading zeros the hash of the block must have
+    pub fn new_block(
+        data: Vec<Transaction>,
+        prev_block_hash: String,
+        height: u32,
+        difficulty: usize,
+    ) -> Result<Self> {
         // Get the current time in milliseconds since the Unix Epoch
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
 
+        Self::new_block_at(data, prev_block_hash, height, difficulty, timestamp)
+    }
+
+    // Create a new block with the given timestamp
+    // data: Transactions that are included in the block
+    // prev_block_hash: Hash of the previous block
+    // height: Height of the block in the blockchain
+    // difficulty: Number of leading zeros the hash of the block must have
+    // timestamp: Time of the block creation in milliseconds since the Unix Epoch
+    pub fn new_block_at(
+        data: Vec<Transaction>,
+        prev_block_hash: String,
+        height: u32,
+        difficulty: usize,
+        timestamp: u128,
+    ) -> Result<Self> {
+        let mut block = Self::new_template(data, prev_block_hash, height, difficulty, timestamp)?;
+
+        // Run the dummy proof of work algorithm to get the hash of the block
+        block.run_proof_if_work(None)?;
+
+        // Return the block
+        Ok(block)
+    }
+
+    // Create a new block committing to its transactions but not mined yet
+    // Call mine to find its hash
+    // data: Transactions that are included in the block
+    // prev_block_hash: Hash of the previous block
+    // height: Height of the block in the blockchain
+    // difficulty: Number of leading zeros the hash of the block must have
+    // timestamp: Time of the block creation in milliseconds since the Unix Epoch
+    pub fn new_template(
+        data: Vec<Transaction>,
+        prev_block_hash: String,
+        height: u32,
+        difficulty: usize,
+        timestamp: u128,
+    ) -> Result<Self> {
         // Create a new block
         let mut block = Self {
             timestamp,
-            transactions: data,
             prev_block_hash,
             hash: String::new(),
             height,
             nonce: 0, // Set the nonce to 0 for now
+            difficulty,
+            merkle_root: Vec::new(),
+            utxo_root: Vec::new(),
+            transactions: data,
         };
 
-        // Run the dummy proof of work algorithm to get the hash of the block
-        block.run_proof_if_work()?;
+        // Commit to the transactions before mining
+        block.merkle_root = block.hash_transactions()?;
 
-        // Return the block
         Ok(block)
     }
 
+    // Commit a block template to the UTXO set it leaves, before mining it
+    // The commitment is covered by the hash, so a snapshot of the UTXO set can be checked against it
+    // utxo_root: the commitment to the UTXO set once the block is applied, see utxo_commitment
+    pub fn commit_utxos(&mut self, utxo_root: Vec<u8>) {
+        self.utxo_root = utxo_root;
+    }
+
+    // Run the proof of work on a block template until a valid hash is found or the flag is set
+    // A cancelled block is left without a hash and fails with BlockchainError::MiningCancelled
+    // cancel: the flag another thread sets to stop mining, e.g. when a competing block arrives
+    pub fn mine(&mut self, cancel: &AtomicBool) -> Result<()> {
+        self.run_proof_if_work(Some(cancel))
+    }
+
     // Run the dummy proof of work algorithm
-    fn run_proof_if_work(&mut self) -> Result<()> {
+    // cancel: the flag stopping the algorithm when set, None to run until a valid hash is found
+    fn run_proof_if_work(&mut self, cancel: Option<&AtomicBool>) -> Result<()> {
         // Loop until the block is valid
         while !self.validate()? {
+            if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+                return Err(BlockchainError::MiningCancelled.into());
+            }
             self.nonce += 1;
         }
 
         // Get the hash of the block
-        let data = self.serialize_block()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
+        self.hash = self.calculate_hash()?;
 
         // Done mining
         Ok(())
     }
 
     // Hash the data of the block
-    fn serialize_block(&mut self) -> Result<Vec<u8>> {
-        // Serialize the block
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
+    fn serialize_block(&self) -> Result<Vec<u8>> {
+        hash_content(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            &self.utxo_root,
             self.timestamp,
-            TARGET_HEXT,
+            self.difficulty,
             self.nonce,
-        );
-        let bytes = bincode::serialize(&content)?;
-
-        // Return the serialized block
-        Ok(bytes)
+        )
     }
 
-    // Create merkle tree of the transactions and return the root hash
-    pub fn hash_transactions(&mut self) -> Result<Vec<u8>> {
+    // Get the leaves of the merkle tree of the transactions
+    fn transaction_hashes(&self) -> Result<Vec<Vec<u8>>> {
         let mut transactions = Vec::new();
 
         // Get the hash of each transaction and push it to the transactions vector
-        for tx in &mut self.transactions {
+        for tx in &self.transactions {
             transactions.push(tx.hash()?.as_bytes().to_vec());
         }
 
+        Ok(transactions)
+    }
+
+    // Create merkle tree of the transactions and return the root hash
+    pub fn hash_transactions(&self) -> Result<Vec<u8>> {
+        let transactions = self.transaction_hashes()?;
+
         // Create a merkle tree from the transactions
         let tree = CBMT::<Vec<u8>, MergeTx>::build_merkle_tree(&transactions);
 
@@ -121,19 +306,184 @@ impl Block {
         Ok(tree.root())
     }
 
-    // Validate the block
-    fn validate(&mut self) -> Result<bool> {
-        // Get the hash of the block
+    // Build the proof that a transaction is included in the merkle tree of the block
+    // The leaf to verify the proof with is the bytes of the hash of the transaction
+    // txid: the ID of the transaction
+    pub fn merkle_proof(&self, txid: &str) -> Result<MerkleProof<Vec<u8>, MergeTx>> {
+        let index = match self.transactions.iter().position(|tx| tx.id == txid) {
+            Some(i) => i,
+            None => {
+                return Err(anyhow!(
+                    "Transaction {} is not in block {}",
+                    txid,
+                    self.hash
+                ))
+            }
+        };
+
+        let transactions = self.transaction_hashes()?;
+        match CBMT::<Vec<u8>, MergeTx>::build_merkle_proof(&transactions, &[index as u32]) {
+            Some(proof) => Ok(proof),
+            None => Err(anyhow!("Failed to build the merkle proof of {}", txid)),
+        }
+    }
+
+    // Get the sibling hashes proving a transaction is included in the block
+    // The merge is ordered, so the position of the leaf in the tree is returned along with them
+    // txid: the ID of the transaction
+    pub fn merkle_branch(&self, txid: &str) -> Result<(u32, Vec<Vec<u8>>)> {
+        let proof = self.merkle_proof(txid)?;
+
+        Ok((proof.indices()[0], proof.lemmas().to_vec()))
+    }
+
+    // Format the block and its transactions for humans, e.g. for printchain
+    // The receivers and senders are shown as addresses decoded from their public key hashes
+    pub fn describe(&self) -> String {
+        let mut lines = vec![
+            format!("Block {}", self.hash),
+            format!("  height:    {}", self.height),
+            format!("  prev hash: {}", self.prev_block_hash),
+            format!("  nonce:     {}", self.nonce),
+            format!("  timestamp: {}", self.timestamp),
+        ];
+
+        for tx in &self.transactions {
+            lines.push(format!("  Transaction {}", tx.id));
+            for vin in &tx.vin {
+                if vin.vout.is_coinbase() {
+                    lines.push(format!(
+                        "    in:  coinbase ({})",
+                        String::from_utf8_lossy(&vin.pub_key)
+                    ));
+                } else {
+                    lines.push(format!(
+                        "    in:  {}:{} from {}",
+                        vin.txid,
+                        vin.vout,
+                        readable_address(vin.pub_key_hash())
+                    ));
+                }
+            }
+            for (idx, out) in tx.vout.iter().enumerate() {
+                lines.push(format!(
+                    "    out: {}: {} to {}",
+                    idx,
+                    out.value,
+                    readable_address(out.pub_key_hash.clone())
+                ));
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    // Recompute the hash of the block from its content
+    pub fn calculate_hash(&self) -> Result<String> {
         let data = self.serialize_block()?;
         let mut hasher = Sha256::new();
         hasher.input(&data[..]);
 
-        // Check if the prefix of the hash includes 'TARGET_HEXT' zeros (difficulty)
-        let mut target: Vec<u8> = vec![];
-        target.resize(TARGET_HEXT, '0' as u8);
+        Ok(hasher.result_str())
+    }
+
+    // Get the number of leading zeros the proof of work must have
+    pub fn get_difficulty(&self) -> usize {
+        self.difficulty
+    }
+
+    // Check if the stored hash matches the content of the block and satisfies the difficulty
+    // Unlike validate, the hash is compared to the stored one, so it can check received blocks
+    pub fn verify_pow(&self) -> Result<bool> {
+        let hash = self.calculate_hash()?;
+
+        Ok(hash == self.hash && leading_zeros(&hash) >= self.difficulty)
+    }
+
+    // Check if the merkle root committed in the header matches the transactions of the block
+    // The hash only covers the stored root, so swapped transactions are caught here
+    pub fn verify_merkle_root(&self) -> Result<bool> {
+        Ok(self.hash_transactions()? == self.merkle_root)
+    }
+
+    // Validate the block
+    fn validate(&self) -> Result<bool> {
+        // Check if the prefix of the hash includes 'difficulty' zeros
+        Ok(leading_zeros(&self.calculate_hash()?) >= self.difficulty)
+    }
+}
+
+// Serialize the fields of a block covered by its hash
+// Blocks and headers share it, so a header alone can be checked
+fn hash_content(
+    prev_block_hash: &str,
+    merkle_root: &[u8],
+    utxo_root: &[u8],
+    timestamp: u128,
+    difficulty: usize,
+    nonce: u32,
+) -> Result<Vec<u8>> {
+    let content = (
+        prev_block_hash,
+        merkle_root,
+        utxo_root,
+        timestamp,
+        difficulty,
+        nonce,
+    );
+    Ok(bincode::serialize(&content)?)
+}
+
+// Encode a public key hash as an address, or show it as hex if it can't be encoded
+fn readable_address(pub_key_hash: Vec<u8>) -> String {
+    address_from_pub_key_hash(pub_key_hash.clone())
+        .unwrap_or_else(|_| pub_key_hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
 
-        Ok(hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(target)?)
+// Get the coinbase transaction of the transactions of a block
+// A block has exactly one coinbase transaction, placed before all others
+// transactions: the transactions of the block
+pub fn coinbase_of(transactions: &[Transaction]) -> Result<&Transaction> {
+    let coinbase = match transactions.first() {
+        Some(tx) if tx.is_coinbase() => tx,
+        _ => {
+            return Err(anyhow!(
+                "the first transaction of a block must be its coinbase"
+            ))
+        }
+    };
+    if transactions[1..].iter().any(|tx| tx.is_coinbase()) {
+        return Err(anyhow!(
+            "a block must have exactly one coinbase transaction"
+        ));
     }
+    Ok(coinbase)
+}
+
+// Count the leading zeros of a hex encoded hash
+pub fn leading_zeros(hash: &str) -> usize {
+    hash.chars().take_while(|c| *c == '0').count()
+}
+
+// Get the work a block mined with the given difficulty stands for
+// Each leading zero hex digit makes a valid hash 16 times harder to find
+pub fn block_work(difficulty: usize) -> u128 {
+    u32::try_from(difficulty)
+        .ok()
+        .and_then(|d| d.checked_mul(4))
+        .and_then(|shift| 1u128.checked_shl(shift))
+        .unwrap_or(u128::MAX)
+}
+
+// Check a merkle branch built by Block::merkle_branch against a merkle root
+// It only needs the header of the block, so light clients can confirm a payment
+// root: the merkle root committed in the block header
+// tx_hash: the hash of the transaction
+// index: the position of the leaf in the tree
+// proof: the sibling hashes from the leaf up to the root
+pub fn verify_merkle_proof(root: &[u8], tx_hash: &[u8], index: u32, proof: &[Vec<u8>]) -> bool {
+    MerkleProof::<Vec<u8>, MergeTx>::new(vec![index], proof.to_vec())
+        .verify(&root.to_vec(), &[tx_hash.to_vec()])
 }
 
 // Implement the merge trait for the merkle tree
@@ -160,3 +510,185 @@ impl Merge for MergeTx {
         res.to_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_merkle_proof, Block, BlockHeader};
+    use crate::errors::BlockchainError;
+    use crate::test_utils::TestChain;
+    use bincode::serialize;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn header_read_matches_the_block() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let to = chain.address();
+        let tx = chain.send(&chain.miner, &to, 10);
+        let block = chain.mine(vec![tx]);
+
+        let header = BlockHeader::from_block_bytes(&serialize(&block).unwrap()).unwrap();
+        assert_eq!(
+            header,
+            chain.bc().get_block_header(&block.get_hash()).unwrap()
+        );
+        assert_eq!(header.get_timestamp(), block.get_timestamp());
+        assert_eq!(header.get_prev_hash(), block.get_prev_hash());
+        assert_eq!(header.get_hash(), block.get_hash());
+        assert_eq!(header.get_height(), block.get_height());
+        assert_eq!(header.get_nonce(), block.get_nonce());
+        assert_eq!(header.get_difficulty(), block.get_difficulty());
+        assert_eq!(header.get_merkle_root(), block.get_merkle_root());
+        assert_eq!(header.get_utxo_root(), block.get_utxo_root());
+        assert!(header.verify_pow().unwrap());
+    }
+
+    #[test]
+    fn rejects_blocks_whose_hash_misses_their_content() {
+        let mut chain = TestChain::new();
+        let block = Block::new_block(
+            vec![chain.coinbase(&chain.miner)],
+            chain.bc().get_tip_hash(),
+            chain.next_height() as u32,
+            chain.bc().get_params().difficulty,
+        )
+        .unwrap();
+        assert!(block.verify_pow().unwrap());
+
+        // Flip a bit of the timestamp, the stored hash no longer matches
+        let mut data = serialize(&block).unwrap();
+        data[0] ^= 1;
+        let tampered: Block = bincode::deserialize(&data).unwrap();
+        assert!(!tampered.verify_pow().unwrap());
+
+        let bc = &mut chain.utxo.blockchain;
+        assert!(bc.add_block(tampered).is_err());
+        assert!(!bc.has_block(&block.get_hash()).unwrap());
+        assert!(bc.add_block(block).is_ok());
+    }
+
+    #[test]
+    fn rejects_blocks_whose_transactions_were_tampered_after_mining() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let coinbase = chain.coinbase(&chain.miner);
+        let mut block = chain.bc().new_block_template(vec![coinbase, tx]).unwrap();
+        block.mine(&AtomicBool::new(false)).unwrap();
+        assert!(block.verify_merkle_root().unwrap());
+
+        // Drop the payment, the header and its proof of work are left untouched
+        let mut tampered = block.clone();
+        tampered.transactions.truncate(1);
+        assert!(tampered.verify_pow().unwrap());
+        assert!(!tampered.verify_merkle_root().unwrap());
+
+        let bc = &mut chain.utxo.blockchain;
+        let err = bc.add_block(tampered).unwrap_err();
+        assert!(err.to_string().contains("Merkle root"), "{}", err);
+        assert!(!bc.has_block(&block.get_hash()).unwrap());
+        assert!(bc.add_block(block).is_ok());
+    }
+
+    #[test]
+    fn rejects_received_blocks_with_a_mutated_transaction() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let coinbase = chain.coinbase(&chain.miner);
+        let mut block = chain.bc().new_block_template(vec![coinbase, tx]).unwrap();
+        block.mine(&AtomicBool::new(false)).unwrap();
+
+        // The block goes through the wire, then the payment is raised on the way
+        let mut received: Block = bincode::deserialize(&serialize(&block).unwrap()).unwrap();
+        received.transactions[1].vout[0].value += 1;
+        assert_eq!(received.get_hash(), block.get_hash());
+
+        let bc = &mut chain.utxo.blockchain;
+        let err = bc.add_block(received).unwrap_err();
+        assert!(err.to_string().contains("Merkle root"), "{}", err);
+        assert!(!bc.has_block(&block.get_hash()).unwrap());
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 0);
+    }
+
+    #[test]
+    fn proves_the_inclusion_of_each_transaction() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        chain.mine_empty(4);
+        let alice = chain.address();
+
+        // Blocks of 1, 2 and 5 transactions, the coinbase included
+        for count in [1, 2, 5] {
+            let mut txs = vec![chain.coinbase(&chain.miner)];
+            for height in 0..count - 1 {
+                let mature = chain.bc().get_block_by_height(height).unwrap();
+                let coinbase = &mature.get_transactions()[0];
+                txs.push(chain.spend(coinbase, 0, &chain.miner, &alice, 30));
+            }
+            let mut block = chain.bc().new_block_template(txs).unwrap();
+            block.mine(&AtomicBool::new(false)).unwrap();
+            let root = block.get_merkle_root();
+            let txs = block.get_transactions();
+            assert_eq!(txs.len(), count);
+
+            for (i, tx) in txs.iter().enumerate() {
+                let tx_hash = tx.hash().unwrap().into_bytes();
+                let (index, proof) = block.merkle_branch(&tx.id).unwrap();
+                assert!(verify_merkle_proof(&root, &tx_hash, index, &proof));
+
+                // The proof doesn't hold for another transaction
+                let other = txs[(i + 1) % txs.len()].hash().unwrap().into_bytes();
+                assert_eq!(
+                    verify_merkle_proof(&root, &other, index, &proof),
+                    count == 1
+                );
+            }
+        }
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        assert!(genesis.merkle_branch("unknown").is_err());
+    }
+
+    #[test]
+    fn stops_mining_promptly_once_cancelled() {
+        let chain = TestChain::new();
+        // No hash has 64 leading zero digits, mining only ends when cancelled
+        let template = || {
+            Block::new_template(
+                vec![chain.coinbase(&chain.miner)],
+                chain.bc().get_tip_hash(),
+                chain.next_height() as u32,
+                64,
+                0,
+            )
+            .unwrap()
+        };
+        let mut block = template();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let setter = cancel.clone();
+        let start = Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            setter.store(true, Ordering::Relaxed);
+        });
+        let err = block.mine(&cancel).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(BlockchainError::MiningCancelled)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(block.get_hash().is_empty());
+        assert!(!block.verify_pow().unwrap());
+
+        // A flag set beforehand stops mining right away
+        let mut block = template();
+        assert!(block.mine(&AtomicBool::new(true)).is_err());
+        assert_eq!(block.get_nonce(), 0);
+    }
+}
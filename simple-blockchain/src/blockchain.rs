@@ -1,27 +1,190 @@
 use anyhow::anyhow;
 use bincode::{deserialize, serialize};
-use std::collections::HashMap;
-use std::path::Path;
+use bitcoincash_addr::Address;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::time::SystemTime;
 
-use crate::block::Block;
-use crate::errors::Result;
-use crate::transaction::{TXOutputs, Transaction};
+use crate::block::{block_work, coinbase_of, Block, BlockHeader};
+use crate::errors::{BlockchainError, Result, StorageError};
+use crate::params::ChainParams;
+use crate::transaction::{coinbase_reward, OutPoint, TXOutputs, Transaction, COINBASE_MATURITY};
+use crate::utils::address_from_pub_key_hash;
+use crate::utxoset::{connect_block, utxo_commitment, utxo_commitment_after};
+use crate::wallet::Signer;
 
+pub const DATA_DIR: &str = "data"; // default base directory of the databases
+pub const MEDIAN_TIME_SPAN: usize = 11; // number of blocks the median time past is taken over
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000; // how far in milliseconds a block may be ahead of the local clock
+pub const RETARGET_INTERVAL: u32 = 10; // number of blocks between two difficulty adjustments
+const REORGS_TREE: &str = "reorgs"; // sled tree holding the reorganization events
+const HEIGHTS_TREE: &str = "heights"; // sled tree mapping the height of each block of the chain to its hash
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks"; // genesis block data
 
+// VerificationLevel enum decides how thoroughly imported blocks are checked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerificationLevel {
+    Full,            // Verify the consensus rules and the transactions of every block
+    PowOnly,         // Verify the consensus rules, skip the signatures
+    TrustedNoVerify, // Verify the links and the UTXO set changes only
+}
+
+// BlockAcceptResult enum tells how adding a block changed the chain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockAcceptResult {
+    AlreadyKnown, // The block was already stored
+    Extended,     // The block extends the current tip and became the new tip
+    Reorganized,  // The block made a competing chain the best one, the UTXO set was rebuilt
+    SideChain,    // The block was stored on a competing chain that isn't the best one
+}
+
+// BlockSummary struct holds the overview of a block for the explorer
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSummary {
+    pub height: u32,             // Height of the block
+    pub hash: String,            // Hash of the block
+    pub tx_count: usize,         // Number of transactions in the block
+    pub total_output_value: i32, // Sum of the output values of all transactions in the block
+    pub timestamp: u128,         // Time of the block creation in milliseconds since the Unix Epoch
+}
+
+// Get the difficulty of the blocks following a retarget
+// It moves by one step when the last interval took less than half or more than twice the
+// targeted time, and never goes below the minimum difficulty of the network
+// difficulty: the difficulty of the last block of the interval
+// actual: the time the interval took in milliseconds
+// target: the targeted time of the interval in milliseconds
+// min_difficulty: the minimum difficulty of the network
+pub fn retarget(difficulty: usize, actual: u128, target: u128, min_difficulty: usize) -> usize {
+    let next = if actual < target / 2 {
+        difficulty + 1
+    } else if actual > target.saturating_mul(2) {
+        difficulty.saturating_sub(1)
+    } else {
+        difficulty
+    };
+    next.max(min_difficulty)
+}
+
+// Get the difficulty the block following a block must be mined with at least
+// It is the difficulty of the previous block, retargeted every RETARGET_INTERVAL blocks
+// according to how long the last interval took compared to the target block time
+// prev: the header of the previous block
+// interval_start: gets the header of the first block of the interval ending with prev,
+// only called when the next block is retargeted
+// params: the parameters of the network
+pub fn difficulty_after(
+    prev: &BlockHeader,
+    interval_start: impl FnOnce() -> Result<BlockHeader>,
+    params: &ChainParams,
+) -> Result<usize> {
+    let difficulty = prev.get_difficulty().max(params.difficulty);
+    if !(prev.get_height() + 1).is_multiple_of(RETARGET_INTERVAL) {
+        return Ok(difficulty);
+    }
+
+    let first = interval_start()?;
+    let actual = prev.get_timestamp().saturating_sub(first.get_timestamp());
+    let target = u128::from(RETARGET_INTERVAL - 1) * u128::from(params.target_block_time) * 1000;
+
+    Ok(retarget(difficulty, actual, target, params.difficulty))
+}
+
+// Get the current time in milliseconds since the Unix Epoch
+fn now_millis() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis())
+}
+
+// Open a sled database, telling a lock held by another process apart from a corruption
+// path: the path of the database
+pub(crate) fn open_db(path: &Path) -> Result<sled::Db> {
+    match sled::open(path) {
+        Ok(db) => Ok(db),
+        // sled reports a failed file lock as an I/O error of kind Other
+        Err(sled::Error::Io(e)) if e.kind() == io::ErrorKind::Other && db_file_locked(path) => {
+            Err(StorageError::Locked(path.display().to_string()).into())
+        }
+        Err(sled::Error::Corruption { .. }) => {
+            Err(StorageError::Corrupt(path.display().to_string()).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Check whether another handle holds the lock of the file of a sled database
+// sled drops the kind of the lock error, so the lock is probed again
+// path: the path of the database
+fn db_file_locked(path: &Path) -> bool {
+    match File::open(path.join("db")) {
+        Ok(file) => matches!(file.try_lock(), Err(TryLockError::WouldBlock)),
+        Err(_) => false,
+    }
+}
+
+// GenesisInfo struct holds what identifies the network a chain belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenesisInfo {
+    pub hash: String,          // Hash of the genesis block
+    pub timestamp: u128, // Time of the genesis block creation in milliseconds since the Unix Epoch
+    pub coinbase_data: String, // Data embedded in the coinbase input of the genesis block
+    pub recipient: String, // Address the genesis block reward was sent to
+}
+
+// ReorgEvent struct records a switch of the tip to a competing chain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReorgEvent {
+    pub old_tip: String,           // Hash of the tip before the reorganization
+    pub new_tip: String,           // Hash of the tip after the reorganization
+    pub fork_height: Option<u32>, // Height of the common ancestor, None if the chains share no block
+    pub disconnected: Vec<String>, // Hashes of the blocks leaving the chain, from the old tip
+    pub connected: Vec<String>,   // Hashes of the blocks joining the chain, towards the new tip
+}
+
 // Blockchain struct contains a current hash and a database
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     current_hash: String, // hash of the last block
     db: sled::Db,         // database
+    params: ChainParams,  // parameters of the network
+    path: PathBuf,        // base directory of the databases
+    utxos: sled::Db,      // UTXO set database, shared with the UTXO sets built on the chain
 }
 
 impl Blockchain {
     // Create a blockchain instance
     pub fn new() -> Result<Self> {
-        // open the database
-        let db = sled::open("data/blocks")?;
+        Self::new_with_params(ChainParams::default())
+    }
+
+    // Create a blockchain instance for the network
+    // params: the parameters of the network (e.g. the mining difficulty)
+    pub fn new_with_params(params: ChainParams) -> Result<Self> {
+        Self::open(DATA_DIR, params)
+    }
+
+    // Create a blockchain instance keeping its databases in the given directory
+    // path: the base directory of the databases
+    pub fn new_with_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path, ChainParams::default())
+    }
+
+    // Create a blockchain instance for the network keeping its databases in the given directory
+    // path: the base directory of the databases
+    // params: the parameters of the network (e.g. the mining difficulty)
+    pub fn open<P: AsRef<Path>>(path: P, params: ChainParams) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        // open the databases
+        let db = open_db(&path.join("blocks"))?;
+        let utxos = open_db(&path.join("utxos"))?;
 
         // get the hash of the last block
         let hash = match db.get("LAST")? {
@@ -35,22 +198,57 @@ impl Blockchain {
             String::from_utf8(hash)?
         };
 
-        // return the Blockchain
-        Ok(Self {
+        let bc = Self {
             current_hash: lasthash,
             db,
-        })
+            params,
+            path,
+            utxos,
+        };
+
+        // Databases created before the height index existed are indexed once
+        if !bc.current_hash.is_empty() && bc.db.open_tree(HEIGHTS_TREE)?.is_empty() {
+            bc.index_heights(&bc.current_hash)?;
+        }
+
+        // return the Blockchain
+        Ok(bc)
+    }
+
+    // Get the parameters of the network
+    pub fn get_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // Get the desired number of seconds between two blocks of the network
+    pub fn target_block_time(&self) -> u64 {
+        self.params.target_block_time
+    }
+
+    // Get the hash of the last block of the chain
+    pub fn get_tip_hash(&self) -> String {
+        self.current_hash.clone()
     }
 
-    // Sign a transaction with a private key
+    // Get the base directory of the databases
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    // Get the UTXO set database
+    pub(crate) fn utxos_db(&self) -> &sled::Db {
+        &self.utxos
+    }
+
+    // Sign a transaction with a signer
     // tx: the transaction to sign
-    // priate_key: the private key to sign the transaction with
-    pub fn sign_transaction(&self, tx: &mut Transaction, priate_key: &[u8]) -> Result<()> {
+    // signer: the signer holding the private key to sign the transaction with
+    pub fn sign_transaction(&self, tx: &mut Transaction, signer: &dyn Signer) -> Result<()> {
         // get previous transactions referenced in the transaction (inputs)
         let prev_txs = self.get_prev_txs(tx)?;
 
-        // sign the transaction with the private key and previous transactions
-        tx.sign(priate_key, prev_txs)?;
+        // sign the transaction with the signer and previous transactions
+        tx.sign(signer, prev_txs)?;
 
         // return Ok
         Ok(())
@@ -59,11 +257,26 @@ impl Blockchain {
     // Find previous transactions referenced in the transaction
     // tx: the transaction to verify
     pub fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        self.get_prev_txs_with(tx, &HashMap::new())
+    }
+
+    // Get the previous transactions of a transaction, looking them up in the pending
+    // transactions before the chain
+    // tx: the transaction whose inputs are resolved
+    // pending: unconfirmed transactions the inputs may refer to, by ID
+    pub fn get_prev_txs_with(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<HashMap<String, Transaction>> {
         let mut prev_txs = HashMap::new();
 
         // Find previous transactions referenced in the transaction (inputs)
         for vin in &tx.vin {
-            let prev_tx = self.find_transaction(&vin.txid)?;
+            let prev_tx = match pending.get(&vin.txid) {
+                Some(prev_tx) => prev_tx.clone(),
+                None => self.find_transaction(&vin.txid)?,
+            };
             prev_txs.insert(prev_tx.id.clone(), prev_tx);
         }
 
@@ -74,28 +287,132 @@ impl Blockchain {
     // Find a transaction by its ID
     // id: the ID of the transaction to find
     pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
+        Ok(self.find_transaction_with_height(id)?.0)
+    }
+
+    // Find a transaction by its ID along with the height of the block containing it
+    // id: the ID of the transaction to find
+    pub fn find_transaction_with_height(&self, id: &str) -> Result<(Transaction, u32)> {
         // iterate over the blockchain
         for block in self.iter() {
             for tx in block.get_transactions() {
                 if tx.id == id {
-                    return Ok(tx.clone());
+                    return Ok((tx.clone(), block.get_height()));
                 }
             }
         }
 
         // return an error if the transaction is not found
-        Err(anyhow!("Transaction is not found"))
+        Err(BlockchainError::TransactionNotFound(id.to_string()).into())
+    }
+
+    // Find the block that includes a transaction
+    // id: the ID of the transaction
+    pub fn find_transaction_block(&self, id: &str) -> Result<Block> {
+        for block in self.iter() {
+            if block.get_transactions().iter().any(|tx| tx.id == id) {
+                return Ok(block);
+            }
+        }
+
+        // Transactions only in the mempool are not part of any block yet
+        Err(anyhow!("Transaction {} is not confirmed in any block", id))
+    }
+
+    // Find a transaction by its ID along with the hash of the block containing it and
+    // its number of confirmations (1 when the block is the tip)
+    // id: the ID of the transaction to find
+    pub fn find_transaction_location(&self, id: &str) -> Result<(Transaction, String, usize)> {
+        let block = self.find_transaction_block(id)?;
+        let tx = match block.get_transactions().iter().find(|tx| tx.id == id) {
+            Some(tx) => tx.clone(),
+            None => return Err(BlockchainError::TransactionNotFound(id.to_string()).into()),
+        };
+
+        let confirmations = (self.get_best_height()? - block.get_height()) as usize + 1;
+        Ok((tx, block.get_hash(), confirmations))
+    }
+
+    // Get the fee paid by a confirmed transaction
+    // txid: the ID of the transaction
+    pub fn transaction_fee(&self, txid: &str) -> Result<i32> {
+        let tx = self.find_transaction(txid)?;
+
+        // coinbase transactions don't pay fees
+        if tx.is_coinbase() {
+            return Ok(0);
+        }
+
+        let prev_txs = self.get_prev_txs(&tx)?;
+        tx.fee(&prev_txs)
     }
 
     // Verify a transaction
     pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
-        // coinbase transactions are always valid
+        self.verify_transaction_with(tx, &HashMap::new())
+    }
+
+    // Verify a transaction spending outputs of unconfirmed transactions
+    // tx: the transaction to verify
+    // pending: unconfirmed transactions the inputs may refer to, by ID
+    pub fn verify_transaction_with(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<bool> {
+        // transactions with an unknown version are always invalid
+        if !tx.is_supported_version() {
+            return Ok(false);
+        }
+
+        // transactions with too many inputs or outputs are rejected before any expensive check
+        if !tx.within_limits(self.params.max_inputs, self.params.max_outputs) {
+            return Ok(false);
+        }
+
+        // transactions creating dust outputs would only bloat the UTXO set
+        if tx.has_dust(self.params.dust_threshold) {
+            return Ok(false);
+        }
+
+        // expired transactions can't be included in the next block
+        if tx.is_expired(self.get_best_height()? + 1) {
+            return Ok(false);
+        }
+
+        // height-locked transactions can't be included before the best height reaches the lock
+        if tx.is_height_locked(self.get_best_height()? + 1) {
+            return Ok(false);
+        }
+
+        // time-locked transactions can't be included before the median time past reaches the lock
+        if tx.time_lock.is_some() && tx.is_time_locked(self.median_time_past(MEDIAN_TIME_SPAN)?) {
+            return Ok(false);
+        }
+
+        // coinbase transactions are valid as long as they don't mint more than the reward
+        // of the next block
         if tx.is_coinbase() {
-            return Ok(true);
+            let height = self.get_best_height()? as usize + 1;
+            return Ok(tx.output_value()? <= coinbase_reward(height));
         }
 
-        // get previous transactions referenced in the transaction (inputs)
-        let prev_txs = self.get_prev_txs(tx)?;
+        // coinbase outputs can't be spent before they reach COINBASE_MATURITY confirmations
+        // unconfirmed transactions are never coinbase transactions
+        let best_height = self.get_best_height()?;
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.vin {
+            if let Some(prev_tx) = pending.get(&vin.txid) {
+                prev_txs.insert(prev_tx.id.clone(), prev_tx.clone());
+                continue;
+            }
+
+            let (prev_tx, height) = self.find_transaction_with_height(&vin.txid)?;
+            if prev_tx.is_coinbase() && best_height - height + 1 < COINBASE_MATURITY {
+                return Ok(false);
+            }
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
 
         // verify the transaction
         tx.verify(prev_txs)
@@ -104,24 +421,48 @@ impl Blockchain {
     // Create a new Blockchain with a genesis block
     // address: the address to send the genesis block reward to
     pub fn create_blockchain(address: String) -> Result<Self> {
+        Self::create_blockchain_with_params(address, ChainParams::default())
+    }
+
+    // Create a new Blockchain with a genesis block for the network
+    // address: the address to send the genesis block reward to
+    // params: the parameters of the network (e.g. the mining difficulty)
+    pub fn create_blockchain_with_params(address: String, params: ChainParams) -> Result<Self> {
+        Self::create_blockchain_at(DATA_DIR, address, params)
+    }
+
+    // Create a new Blockchain with a genesis block keeping its databases in the given directory
+    // path: the base directory of the databases
+    // address: the address to send the genesis block reward to
+    // params: the parameters of the network (e.g. the mining difficulty)
+    pub fn create_blockchain_at<P: AsRef<Path>>(
+        path: P,
+        address: String,
+        params: ChainParams,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
         // check if the blockchain already exists
-        if Path::new("data/blocks").is_dir() {
+        if path.join("blocks").is_dir() {
             return Err(anyhow!("Blockchain already exists"));
         }
 
-        // open the database
-        let db = sled::open("data/blocks")?;
-
         // create a coinbase transaction
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA), 0)?;
+
+        // open the databases
+        let db = open_db(&path.join("blocks"))?;
+        let utxos = open_db(&path.join("utxos"))?;
 
         // create a genesis block
-        let genesis: Block = Block::new_genesis_block(cbtx);
+        let genesis: Block = Block::new_genesis_block(cbtx, params.difficulty);
 
         // insert the genesis block into the database
         db.insert(genesis.get_hash(), serialize(&genesis)?)
             .expect("Failed to insert");
         db.insert("LAST", genesis.get_hash().as_bytes())?;
+        db.open_tree(HEIGHTS_TREE)?
+            .insert(0u32.to_be_bytes(), genesis.get_hash().as_bytes())?;
 
         // flush the database
         db.flush()?;
@@ -130,17 +471,62 @@ impl Blockchain {
         Ok(Self {
             current_hash: genesis.get_hash(),
             db,
+            params,
+            path,
+            utxos,
         })
     }
 
     // Mine a new block with the provided transactions
     // transactions: the transactions to include in the block
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        // verify the transactions before mining
+        let mut new_block = self.new_block_template(transactions)?;
+        new_block.mine(&AtomicBool::new(false))?;
+
+        // insert the new block into the database
+        self.db
+            .insert(new_block.get_hash(), serialize(&new_block)?)?;
+        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
+        self.index_heights(&new_block.get_hash())?;
+        self.db.flush()?;
+
+        self.current_hash = new_block.get_hash();
+
+        // return the new block
+        Ok(new_block)
+    }
+
+    // Build the next block of the chain with the provided transactions, ready to be mined
+    // The block can be mined without borrowing the chain, then added with add_block
+    // transactions: the transactions to include in the block
+    pub fn new_block_template(&self, mut transactions: Vec<Transaction>) -> Result<Block> {
+        // the coinbase comes first and is the only one
+        coinbase_of(&transactions)?;
+
+        // verify the transactions before mining, they may spend outputs of the transactions
+        // before them in the block
+        let mut fees: i32 = 0;
+        let mut earlier: HashMap<String, Transaction> = HashMap::new();
         for tx in &transactions {
-            if !self.verify_transaction(tx)? {
+            if !self.verify_transaction_with(tx, &earlier)? {
                 return Err(anyhow!("Invalid transaction"));
             }
+            if !tx.is_coinbase() {
+                fees = fees
+                    .checked_add(tx.fee(&self.get_prev_txs_with(tx, &earlier)?)?)
+                    .ok_or_else(|| anyhow!("fees of the block overflow"))?;
+                earlier.insert(tx.id.clone(), tx.clone());
+            }
+        }
+
+        // the miner collects the fees of the included transactions with the coinbase
+        if fees > 0 {
+            let cbtx = &mut transactions[0];
+            cbtx.vout[0].value = cbtx.vout[0]
+                .value
+                .checked_add(fees)
+                .ok_or_else(|| anyhow!("coinbase value overflows"))?;
+            cbtx.id = cbtx.hash()?;
         }
 
         // get the hash of the last block
@@ -149,57 +535,502 @@ impl Blockchain {
             None => Err(anyhow!("Last hash not found"))?,
         };
 
-        // create a new block with the transactions, the hash of the last block and the best block height
-        let new_block = Block::new_block(
+        // the block must be dated after the median time past, even when blocks are mined
+        // within the same millisecond
+        let lasthash = String::from_utf8(lasthash)?;
+        let timestamp =
+            now_millis()?.max(self.median_time_past_at(&lasthash, MEDIAN_TIME_SPAN)? + 1);
+
+        // create a new block with the transactions, the hash of the last block and the next block height
+        let difficulty = self.next_difficulty(&lasthash)?;
+        let mut block = Block::new_template(
             transactions,
-            String::from_utf8(lasthash)?,
-            self.get_best_height()?,
+            lasthash,
+            self.get_best_height()? + 1,
+            difficulty,
+            timestamp,
         )?;
 
-        // insert the new block into the database
-        self.db
-            .insert(new_block.get_hash(), serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
+        // the UTXO set must be able to apply the block once it's mined, and the block commits
+        // to the UTXO set it leaves
+        let changes = self.check_utxos(&block)?;
+        block.commit_utxos(utxo_commitment_after(&self.utxos, &changes)?);
+        Ok(block)
+    }
+
+    // Check that a block extending the tip only spends unspent outputs and conserves value,
+    // against the UTXO set of the chain, and matches its UTXO set commitment if it has one
+    // Return the changes the block makes to the UTXO set
+    // block: the block to check
+    fn check_utxos(&self, block: &Block) -> Result<HashMap<String, Option<TXOutputs>>> {
+        let changes = connect_block(block, |txid| match self.utxos.get(txid)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        })?;
+
+        let utxo_root = block.get_utxo_root();
+        if !utxo_root.is_empty() && utxo_commitment_after(&self.utxos, &changes)? != utxo_root {
+            return Err(anyhow!(
+                "UTXO commitment of block {} doesn't match the UTXO set it leaves",
+                block.get_hash()
+            ));
+        }
+
+        Ok(changes)
+    }
+
+    // Import blocks extending the current tip, e.g. from a snapshot
+    // Every block is applied to the UTXO set, so the chain can be extended right after
+    // blocks: the blocks to import, from the oldest to the newest
+    // level: how thoroughly the blocks are verified
+    pub fn import(&mut self, blocks: Vec<Block>, level: VerificationLevel) -> Result<()> {
+        self.import_with(blocks, level, true)
+    }
+
+    // Import blocks extending the current tip without applying them to the UTXO set, to fast sync
+    // The consensus rules are checked but not the transactions, the UTXO set must then be
+    // adopted from a snapshot matching the commitment of the new tip, see UTXOSet::fast_sync
+    // blocks: the blocks to import, from the oldest to the newest
+    pub fn import_without_utxos(&mut self, blocks: Vec<Block>) -> Result<()> {
+        self.import_with(blocks, VerificationLevel::PowOnly, false)
+    }
+
+    // Import blocks extending the current tip
+    // blocks: the blocks to import, from the oldest to the newest
+    // level: how thoroughly the blocks are verified
+    // connect: whether to apply the blocks to the UTXO set
+    fn import_with(
+        &mut self,
+        blocks: Vec<Block>,
+        level: VerificationLevel,
+        connect: bool,
+    ) -> Result<()> {
+        for block in blocks {
+            // Every block must link to the previous one, the first to the current tip
+            if block.get_prev_hash() != self.current_hash {
+                return Err(anyhow!(
+                    "Block {} does not link to {}",
+                    block.get_hash(),
+                    self.current_hash
+                ));
+            }
+
+            // Check the consensus rules unless the source is fully trusted, and the
+            // transactions against the blocks imported so far for a full verification
+            if level != VerificationLevel::TrustedNoVerify {
+                self.check_block(&block)?;
+            }
+            if level == VerificationLevel::Full {
+                self.verify_block_transactions(&block)?;
+            }
+
+            // Apply the block to the UTXO set, which rejects blocks spending missing outputs,
+            // not conserving value or missing their UTXO set commitment at every level
+            if connect {
+                let changes = self.check_utxos(&block)?;
+                let mut batch = sled::Batch::default();
+                for (txid, outs) in &changes {
+                    match outs {
+                        Some(outs) => batch.insert(txid.as_bytes(), serialize(outs)?),
+                        None => batch.remove(txid.as_bytes()),
+                    }
+                }
+                self.utxos.apply_batch(batch)?;
+            }
+
+            // Insert the block and make it the new tip
+            self.db.insert(block.get_hash(), serialize(&block)?)?;
+            self.db.insert("LAST", block.get_hash().as_bytes())?;
+            self.index_heights(&block.get_hash())?;
+            self.current_hash = block.get_hash();
+        }
+
+        // Flush the databases
+        self.utxos.flush()?;
         self.db.flush()?;
 
-        self.current_hash = new_block.get_hash();
+        Ok(())
+    }
 
-        // return the new block
-        Ok(new_block)
+    // Check a block against the consensus rules that don't depend on the UTXO set
+    // Its previous block must be stored, it must sit right above it with a valid proof of work at
+    // the required difficulty, match its merkle root, start with its only coinbase, follow the
+    // transaction limits and the height and time rules
+    // block: the block to check
+    fn check_block(&self, block: &Block) -> Result<()> {
+        // Reject orphan blocks, the previous block must be known (only a genesis block has none)
+        let prev_hash = block.get_prev_hash();
+        if prev_hash.is_empty() {
+            if block.get_height() != 0 {
+                return Err(anyhow!(
+                    "Block {} has no previous block but height {}",
+                    block.get_hash(),
+                    block.get_height()
+                ));
+            }
+        } else {
+            if prev_hash != self.current_hash && !self.has_block(&prev_hash)? {
+                return Err(anyhow!(
+                    "Previous block {} of block {} is unknown",
+                    prev_hash,
+                    block.get_hash()
+                ));
+            }
+
+            // Each block sits right above its previous block, so the height index stays exact
+            let prev_height = self.get_block_header(&prev_hash)?.get_height();
+            if block.get_height() != prev_height + 1 {
+                return Err(anyhow!(
+                    "Block {} has height {} but its previous block {} has height {}",
+                    block.get_hash(),
+                    block.get_height(),
+                    prev_hash,
+                    prev_height
+                ));
+            }
+        }
+
+        // Reject blocks whose hash doesn't match their content or misses the difficulty
+        if block.get_difficulty() < self.next_difficulty(&prev_hash)? || !block.verify_pow()? {
+            return Err(BlockchainError::InvalidProofOfWork(block.get_hash()).into());
+        }
+
+        // Reject blocks whose transactions don't match the merkle root of their header
+        if !block.verify_merkle_root()? {
+            return Err(anyhow!(
+                "Merkle root doesn't match the transactions of block {}",
+                block.get_hash()
+            ));
+        }
+
+        // Reject blocks without a single coinbase transaction in first position
+        block.coinbase()?;
+
+        // Reject blocks including transactions with too many inputs or outputs
+        for tx in block.get_transactions() {
+            if !tx.within_limits(self.params.max_inputs, self.params.max_outputs) {
+                return Err(anyhow!(
+                    "Block contains a transaction exceeding the input/output limits: {}",
+                    tx.id
+                ));
+            }
+        }
+
+        // Reject blocks including transactions that expired before the block's height
+        for tx in block.get_transactions() {
+            if tx.is_expired(block.get_height()) {
+                return Err(anyhow!("Block contains an expired transaction: {}", tx.id));
+            }
+        }
+
+        // Reject blocks including transactions whose lock time the chain hasn't reached yet
+        for tx in block.get_transactions() {
+            if tx.is_height_locked(block.get_height()) {
+                return Err(anyhow!(
+                    "Block contains a height-locked transaction: {}",
+                    tx.id
+                ));
+            }
+        }
+
+        // Reject blocks dated too far ahead of the local clock, they could skew the time rules
+        let max_time = now_millis()? + MAX_FUTURE_BLOCK_TIME;
+        if block.get_timestamp() > max_time {
+            return Err(anyhow!(
+                "Block {} is dated too far in the future: {} > {}",
+                block.get_hash(),
+                block.get_timestamp(),
+                max_time
+            ));
+        }
+
+        // Reject blocks not dated after the median time past of the blocks before them
+        let median_time = if prev_hash.is_empty() {
+            0
+        } else {
+            self.median_time_past_at(&prev_hash, MEDIAN_TIME_SPAN)?
+        };
+        if !prev_hash.is_empty() && block.get_timestamp() <= median_time {
+            return Err(anyhow!(
+                "Block {} is not dated after the median time past: {} <= {}",
+                block.get_hash(),
+                block.get_timestamp(),
+                median_time
+            ));
+        }
+
+        // Reject blocks including transactions whose time lock the chain hasn't passed yet
+        for tx in block.get_transactions() {
+            if tx.is_time_locked(median_time) {
+                return Err(anyhow!(
+                    "Block contains a time-locked transaction: {}",
+                    tx.id
+                ));
+            }
+        }
+
+        Ok(())
     }
 
-    // Add a block to the blockchain
+    // Add a block to the blockchain and report how it changed the chain
     // block: the block to add
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
+    pub fn add_block(&mut self, block: Block) -> Result<BlockAcceptResult> {
         // Serialize the block
         let data = serialize(&block)?;
 
         // Check if the block already exists
         if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
+            return Ok(BlockAcceptResult::AlreadyKnown);
+        }
+
+        // Reject blocks breaking the consensus rules
+        self.check_block(&block)?;
+        let prev_hash = block.get_prev_hash();
+
+        // A block extending the tip must apply to the UTXO set before it's stored, so the tip
+        // never moves to a block the UTXO set can't follow
+        if prev_hash == self.current_hash {
+            self.verify_block_transactions(&block)?;
+            self.check_utxos(&block)?;
         }
 
         // Insert the block into the database
         self.db.insert(block.get_hash(), data)?;
 
-        let height = self.get_best_height()?;
+        // An empty chain takes the block as its tip
+        if self.current_hash.is_empty() {
+            self.set_tip(&block.get_hash())?;
+            return Ok(BlockAcceptResult::Extended);
+        }
 
-        if block.get_height() > height {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.current_hash = block.get_hash();
+        if prev_hash == self.current_hash {
+            // The block extends the current tip
+            self.set_tip(&block.get_hash())?;
+            Ok(BlockAcceptResult::Extended)
+        } else {
+            // The block builds a competing chain, which wins if it has more work, or as much
+            // work and a tip with a lower hash, so every node settles on the same tip
+            // whatever the arrival order
+            let work = self.work_of(&block.get_hash())?;
+            let current_work = self.work_of(&self.current_hash)?;
+            if work > current_work || (work == current_work && block.get_hash() < self.current_hash)
+            {
+                self.reorg_to(&block.get_hash())?;
+                Ok(BlockAcceptResult::Reorganized)
+            } else {
+                Ok(BlockAcceptResult::SideChain)
+            }
+        }
+    }
+
+    // Switch to the chain ending with the given block and rebuild the UTXO set
+    // new_tip_hash: the hash of the tip of the chain to switch to
+    pub fn reorg_to(&mut self, new_tip_hash: &str) -> Result<()> {
+        // Walk back from the new tip to the common ancestor with the current chain
+        let current: HashSet<String> = self.get_block_hashs()?.into_iter().collect();
+        let mut hash = new_tip_hash.to_string();
+        let mut connected = Vec::new();
+        while !hash.is_empty() && !current.contains(&hash) {
+            // Every block of the new chain must be stored
+            let block = self.get_block(&hash)?;
+            connected.push(hash);
+            hash = block.get_prev_hash();
+        }
+        connected.reverse();
+        let ancestor = hash;
+
+        // Walk back from the current tip to the common ancestor
+        let mut disconnected = Vec::new();
+        let mut hash = self.current_hash.clone();
+        while !hash.is_empty() && hash != ancestor {
+            let header = self.get_block_header(&hash)?;
+            disconnected.push(hash);
+            hash = header.get_prev_hash();
+        }
+
+        let fork_height = if ancestor.is_empty() {
+            None
+        } else {
+            Some(self.get_block_header(&ancestor)?.get_height())
+        };
+
+        info!(
+            "Reorganizing from {} to {}: {} blocks disconnected, {} blocks connected after the common ancestor {}",
+            self.current_hash,
+            new_tip_hash,
+            disconnected.len(),
+            connected.len(),
+            ancestor
+        );
+
+        // Move the tip to the new chain, then validate the connected blocks in order and
+        // rebuild the UTXO set, which fails on the first block spending a missing output
+        let old_tip = self.current_hash.clone();
+        self.set_tip(new_tip_hash)?;
+        let mut invalid = None;
+        for (idx, hash) in connected.iter().enumerate() {
+            let block = self.get_block(hash)?;
+            if let Err(err) = self.verify_block_transactions(&block) {
+                invalid = Some((idx, err));
+                break;
+            }
+        }
+        if invalid.is_none() {
+            if let Err(err) = self.replay() {
+                let idx = match err.downcast_ref::<BlockchainError>() {
+                    Some(BlockchainError::InvalidBlock { hash, .. }) => {
+                        connected.iter().position(|h| h == hash).unwrap_or(0)
+                    }
+                    _ => 0,
+                };
+                invalid = Some((idx, err));
+            }
+        }
+
+        // Stay on the old chain and forget the invalid block along with the blocks built on it
+        if let Some((idx, err)) = invalid {
+            error!(
+                "Reorganization to {} aborted, block {} is invalid: {}",
+                new_tip_hash, connected[idx], err
+            );
+            self.set_tip(&old_tip)?;
+            self.replay()?;
+            for hash in &connected[idx..] {
+                self.db.remove(hash)?;
+            }
             self.db.flush()?;
+            return Err(err);
+        }
+
+        // Record the reorganization for auditing
+        let event = ReorgEvent {
+            old_tip,
+            new_tip: new_tip_hash.to_string(),
+            fork_height,
+            disconnected,
+            connected,
+        };
+        let reorgs = self.db.open_tree(REORGS_TREE)?;
+        reorgs.insert(self.db.generate_id()?.to_be_bytes(), serialize(&event)?)?;
+        reorgs.flush()?;
+
+        Ok(())
+    }
+
+    // Check the signatures of the transactions of a block and that they only spend
+    // outputs of earlier transactions, coinbase outputs once they matured
+    // The inputs are looked up in the block itself and in the chain from the current tip,
+    // which must include the parent of the block
+    // block: the block whose transactions to check
+    fn verify_block_transactions(&self, block: &Block) -> Result<()> {
+        let height = block.get_height();
+        let mut earlier: HashMap<String, Transaction> = HashMap::new();
+
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                let mut prev_txs = HashMap::new();
+                for vin in &tx.vin {
+                    let (prev_tx, prev_height) = match earlier.get(&vin.txid) {
+                        Some(prev_tx) => (prev_tx.clone(), height),
+                        None => self.find_transaction_with_height(&vin.txid)?,
+                    };
+
+                    // Outputs found at the height of the block or above are not created yet
+                    if prev_height >= height && !earlier.contains_key(&vin.txid) {
+                        return Err(anyhow!(
+                            "transaction {} spends an output of a later transaction: {}",
+                            tx.id,
+                            vin.txid
+                        ));
+                    }
+                    if prev_tx.is_coinbase() && height - prev_height < COINBASE_MATURITY {
+                        return Err(anyhow!(
+                            "transaction {} spends an immature coinbase output: {}",
+                            tx.id,
+                            vin.txid
+                        ));
+                    }
+                    prev_txs.insert(prev_tx.id.clone(), prev_tx);
+                }
+
+                if !tx.verify(prev_txs)? {
+                    return Err(anyhow!("Invalid transaction: {}", tx.id));
+                }
+            }
+            earlier.insert(tx.id.clone(), tx.clone());
+        }
+
+        Ok(())
+    }
+
+    // Get the recorded reorganizations from the oldest to the most recent
+    pub fn reorg_history(&self) -> Result<Vec<ReorgEvent>> {
+        let reorgs = self.db.open_tree(REORGS_TREE)?;
+
+        let mut events = Vec::new();
+        for kv in reorgs.iter() {
+            let (_, v) = kv?;
+            events.push(deserialize(&v)?);
+        }
+        Ok(events)
+    }
+
+    // Set the tip of the chain
+    // hash: the hash of the new last block
+    fn set_tip(&mut self, hash: &str) -> Result<()> {
+        self.db.insert("LAST", hash.as_bytes())?;
+        self.index_heights(hash)?;
+        self.current_hash = hash.to_string();
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Point the height index at the chain ending with the given block
+    // Entries of a chain switched away from are overwritten, or removed above the new tip,
+    // and the walk stops at the first height already pointing at the chain
+    // tip_hash: the hash of the new tip
+    fn index_heights(&self, tip_hash: &str) -> Result<()> {
+        let heights = self.db.open_tree(HEIGHTS_TREE)?;
+        let tip_height = self.get_block_header(tip_hash)?.get_height();
+
+        // Remove the heights above the new tip
+        while let Some((key, _)) = heights.last()? {
+            if u32::from_be_bytes(key.as_ref().try_into()?) <= tip_height {
+                break;
+            }
+            heights.remove(key)?;
+        }
+
+        let mut hash = tip_hash.to_string();
+        while !hash.is_empty() {
+            let header = self.get_block_header(&hash)?;
+            let key = header.get_height().to_be_bytes();
+            if heights.get(key)?.as_deref() == Some(hash.as_bytes()) {
+                break;
+            }
+            heights.insert(key, hash.as_bytes())?;
+            hash = header.get_prev_hash();
         }
 
-        // Return Ok
         Ok(())
     }
 
+    // Get the block of the chain at the given height
+    // height: the height of the block, 0 for the genesis block
+    pub fn get_block_by_height(&self, height: usize) -> Result<Block> {
+        let key = u32::try_from(height)?.to_be_bytes();
+        match self.db.open_tree(HEIGHTS_TREE)?.get(key)? {
+            Some(hash) => self.get_block(std::str::from_utf8(&hash)?),
+            None => Err(anyhow!("No block at height {}", height)),
+        }
+    }
+
     // Get a block by its hash
     pub fn get_block(&self, hash: &str) -> Result<Block> {
         // Get the block from the database
         let data = match self.db.get(hash)? {
             Some(d) => d,
-            None => Err(anyhow!("Block not found"))?,
+            None => Err(BlockchainError::BlockNotFound(hash.to_string()))?,
         }
         .to_vec();
 
@@ -210,45 +1041,354 @@ impl Blockchain {
         Ok(block)
     }
 
-    // Get the best block height
-    pub fn get_best_height(&self) -> Result<u32> {
+    // Check if a block is stored in the database
+    pub fn has_block(&self, hash: &str) -> Result<bool> {
+        Ok(self.db.contains_key(hash)?)
+    }
+
+    // Persist the hashes of the blocks that are being downloaded
+    // hashs: the hashes of the blocks still to be requested, in order
+    pub fn save_in_transit(&self, hashs: &[String]) -> Result<()> {
+        self.db.insert("IN_TRANSIT", serialize(hashs)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Load the hashes of the blocks that were being downloaded, skipping already received blocks
+    pub fn load_in_transit(&self) -> Result<Vec<String>> {
+        let hashs: Vec<String> = match self.db.get("IN_TRANSIT")? {
+            Some(data) => deserialize(&data)?,
+            None => Vec::new(),
+        };
+
+        let mut remaining = Vec::new();
+        for hash in hashs {
+            if !self.has_block(&hash)? {
+                remaining.push(hash);
+            }
+        }
+
+        Ok(remaining)
+    }
+
+    // Get the cumulative work of the chain, the sum of the work of its blocks
+    pub fn chain_work(&self) -> u128 {
+        self.iter()
+            .map(|block| block_work(block.get_difficulty()))
+            .fold(0, u128::saturating_add)
+    }
+
+    // Get the cumulative work of the chain ending with the given block
+    // hash: the hash of the last block of the chain
+    fn work_of(&self, hash: &str) -> Result<u128> {
+        let mut work: u128 = 0;
+        let mut hash = hash.to_string();
+        while !hash.is_empty() {
+            let header = self.get_block_header(&hash)?;
+            work = work.saturating_add(block_work(header.get_difficulty()));
+            hash = header.get_prev_hash();
+        }
+        Ok(work)
+    }
+
+    // Get the timestamp of the tip of the chain in milliseconds since the Unix Epoch
+    pub fn get_tip_timestamp(&self) -> Result<u128> {
+        Ok(self.get_block_header(&self.current_hash)?.get_timestamp())
+    }
+
+    // Get the median timestamp of the last n blocks of the chain
+    // Time locks are checked against it over MEDIAN_TIME_SPAN blocks, so a single miner
+    // can't move them with one timestamp
+    // n: the number of blocks, all of them are used when the chain is shorter
+    pub fn median_time_past(&self, n: usize) -> Result<u128> {
+        self.median_time_past_at(&self.current_hash, n)
+    }
+
+    // Get the median timestamp of the last n blocks ending with the given block
+    // hash: the hash of the most recent block to take into account
+    // n: the number of blocks
+    fn median_time_past_at(&self, hash: &str, n: usize) -> Result<u128> {
+        if n == 0 {
+            return Err(anyhow!("median time past needs at least one block"));
+        }
+
+        let mut timestamps = Vec::new();
+        let mut hash = hash.to_string();
+        while !hash.is_empty() && timestamps.len() < n {
+            let header = self.get_block_header(&hash)?;
+            timestamps.push(header.get_timestamp());
+            hash = header.get_prev_hash();
+        }
+
+        if timestamps.is_empty() {
+            return Err(anyhow!("Blockchain is empty"));
+        }
+
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    // Get the difficulty the block following a stored block must be mined with at least
+    // prev_hash: the hash of the previous block, empty for a genesis block
+    pub fn next_difficulty(&self, prev_hash: &str) -> Result<usize> {
+        if prev_hash.is_empty() {
+            return Ok(self.params.difficulty);
+        }
+
+        // Walk back to the first block of the interval, along the branch of the block
+        let prev = self.get_block_header(prev_hash)?;
+        difficulty_after(
+            &prev,
+            || {
+                let mut first = prev.clone();
+                for _ in 1..RETARGET_INTERVAL {
+                    first = self.get_block_header(&first.get_prev_hash())?;
+                }
+                Ok(first)
+            },
+            &self.params,
+        )
+    }
+
+    // Get the header of a block by its hash without decoding its transactions
+    pub fn get_block_header(&self, hash: &str) -> Result<BlockHeader> {
+        // Get the block from the database
+        let data = match self.db.get(hash)? {
+            Some(d) => d,
+            None => Err(BlockchainError::BlockNotFound(hash.to_string()))?,
+        };
+
+        // Deserialize only the header of the block
+        BlockHeader::from_block_bytes(&data)
+    }
+
+    // Get the best block height
+    pub fn get_best_height(&self) -> Result<u32> {
         // Get the hash of the last block
         let lasthash = match self.db.get("LAST")? {
             Some(h) => h.to_vec(),
             None => Err(anyhow!("Last hash not found"))?,
         };
 
-        // Get the last block from the database
-        let data = match self.db.get(String::from_utf8(lasthash)?)? {
-            Some(d) => d,
-            None => Err(anyhow!("Block not found"))?,
-        };
-
-        // Deserialize the block
-        let block = deserialize::<Block>(&data.to_vec())?;
+        // Get the header of the last block, the transactions are not needed
+        let header = self.get_block_header(&String::from_utf8(lasthash)?)?;
 
         // Return the height of the block
-        Ok(block.get_height())
+        Ok(header.get_height())
     }
 
     // Get the hash of all blocks from the last to the first
-    pub fn get_block_hashs(&self) -> Vec<String> {
-        // Create a vector to store the hashs
-        let mut hashs = Vec::new();
+    // Fails on a missing or corrupt block instead of returning part of the chain
+    pub fn get_block_hashs(&self) -> Result<Vec<String>> {
+        // Walk the chain reading only the block headers
+        self.iter_hashes().collect()
+    }
+
+    // Get the height and hash of all blocks whose coinbase pays the given address
+    // address: the address of the miner
+    pub fn blocks_by_miner(&self, address: &str) -> Result<Vec<(usize, String)>> {
+        // Decode the address to the public key hash
+        let pub_key_hash = match Address::decode(address) {
+            Ok(addr) => addr.body,
+            Err(_) => return Err(BlockchainError::InvalidAddress(address.to_string()).into()),
+        };
+
+        let mut blocks = Vec::new();
 
         // Iterate over all blocks in the blockchain
         for block in self.iter() {
-            hashs.push(block.get_hash());
+            // Check if any output of the coinbase transaction is locked with the miner's key
+            let mined = block
+                .get_transactions()
+                .iter()
+                .filter(|tx| tx.is_coinbase())
+                .flat_map(|tx| tx.vout.iter())
+                .any(|out| out.is_locked_with_key(&pub_key_hash));
+
+            if mined {
+                blocks.push((block.get_height() as usize, block.get_hash()));
+            }
+        }
+
+        // Return the blocks mined by the address
+        Ok(blocks)
+    }
+
+    // Get the parameters of the genesis block, so operators can check they share a network
+    pub fn genesis_info(&self) -> Result<GenesisInfo> {
+        // The iterator ends with the genesis block
+        let genesis = match self.iter().last() {
+            Some(block) => block,
+            None => return Err(anyhow!("Blockchain is empty")),
+        };
+        if genesis.get_height() != 0 {
+            return Err(anyhow!("Genesis block not found"));
+        }
+
+        // The genesis block holds the coinbase transaction only
+        let cbtx = match genesis.get_transactions().first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err(anyhow!("Genesis block has no coinbase transaction")),
+        };
+        let recipient = match cbtx.vout.first() {
+            Some(out) => address_from_pub_key_hash(out.pub_key_hash.clone())?,
+            None => return Err(anyhow!("Genesis coinbase has no output")),
+        };
+
+        Ok(GenesisInfo {
+            hash: genesis.get_hash(),
+            timestamp: genesis.get_timestamp(),
+            coinbase_data: String::from_utf8_lossy(&cbtx.vin[0].pub_key).to_string(),
+            recipient,
+        })
+    }
+
+    // Get the summaries of the last n blocks from the newest to the oldest
+    // n: the number of blocks to summarize
+    pub fn recent_blocks(&self, n: usize) -> Result<Vec<BlockSummary>> {
+        let summaries = self
+            .iter()
+            .take(n)
+            .map(|block| BlockSummary {
+                height: block.get_height(),
+                hash: block.get_hash(),
+                tx_count: block.get_transactions().len(),
+                total_output_value: block
+                    .get_transactions()
+                    .iter()
+                    .flat_map(|tx| tx.vout.iter())
+                    .map(|out| out.value)
+                    .sum(),
+                timestamp: block.get_timestamp(),
+            })
+            .collect();
+
+        // Return the summaries
+        Ok(summaries)
+    }
+
+    // Replay every block from the genesis to the tip to rebuild the derived state
+    // The UTXO set and the height index are rebuilt from scratch, so missing or corrupt
+    // entries are repaired, and a block that doesn't connect fails with BlockchainError::InvalidBlock
+    pub fn replay(&self) -> Result<()> {
+        // Collect the blocks from the genesis to the tip
+        let mut blocks: Vec<Block> = self.iter().collect();
+        blocks.reverse();
+
+        // Connect each block in order, keeping the outputs at their original index
+        // so that inputs spend exactly the output they reference
+        // Any block spending a missing output, overwriting unspent outputs or missing its
+        // UTXO set commitment stops the replay
+        let mut utxos: BTreeMap<String, TXOutputs> = BTreeMap::new();
+        for block in &blocks {
+            let invalid = |reason: String| BlockchainError::InvalidBlock {
+                hash: block.get_hash(),
+                reason,
+            };
+            let changes = connect_block(block, |txid| Ok(utxos.get(txid).cloned()))
+                .map_err(|err| invalid(err.to_string()))?;
+            for (txid, outs) in changes {
+                match outs {
+                    Some(outs) => utxos.insert(txid, outs),
+                    None => utxos.remove(&txid),
+                };
+            }
+
+            let utxo_root = block.get_utxo_root();
+            if !utxo_root.is_empty() && utxo_commitment(&utxos)? != utxo_root {
+                return Err(invalid("UTXO commitment mismatch".to_string()).into());
+            }
+        }
+
+        // Clear the old UTXO set
+        let db = &self.utxos;
+        db.clear()?;
+
+        // Write the unspent outputs to the UTXO set
+        for (txid, outs) in utxos {
+            if !outs.outputs.is_empty() {
+                db.insert(txid.as_bytes(), serialize(&outs)?)?;
+            }
+        }
+        db.flush()?;
+
+        // Rebuild the height index of the chain
+        self.db.open_tree(HEIGHTS_TREE)?.clear()?;
+        if !self.current_hash.is_empty() {
+            self.index_heights(&self.current_hash)?;
         }
+        self.db.flush()?;
 
-        // Return the hashs
-        hashs
+        Ok(())
+    }
+
+    // Verify the whole chain from the tip to the genesis block
+    // Each block must be stored under its own hash, satisfy its proof of work, match its merkle root
+    // and sit one height above the block it links to, and every non-coinbase transaction must verify
+    // The error names the first bad block found walking down from the tip
+    pub fn validate_chain(&self) -> Result<()> {
+        let mut current_hash = self.current_hash.clone();
+        let mut expected_height: Option<u32> = None;
+
+        while !current_hash.is_empty() {
+            let block = self.get_block(&current_hash)?;
+
+            if block.get_hash() != current_hash {
+                return Err(anyhow!(
+                    "Invalid block {}: stored under the hash of another block",
+                    current_hash
+                ));
+            }
+            if !block.verify_pow()? {
+                return Err(BlockchainError::InvalidProofOfWork(current_hash).into());
+            }
+            if !block.verify_merkle_root()? {
+                return Err(anyhow!(
+                    "Invalid block {}: merkle root mismatch",
+                    current_hash
+                ));
+            }
+
+            // Heights decrease by one down to the genesis block at height 0
+            if let Some(height) = expected_height {
+                if block.get_height() != height {
+                    return Err(anyhow!(
+                        "Invalid block {}: height {} where {} was expected",
+                        current_hash,
+                        block.get_height(),
+                        height
+                    ));
+                }
+            }
+            if block.get_prev_hash().is_empty() && block.get_height() != 0 {
+                return Err(anyhow!(
+                    "Invalid block {}: no previous block at height {}",
+                    current_hash,
+                    block.get_height()
+                ));
+            }
+
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() && !tx.verify(self.get_prev_txs(tx)?)? {
+                    return Err(anyhow!(
+                        "Invalid block {}: transaction {} doesn't verify",
+                        current_hash,
+                        tx.id
+                    ));
+                }
+            }
+
+            expected_height = block.get_height().checked_sub(1);
+            current_hash = block.get_prev_hash();
+        }
+
+        Ok(())
     }
 
     // Find all unspent transaction outputs and return transactions with spent outputs removed
     pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
         let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut spent_txos: HashMap<String, Vec<OutPoint>> = HashMap::new();
 
         // Iterate over all blocks in the blockchain
         for block in self.iter() {
@@ -258,7 +1398,7 @@ impl Blockchain {
                 for idx in 0..tx.vout.len() {
                     // Check if output is already in unspent outputs and if so, skip it
                     if let Some(ids) = spent_txos.get(&tx.id) {
-                        if ids.contains(&(idx as i32)) {
+                        if ids.contains(&OutPoint::Index(idx as u32)) {
                             continue;
                         }
                     }
@@ -267,12 +1407,16 @@ impl Blockchain {
                     match utxos.get_mut(&tx.id) {
                         Some(v) => {
                             v.outputs.push(tx.vout[idx].clone());
+                            v.indices.push(idx as u32);
                         }
                         None => {
                             utxos.insert(
                                 tx.id.clone(),
                                 TXOutputs {
                                     outputs: vec![tx.vout[idx].clone()],
+                                    indices: vec![idx as u32],
+                                    height: block.get_height(),
+                                    coinbase: tx.is_coinbase(),
                                 },
                             );
                         }
@@ -307,11 +1451,31 @@ impl Blockchain {
         utxos
     }
 
+    // Print all blocks from the tip to the genesis block and return the number of blocks
+    // out: the writer to print to, e.g. stdout or a buffer
+    pub fn print_chain(&self, out: &mut impl Write) -> Result<usize> {
+        let mut count = 0;
+        let mut iter = self.iter();
+        while let Some(b) = iter.try_next()? {
+            writeln!(out, "{}\n", b.describe())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
     // Create a new BlockchainIteratorator
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
             current_hash: self.current_hash.clone(),
-            bc: &self,
+            bc: self,
+        }
+    }
+
+    // Create a new HashIterator over the hashes of the blocks from the tip to the genesis block
+    pub fn iter_hashes(&self) -> HashIterator<'_> {
+        HashIterator {
+            current_hash: self.current_hash.clone(),
+            bc: self,
         }
     }
 }
@@ -323,27 +1487,1121 @@ pub struct BlockchainIterator<'a> {
     bc: &'a Blockchain,
 }
 
+impl<'a> BlockchainIterator<'a> {
+    // Get the next block, telling the end of the chain apart from a missing or corrupt block
+    // Returns None only after the genesis block, whose previous hash is empty
+    pub fn try_next(&mut self) -> Result<Option<Block>> {
+        if self.current_hash.is_empty() {
+            return Ok(None);
+        }
+
+        let data = match self.bc.db.get(&self.current_hash)? {
+            Some(d) => d,
+            None => Err(BlockchainError::BlockNotFound(self.current_hash.clone()))?,
+        };
+
+        // Deserialize the block and set the current hash to the previous hash
+        let block = match bincode::deserialize::<Block>(&data) {
+            Ok(block) => block,
+            Err(e) => {
+                return Err(anyhow!(
+                    "Block {} is corrupt, resync the chain to recover: {}",
+                    self.current_hash,
+                    e
+                ))
+            }
+        };
+        self.current_hash = block.get_prev_hash();
+
+        Ok(Some(block))
+    }
+}
+
 impl<'a> Iterator for BlockchainIterator<'a> {
     type Item = Block; // The type of the data that iterates over
 
     // Get the next item in the iterator
+    // A missing or corrupt block ends the iteration, use try_next to get the error
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encode_block) = self.bc.db.get(&self.current_hash) {
-            return match encode_block {
-                Some(b) => {
-                    // Deserialize the block and set the current hash to the previous hash
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
-                        self.current_hash = block.get_prev_hash();
-
-                        // Return the block
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
+        match self.try_next() {
+            Ok(block) => block,
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+}
+
+// HashIterator struct walks the chain like BlockchainIterator but yields the block hashes
+// Only the header of each block is deserialized, the transactions are skipped
+pub struct HashIterator<'a> {
+    current_hash: String,
+    bc: &'a Blockchain,
+}
+
+impl<'a> Iterator for HashIterator<'a> {
+    type Item = Result<String>;
+
+    // Get the hash of the next block
+    // A missing or corrupt block is yielded as an error and ends the iteration
+    fn next(&mut self) -> Option<Self::Item> {
+        // The genesis block has no previous block
+        if self.current_hash.is_empty() {
+            return None;
+        }
+        let hash = std::mem::take(&mut self.current_hash);
+
+        let data = match self.bc.db.get(&hash) {
+            Ok(Some(d)) => d,
+            Ok(None) => return Some(Err(BlockchainError::BlockNotFound(hash).into())),
+            Err(e) => return Some(Err(e.into())),
+        };
+        let header = match BlockHeader::from_block_bytes(&data) {
+            Ok(header) => header,
+            Err(e) => {
+                return Some(Err(anyhow!(
+                    "Block {} is corrupt, resync the chain to recover: {}",
+                    hash,
+                    e
+                )))
+            }
+        };
+
+        // Move to the previous block
+        self.current_hash = header.get_prev_hash();
+        Some(Ok(header.get_hash()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        now_millis, open_db, retarget, BlockAcceptResult, Blockchain, ReorgEvent,
+        VerificationLevel, GENESIS_COINBASE_DATA, HEIGHTS_TREE, MAX_FUTURE_BLOCK_TIME,
+        MEDIAN_TIME_SPAN, RETARGET_INTERVAL,
+    };
+    use crate::block::{block_work, Block};
+    use crate::errors::{BlockchainError, StorageError};
+    use crate::params::ChainParams;
+    use crate::test_utils::TestChain;
+    use crate::transaction::{coinbase_reward, Transaction, COINBASE_MATURITY};
+    use crate::utxoset::UTXOSet;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn attributes_blocks_to_their_miner() {
+        let mut chain = TestChain::new();
+        let other = chain.address();
+
+        let mut mined_by_miner = vec![(0, chain.bc().get_tip_hash())];
+        let mut mined_by_other = Vec::new();
+        for height in 1..=6 {
+            if height % 3 == 0 {
+                let block = chain.mine_to(&other, Vec::new());
+                mined_by_other.push((height, block.get_hash()));
+            } else {
+                let block = chain.mine(Vec::new());
+                mined_by_miner.push((height, block.get_hash()));
+            }
+        }
+
+        // Blocks are listed from the tip
+        mined_by_miner.reverse();
+        mined_by_other.reverse();
+        assert_eq!(
+            chain.bc().blocks_by_miner(&chain.miner).unwrap(),
+            mined_by_miner
+        );
+        assert_eq!(chain.bc().blocks_by_miner(&other).unwrap(), mined_by_other);
+        assert!(chain.bc().blocks_by_miner("not-an-address").is_err());
+    }
+
+    #[test]
+    fn rejects_blocks_with_expired_transactions() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let to = chain.address();
+        let tip_height = chain.bc().get_best_height().unwrap();
+
+        // The transaction may be included up to the current best height only
+        let mut tx = chain.send(&chain.miner, &to, 10);
+        tx.expiry_height = Some(tip_height);
+        chain.resign(&chain.miner, &mut tx);
+        assert!(!chain.bc().verify_transaction(&tx).unwrap());
+
+        let block = Block::new_block(
+            vec![chain.coinbase(&chain.miner), tx],
+            chain.bc().get_tip_hash(),
+            tip_height + 1,
+            chain.bc().get_params().difficulty,
+        )
+        .unwrap();
+        let err = chain.utxo.blockchain.add_block(block).unwrap_err();
+        assert!(err.to_string().contains("expired"), "{}", err);
+        assert_eq!(chain.bc().get_best_height().unwrap(), tip_height);
+    }
+
+    #[test]
+    fn summarizes_the_most_recent_blocks() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let with_tx = chain.mine(vec![tx.clone()]);
+        let empty = chain.mine(Vec::new());
+
+        let summaries = chain.bc().recent_blocks(3).unwrap();
+        let heights: Vec<u32> = summaries.iter().map(|s| s.height).collect();
+        assert_eq!(heights, vec![12, 11, 10]);
+
+        assert_eq!(summaries[0].hash, empty.get_hash());
+        assert_eq!(summaries[0].tx_count, 1);
+        assert_eq!(summaries[0].timestamp, empty.get_timestamp());
+
+        // The coinbase reward plus every output of the payment, change included
+        let reward = with_tx.get_transactions()[0].vout[0].value;
+        let paid: i32 = tx.vout.iter().map(|out| out.value).sum();
+        assert_eq!(summaries[1].hash, with_tx.get_hash());
+        assert_eq!(summaries[1].tx_count, 2);
+        assert_eq!(summaries[1].total_output_value, reward + paid);
+
+        // Asking for more blocks than the chain has returns the whole chain
+        assert_eq!(chain.bc().recent_blocks(100).unwrap().len(), 13);
+    }
+
+    #[test]
+    fn replay_rebuilds_the_derived_state() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+
+        let miner_balance = chain.utxo.get_balance(&chain.miner).unwrap();
+        let utxo_count = chain.utxo.count_transactions().unwrap();
+        let blocks: Vec<Block> = (0..=11)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+
+        // Drop part of the UTXO set, add a bogus entry and point a height at the wrong block
+        let utxos = chain.bc().utxos_db();
+        let first = utxos.first().unwrap().unwrap().0;
+        utxos.remove(first).unwrap();
+        utxos.insert("bogus", vec![1, 2, 3]).unwrap();
+        let heights = chain.bc().db.open_tree(HEIGHTS_TREE).unwrap();
+        heights
+            .insert(3u32.to_be_bytes(), blocks[7].get_hash().as_bytes())
+            .unwrap();
+        heights.remove(5u32.to_be_bytes()).unwrap();
+
+        chain.bc().replay().unwrap();
+        chain.utxo.clear_cache();
+
+        assert_eq!(chain.utxo.count_transactions().unwrap(), utxo_count);
+        assert_eq!(chain.utxo.get_balance(&chain.miner).unwrap(), miner_balance);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 30);
+        for (height, block) in blocks.iter().enumerate() {
+            let indexed = chain.bc().get_block_by_height(height).unwrap();
+            assert_eq!(indexed.get_hash(), block.get_hash());
+        }
+    }
+
+    #[test]
+    fn import_verifies_according_to_the_level() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let prefix: Vec<Block> = (0..=10)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+
+        // A mined block carrying a payment with a broken signature
+        let mut tx = chain.send(&chain.miner, &alice, 30);
+        tx.vin[0].signature[0] ^= 1;
+        let bad_signature = Block::new_block(
+            vec![chain.coinbase(&chain.miner), tx],
+            chain.bc().get_tip_hash(),
+            11,
+            chain.bc().get_params().difficulty,
+        )
+        .unwrap();
+
+        // A block whose hash no longer matches its timestamp
+        let mut data = bincode::serialize(&chain.mine(Vec::new())).unwrap();
+        data[0] ^= 1;
+        let bad_pow: Block = bincode::deserialize(&data).unwrap();
+
+        let import = |block: &Block, level: VerificationLevel| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut bc = Blockchain::open(dir.path(), ChainParams::devnet()).unwrap();
+            bc.import(prefix.clone(), VerificationLevel::Full).unwrap();
+            bc.import(vec![block.clone()], level)
+        };
+
+        assert!(import(&bad_signature, VerificationLevel::Full).is_err());
+        assert!(import(&bad_signature, VerificationLevel::PowOnly).is_ok());
+        assert!(import(&bad_pow, VerificationLevel::PowOnly).is_err());
+        assert!(import(&bad_pow, VerificationLevel::TrustedNoVerify).is_ok());
+    }
+
+    #[test]
+    fn extends_the_chain_right_after_an_import() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let blocks: Vec<Block> = (0..=10)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let next = chain.mine(vec![tx]);
+
+        // The UTXO set follows the imported blocks, no replay needed
+        for level in [
+            VerificationLevel::Full,
+            VerificationLevel::PowOnly,
+            VerificationLevel::TrustedNoVerify,
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut bc = Blockchain::open(dir.path(), ChainParams::devnet()).unwrap();
+            bc.import(blocks.clone(), level).unwrap();
+            assert_eq!(
+                bc.add_block(next.clone()).unwrap(),
+                BlockAcceptResult::Extended
+            );
+        }
+    }
+
+    #[test]
+    fn import_checks_the_consensus_rules_of_each_block() {
+        let mut chain = TestChain::new();
+        chain.mine_empty(2);
+        let blocks: Vec<Block> = (0..=2)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+        let tip = chain.bc().get_tip_hash();
+        let block = |height: u32, difficulty: usize| {
+            Block::new_block(
+                vec![chain.coinbase(&chain.miner)],
+                tip.clone(),
+                height,
+                difficulty,
+            )
+            .unwrap()
+        };
+        let difficulty = chain.bc().get_params().difficulty;
+
+        // Mined below the difficulty of the network, or not right above the tip
+        let easy = block(3, difficulty - 1);
+        let skipping = block(5, difficulty);
+        for bad in [easy, skipping] {
+            let import = |level| {
+                let dir = tempfile::tempdir().unwrap();
+                let mut bc = Blockchain::open(dir.path(), ChainParams::devnet()).unwrap();
+                bc.import(blocks.clone(), VerificationLevel::TrustedNoVerify)
+                    .unwrap();
+                bc.import(vec![bad.clone()], level)
             };
+            assert!(import(VerificationLevel::Full).is_err());
+            assert!(import(VerificationLevel::PowOnly).is_err());
+            assert!(import(VerificationLevel::TrustedNoVerify).is_ok());
+        }
+    }
+
+    #[test]
+    fn pays_a_coinbase_to_several_outputs_within_the_reward() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let height = chain.next_height();
+        let reward = coinbase_reward(height);
+
+        let outputs = [(chain.miner.clone(), reward - 10), (alice.clone(), 10)];
+        let coinbase = Transaction::new_coinbase_multi(&outputs, String::new(), height, 0).unwrap();
+        let block = chain
+            .utxo
+            .blockchain
+            .mine_block(vec![coinbase.clone()])
+            .unwrap();
+        chain.utxo.update(&block).unwrap();
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 10);
+
+        // Outputs must total the reward and the fees exactly
+        let height = height + 1;
+        let multi = |outputs: &[(String, i32)], fees| {
+            Transaction::new_coinbase_multi(outputs, String::new(), height, fees)
+        };
+        let over = [(chain.miner.clone(), reward), (alice.clone(), 10)];
+        assert!(multi(&over, 0).is_err());
+        assert!(multi(&over, 10).is_ok());
+        assert!(multi(&outputs, 10).is_err());
+
+        // A negative output can't hide an oversized one, nor can dust be paid
+        let negative = [(chain.miner.clone(), reward + 50), (alice.clone(), -50)];
+        assert!(multi(&negative, 0).is_err());
+        let dust = [(chain.miner.clone(), reward - 1), (alice.clone(), 1)];
+        assert!(multi(&dust, 0).is_err());
+        let zero = [(chain.miner.clone(), reward), (alice.clone(), 0)];
+        assert!(multi(&zero, 0).is_err());
+
+        // Outputs totalling more than the reward can't be mined
+        let mut inflated = coinbase;
+        inflated.vout[1].value += 1;
+        inflated.id = inflated.hash().unwrap();
+        assert!(chain.utxo.blockchain.mine_block(vec![inflated]).is_err());
+    }
+
+    #[test]
+    fn requires_a_single_leading_coinbase() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let coinbase = chain.coinbase(&chain.miner);
+        let tip_height = chain.bc().get_best_height().unwrap();
+
+        let layouts = [
+            vec![tx.clone()],
+            vec![tx.clone(), coinbase.clone()],
+            vec![coinbase.clone(), coinbase.clone()],
+        ];
+        for txs in layouts {
+            assert!(chain.bc().new_block_template(txs.clone()).is_err());
+
+            let block = Block::new_block(
+                txs,
+                chain.bc().get_tip_hash(),
+                tip_height + 1,
+                chain.bc().get_params().difficulty,
+            )
+            .unwrap();
+            assert!(chain.utxo.blockchain.add_block(block.clone()).is_err());
+            assert!(chain.utxo.update(&block).is_err());
+        }
+        assert_eq!(chain.bc().get_best_height().unwrap(), tip_height);
+    }
+
+    #[test]
+    fn rejects_spending_immature_coinbase_outputs() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        let tx = chain.spend(&genesis.get_transactions()[0], 0, &chain.miner, &alice, 30);
+
+        // The next block would give the genesis coinbase one confirmation short of maturity
+        chain.mine_empty(COINBASE_MATURITY as usize - 2);
+        assert!(!chain.bc().verify_transaction(&tx).unwrap());
+        assert!(chain
+            .bc()
+            .new_block_template(vec![chain.coinbase(&chain.miner), tx.clone()])
+            .is_err());
+
+        chain.mine(Vec::new());
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+        chain.mine(vec![tx]);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 30);
+    }
+
+    #[test]
+    fn reports_the_fee_of_a_confirmed_transaction() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        let coinbase = genesis.get_transactions()[0].clone();
+
+        // Spending the whole genesis coinbase but 30 leaves the rest as fee
+        let tx = chain.spend(&coinbase, 0, &chain.miner, &alice, 30);
+        let block = chain.mine(vec![tx.clone()]);
+
+        let bc = chain.bc();
+        assert_eq!(bc.transaction_fee(&tx.id).unwrap(), coinbase_reward(0) - 30);
+        assert_eq!(bc.transaction_fee(&coinbase.id).unwrap(), 0);
+        assert_eq!(
+            bc.transaction_fee(&block.get_transactions()[0].id).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn rejects_orphan_blocks() {
+        let mut chain = TestChain::new();
+        let orphan = Block::new_block(
+            vec![chain.coinbase(&chain.miner)],
+            "00".repeat(32),
+            chain.next_height() as u32,
+            chain.bc().get_params().difficulty,
+        )
+        .unwrap();
+
+        let tip = chain.bc().get_tip_hash();
+        let bc = &mut chain.utxo.blockchain;
+        let err = bc.add_block(orphan.clone()).unwrap_err();
+        assert!(err.to_string().contains("is unknown"), "{}", err);
+        assert!(!bc.has_block(&orphan.get_hash()).unwrap());
+        assert_eq!(bc.get_tip_hash(), tip);
+    }
+
+    #[test]
+    fn refuses_to_reorganize_to_a_chain_spending_an_output_twice() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tip = chain.bc().get_tip_hash();
+        let fork_point = chain.bc().get_block_by_height(9).unwrap().get_hash();
+        let utxo_count = chain.utxo.count_transactions().unwrap();
+
+        // A competing chain whose first block includes the same payment twice
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let coinbase = |height: usize| {
+            Transaction::new_coinbase_in(&chain.wallets, chain.miner.clone(), "fork".into(), height)
+                .unwrap()
+        };
+        let difficulty = chain.bc().get_params().difficulty;
+        let bad = Block::new_block(
+            vec![coinbase(10), tx.clone(), tx],
+            fork_point,
+            10,
+            difficulty,
+        )
+        .unwrap();
+        let next = Block::new_block(vec![coinbase(11)], bad.get_hash(), 11, difficulty).unwrap();
+
+        // Either block may trigger the reorganization, the tie on work breaks by hash
+        let bc = &mut chain.utxo.blockchain;
+        let results = [bc.add_block(bad.clone()), bc.add_block(next.clone())];
+        assert!(results.iter().any(|result| result.is_err()));
+
+        assert_eq!(bc.get_tip_hash(), tip);
+        assert!(!bc.has_block(&bad.get_hash()).unwrap());
+        assert!(!bc.has_block(&next.get_hash()).unwrap());
+        assert!(bc.reorg_history().unwrap().is_empty());
+        assert_eq!(chain.utxo.count_transactions().unwrap(), utxo_count);
+    }
+
+    #[test]
+    fn replay_fails_on_a_block_spending_a_missing_output() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let block = Block::new_block(
+            vec![chain.coinbase(&chain.miner), tx.clone(), tx],
+            chain.bc().get_tip_hash(),
+            chain.next_height() as u32,
+            chain.bc().get_params().difficulty,
+        )
+        .unwrap();
+
+        // Import refuses the block, store it as the tip behind its back
+        let bc = &mut chain.utxo.blockchain;
+        assert!(bc
+            .import(vec![block.clone()], VerificationLevel::TrustedNoVerify)
+            .is_err());
+        bc.db
+            .insert(block.get_hash(), bincode::serialize(&block).unwrap())
+            .unwrap();
+        bc.set_tip(&block.get_hash()).unwrap();
+        let err = bc.replay().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BlockchainError>().map(|err| match err {
+                BlockchainError::InvalidBlock { hash, .. } => hash.clone(),
+                _ => String::new(),
+            }),
+            Some(block.get_hash())
+        );
+    }
+
+    #[test]
+    fn settles_on_the_same_tip_whatever_the_arrival_order() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let prefix: Vec<Block> = (0..=10)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+
+        // Two competing blocks at the same height on top of the tip
+        let difficulty = chain.bc().get_params().difficulty;
+        let competing: Vec<Block> = ["left", "right"]
+            .iter()
+            .map(|data| {
+                let coinbase = Transaction::new_coinbase_in(
+                    &chain.wallets,
+                    chain.miner.clone(),
+                    data.to_string(),
+                    11,
+                )
+                .unwrap();
+                Block::new_block(vec![coinbase], chain.bc().get_tip_hash(), 11, difficulty).unwrap()
+            })
+            .collect();
+
+        let tip_after = |order: [usize; 2]| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut bc = Blockchain::open(dir.path(), ChainParams::devnet()).unwrap();
+            bc.import(prefix.clone(), VerificationLevel::Full).unwrap();
+            for idx in order {
+                bc.add_block(competing[idx].clone()).unwrap();
+            }
+            bc.get_tip_hash()
+        };
+
+        let tip = tip_after([0, 1]);
+        assert_eq!(tip, tip_after([1, 0]));
+        let lowest = competing
+            .iter()
+            .map(|block| block.get_hash())
+            .min()
+            .unwrap();
+        assert_eq!(tip, lowest);
+    }
+
+    #[test]
+    fn logs_each_reorganization() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let fork_point = chain.bc().get_block_by_height(9).unwrap().get_hash();
+        let old_tip = chain.bc().get_tip_hash();
+        assert!(chain.bc().reorg_history().unwrap().is_empty());
+
+        // A competing chain two blocks long from height 10
+        let difficulty = chain.bc().get_params().difficulty;
+        let coinbase = |height: usize| {
+            Transaction::new_coinbase_in(&chain.wallets, chain.miner.clone(), "fork".into(), height)
+                .unwrap()
+        };
+        let first = Block::new_block(vec![coinbase(10)], fork_point, 10, difficulty).unwrap();
+        let second =
+            Block::new_block(vec![coinbase(11)], first.get_hash(), 11, difficulty).unwrap();
+
+        let bc = &mut chain.utxo.blockchain;
+        let tie_won = bc.add_block(first.clone()).unwrap() == BlockAcceptResult::Reorganized;
+        bc.add_block(second.clone()).unwrap();
+        assert_eq!(bc.get_tip_hash(), second.get_hash());
+
+        // The tie on the first block may already have switched the tip to it
+        let history = bc.reorg_history().unwrap();
+        let expected = ReorgEvent {
+            old_tip: old_tip.clone(),
+            new_tip: match tie_won {
+                true => first.get_hash(),
+                false => second.get_hash(),
+            },
+            fork_height: Some(9),
+            disconnected: vec![old_tip],
+            connected: match tie_won {
+                true => vec![first.get_hash()],
+                false => vec![first.get_hash(), second.get_hash()],
+            },
+        };
+        assert_eq!(history, vec![expected]);
+    }
+
+    #[test]
+    fn reports_a_database_locked_by_another_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks");
+        let db = open_db(&path).unwrap();
+
+        let err = open_db(&path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(StorageError::Locked(locked)) if *locked == path.display().to_string()
+        ));
+
+        // The lock goes away with the first handle
+        drop(db);
+        assert!(open_db(&path).is_ok());
+    }
+
+    #[test]
+    fn spends_time_locked_transactions_once_the_median_time_passes_the_lock() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        // Locked until just after the current tip
+        let lock = chain.bc().get_block_by_height(10).unwrap().get_timestamp() + 1;
+        let mut tx = chain.send(&chain.miner, &alice, 30);
+        tx.time_lock = Some(lock);
+        chain.resign(&chain.miner, &mut tx);
+        assert!(!chain.bc().verify_transaction(&tx).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        // Five later blocks don't move the median of the last MEDIAN_TIME_SPAN past the lock
+        chain.mine_empty(MEDIAN_TIME_SPAN / 2);
+        assert!(chain.bc().median_time_past(MEDIAN_TIME_SPAN).unwrap() < lock);
+        assert!(!chain.bc().verify_transaction(&tx).unwrap());
+        assert!(chain
+            .bc()
+            .new_block_template(vec![chain.coinbase(&chain.miner), tx.clone()])
+            .is_err());
+
+        chain.mine(Vec::new());
+        assert!(chain.bc().median_time_past(MEDIAN_TIME_SPAN).unwrap() >= lock);
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+        chain.mine(vec![tx]);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 30);
+    }
+
+    #[test]
+    fn rejects_height_locked_transactions_until_the_chain_reaches_the_lock() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        // The id covers the lock time
+        let lock_time = chain.bc().get_best_height().unwrap() + 2;
+        let mut tx = chain.send(&chain.miner, &alice, 30);
+        let unlocked_id = tx.id.clone();
+        tx.lock_time = lock_time;
+        chain.resign(&chain.miner, &mut tx);
+        assert_ne!(tx.id, unlocked_id);
+        assert!(!chain.bc().verify_transaction(&tx).unwrap());
+
+        chain.mine(Vec::new());
+        assert!(!chain.bc().verify_transaction(&tx).unwrap());
+        assert!(chain
+            .bc()
+            .new_block_template(vec![chain.coinbase(&chain.miner), tx.clone()])
+            .is_err());
+
+        chain.mine(Vec::new());
+        assert_eq!(chain.bc().get_best_height().unwrap(), lock_time);
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+        chain.mine(vec![tx]);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 30);
+    }
+
+    #[test]
+    fn counts_the_confirmations_of_a_mined_transaction() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        assert!(chain.bc().find_transaction_location(&tx.id).is_err());
+
+        let block = chain.mine(vec![tx.clone()]);
+        for confirmations in 1..=3 {
+            let (found, hash, depth) = chain.bc().find_transaction_location(&tx.id).unwrap();
+            assert_eq!(found.id, tx.id);
+            assert_eq!(hash, block.get_hash());
+            assert_eq!(depth, confirmations);
+            chain.mine(Vec::new());
+        }
+    }
+
+    #[test]
+    fn walks_the_block_hashes_reading_only_headers() {
+        let mut chain = TestChain::new();
+        chain.mine_empty(3);
+        let hashes: Vec<String> = chain.bc().iter().map(|b| b.get_hash()).collect();
+        assert_eq!(chain.bc().get_block_hashs().unwrap(), hashes);
+
+        // A corrupt block is reported instead of ending the walk early
+        chain.bc().db.insert(&hashes[1], vec![1, 2, 3]).unwrap();
+        let mut iter = chain.bc().iter_hashes();
+        assert_eq!(iter.next().unwrap().unwrap(), hashes[0]);
+        let err = iter.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("corrupt"), "{}", err);
+        assert!(iter.next().is_none());
+        assert!(chain.bc().get_block_hashs().is_err());
+    }
+
+    #[test]
+    fn validate_chain_reports_the_block_with_a_corrupt_nonce() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+        chain.mine_empty(2);
+        chain.bc().validate_chain().unwrap();
+
+        // The nonce follows the timestamp, the previous hash, the hash and the height
+        let block = chain.bc().get_block_by_height(5).unwrap();
+        let mut data = bincode::serialize(&block).unwrap();
+        let offset = 16 + 8 + block.get_prev_hash().len() + 8 + block.get_hash().len() + 4;
+        data[offset] ^= 1;
+        chain.bc().db.insert(block.get_hash(), data).unwrap();
+        assert_eq!(
+            chain.bc().get_block(&block.get_hash()).unwrap().get_nonce(),
+            block.get_nonce() ^ 1
+        );
+
+        let err = chain.bc().validate_chain().unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(BlockchainError::InvalidProofOfWork(hash)) if *hash == block.get_hash()
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn iterator_reports_a_corrupt_block_instead_of_a_shorter_chain() {
+        let mut chain = TestChain::new();
+        chain.mine_empty(3);
+        let hashes: Vec<String> = chain.bc().iter().map(|b| b.get_hash()).collect();
+
+        // The walk ends after the genesis block
+        let mut iter = chain.bc().iter();
+        for hash in &hashes {
+            assert_eq!(iter.try_next().unwrap().unwrap().get_hash(), *hash);
+        }
+        assert!(iter.try_next().unwrap().is_none());
+
+        // Garbage written under the hash of a block
+        chain.bc().db.insert(&hashes[2], vec![0xff; 7]).unwrap();
+        let mut iter = chain.bc().iter();
+        iter.try_next().unwrap().unwrap();
+        iter.try_next().unwrap().unwrap();
+        let err = iter.try_next().unwrap_err();
+        assert!(err.to_string().contains(&hashes[2]), "{}", err);
+        assert!(err.to_string().contains("corrupt"), "{}", err);
+        assert!(chain.bc().get_block(&hashes[2]).is_err());
+    }
+
+    #[test]
+    fn reports_the_tip_timestamp_and_the_median_time_past() {
+        let mut chain = TestChain::new();
+        let genesis = chain.bc().get_block_by_height(0).unwrap().get_timestamp();
+        assert_eq!(chain.bc().get_tip_timestamp().unwrap(), genesis);
+
+        // A chain shorter than the span uses all of its blocks
+        assert_eq!(
+            chain.bc().median_time_past(MEDIAN_TIME_SPAN).unwrap(),
+            genesis
+        );
+        assert!(chain.bc().median_time_past(0).is_err());
+
+        let mut timestamps = vec![genesis];
+        for _ in 0..4 {
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            timestamps.push(chain.mine(Vec::new()).get_timestamp());
+        }
+        assert_eq!(chain.bc().get_tip_timestamp().unwrap(), timestamps[4]);
+        assert_eq!(chain.bc().median_time_past(1).unwrap(), timestamps[4]);
+        assert_eq!(chain.bc().median_time_past(3).unwrap(), timestamps[3]);
+        assert_eq!(chain.bc().median_time_past(5).unwrap(), timestamps[2]);
+        assert_eq!(
+            chain.bc().median_time_past(MEDIAN_TIME_SPAN).unwrap(),
+            timestamps[2]
+        );
+    }
+
+    #[test]
+    fn rejects_blocks_dated_too_far_in_the_future_or_in_the_past() {
+        let mut chain = TestChain::new();
+        chain.mine_empty(3);
+        let tip = chain.bc().get_tip_hash();
+        let block_at = |chain: &TestChain, timestamp: u128| {
+            Block::new_block_at(
+                vec![chain.coinbase(&chain.miner)],
+                tip.clone(),
+                chain.next_height() as u32,
+                chain.bc().get_params().difficulty,
+                timestamp,
+            )
+            .unwrap()
+        };
+
+        let year = 365 * 24 * 60 * 60 * 1000;
+        let ahead = block_at(&chain, now_millis().unwrap() + year);
+        let err = chain.utxo.blockchain.add_block(ahead).unwrap_err();
+        assert!(err.to_string().contains("too far in the future"), "{}", err);
+
+        let median = chain.bc().median_time_past(MEDIAN_TIME_SPAN).unwrap();
+        let stale = block_at(&chain, median);
+        let err = chain.utxo.blockchain.add_block(stale).unwrap_err();
+        assert!(err.to_string().contains("median time past"), "{}", err);
+        assert_eq!(chain.bc().get_tip_hash(), tip);
+
+        // A clock slightly ahead of ours is tolerated
+        let ahead = block_at(&chain, now_millis().unwrap() + MAX_FUTURE_BLOCK_TIME / 2);
+        chain.utxo.blockchain.add_block(ahead.clone()).unwrap();
+        assert_eq!(chain.bc().get_tip_hash(), ahead.get_hash());
+    }
+
+    #[test]
+    fn fetches_a_block_by_its_height() {
+        let mut chain = TestChain::new();
+        let blocks: Vec<Block> = (0..5).map(|_| chain.mine(Vec::new())).collect();
+
+        let block = chain.bc().get_block_by_height(3).unwrap();
+        assert_eq!(block.get_hash(), blocks[2].get_hash());
+        assert_eq!(block.get_height(), 3);
+        assert!(chain.bc().get_block_by_height(6).is_err());
+    }
+
+    #[test]
+    fn rejects_blocks_not_right_above_their_previous_block() {
+        let mut chain = TestChain::new();
+        chain.mine_empty(3);
+        let tip = chain.bc().get_tip_hash();
+
+        // The height of a block must follow the height of its previous block
+        for height in [3, 5, 2] {
+            let block = Block::new_block(
+                vec![chain.coinbase(&chain.miner)],
+                tip.clone(),
+                height,
+                chain.bc().get_params().difficulty,
+            )
+            .unwrap();
+            let err = chain.utxo.blockchain.add_block(block).unwrap_err();
+            assert!(err.to_string().contains("previous block"), "{}", err);
         }
-        None
+        assert_eq!(chain.bc().get_tip_hash(), tip);
+        assert_eq!(chain.bc().get_best_height().unwrap(), 3);
+        assert!(chain.bc().get_block_by_height(5).is_err());
+    }
+
+    #[test]
+    fn reports_the_genesis_the_chain_was_created_with() {
+        let before = now_millis().unwrap();
+        let mut chain = TestChain::new();
+        let after = now_millis().unwrap();
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        chain.mine_empty(3);
+
+        let info = chain.bc().genesis_info().unwrap();
+        assert_eq!(info.hash, genesis.get_hash());
+        assert!(before <= info.timestamp && info.timestamp <= after);
+        assert_eq!(info.coinbase_data, GENESIS_COINBASE_DATA);
+        assert_eq!(info.recipient, chain.miner);
+
+        // Another chain has its own genesis
+        let other = TestChain::new();
+        assert_ne!(other.bc().genesis_info().unwrap().hash, info.hash);
+    }
+
+    #[test]
+    fn prefers_a_shorter_chain_with_more_work() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let fork_point = chain.bc().get_block_by_height(8).unwrap().get_hash();
+        let work = chain.bc().chain_work();
+
+        // One block twice as hard replaces the two easier blocks above the fork point
+        let coinbase =
+            Transaction::new_coinbase_in(&chain.wallets, chain.miner.clone(), "hard".into(), 9)
+                .unwrap();
+        let difficulty = chain.bc().get_params().difficulty;
+        let hard = Block::new_block(vec![coinbase], fork_point, 9, difficulty * 2).unwrap();
+        assert!(block_work(difficulty * 2) > 2 * block_work(difficulty));
+
+        let bc = &mut chain.utxo.blockchain;
+        assert_eq!(
+            bc.add_block(hard.clone()).unwrap(),
+            BlockAcceptResult::Reorganized
+        );
+        assert_eq!(bc.get_tip_hash(), hard.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 9);
+        assert_eq!(
+            bc.chain_work(),
+            work - 2 * block_work(difficulty) + block_work(difficulty * 2)
+        );
+    }
+
+    #[test]
+    fn keeps_independent_chains_in_their_own_directories() {
+        let mut first = TestChain::new();
+        let second = TestChain::new();
+        first.mature();
+        let alice = first.address();
+        let tx = first.send(&first.miner, &alice, 30);
+        first.mine(vec![tx]);
+
+        // Nothing done on the first chain shows on the second
+        assert_eq!(second.bc().get_best_height().unwrap(), 0);
+        assert_eq!(
+            second.utxo.get_balance(&second.miner).unwrap(),
+            coinbase_reward(0)
+        );
+        assert_ne!(first.bc().get_tip_hash(), second.bc().get_tip_hash());
+
+        // The first chain reopens from its directory with its own tip and UTXO set
+        let tip = first.bc().get_tip_hash();
+        let TestChain { dir, utxo, .. } = first;
+        drop(utxo);
+        let bc = Blockchain::new_with_path(dir.path()).unwrap();
+        assert_eq!(bc.get_tip_hash(), tip);
+        let utxo = UTXOSet::new(bc).unwrap();
+        assert_eq!(utxo.get_balance(&alice).unwrap(), 30);
+    }
+
+    #[test]
+    fn mines_dependent_transactions_in_one_block() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let bob = chain.address();
+        let prefix: Vec<Block> = (0..=10)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+
+        // Bob is paid from the output Alice receives earlier in the same block
+        let parent = chain.send(&chain.miner, &alice, 30);
+        let child = chain.spend(&parent, 0, &alice, &bob, 20);
+        let block = chain.mine(vec![parent, child]);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 0);
+        assert_eq!(chain.utxo.get_balance(&bob).unwrap(), 20);
+
+        // Another node accepts the block and applies it the same way
+        let dir = tempfile::tempdir().unwrap();
+        let mut bc = Blockchain::open(dir.path(), ChainParams::devnet()).unwrap();
+        bc.import(prefix, VerificationLevel::Full).unwrap();
+        assert_eq!(
+            bc.add_block(block.clone()).unwrap(),
+            BlockAcceptResult::Extended
+        );
+        let utxo = UTXOSet::new(bc).unwrap();
+        utxo.update(&block).unwrap();
+        assert_eq!(utxo.get_balance(&bob).unwrap(), 20);
+    }
+
+    #[test]
+    fn rejects_blocks_committing_to_another_utxo_set() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let coinbase = chain.coinbase(&chain.miner);
+        let mut block = chain.bc().new_block_template(vec![coinbase, tx]).unwrap();
+        assert!(!block.get_utxo_root().is_empty());
+
+        // The commitment is covered by the proof of work, it can't be swapped after mining
+        let mut swapped = block.clone();
+        swapped.mine(&AtomicBool::new(false)).unwrap();
+        swapped.commit_utxos(vec![0; 32]);
+        assert!(!swapped.verify_pow().unwrap());
+        assert!(!swapped.get_header().verify_pow().unwrap());
+
+        // A block mined with a wrong commitment is rejected before the tip moves
+        let root = block.get_utxo_root();
+        let mut wrong = block.clone();
+        wrong.commit_utxos(vec![0; 32]);
+        wrong.mine(&AtomicBool::new(false)).unwrap();
+        let bc = &mut chain.utxo.blockchain;
+        let err = bc.add_block(wrong.clone()).unwrap_err();
+        assert!(err.to_string().contains("UTXO commitment"), "{}", err);
+        assert_ne!(bc.get_tip_hash(), wrong.get_hash());
+
+        block.commit_utxos(root);
+        block.mine(&AtomicBool::new(false)).unwrap();
+        assert_eq!(
+            bc.add_block(block.clone()).unwrap(),
+            BlockAcceptResult::Extended
+        );
+        chain.utxo.update(&block).unwrap();
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 30);
+    }
+
+    #[test]
+    fn rejects_blocks_spending_outputs_the_utxo_set_lacks() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx.clone()]);
+
+        // The same payment again, its inputs are spent already
+        let block = Block::new_block(
+            vec![chain.coinbase(&chain.miner), tx],
+            chain.bc().get_tip_hash(),
+            chain.next_height() as u32,
+            chain.bc().get_params().difficulty,
+        )
+        .unwrap();
+
+        let tip = chain.bc().get_tip_hash();
+        let bc = &mut chain.utxo.blockchain;
+        assert!(bc.add_block(block.clone()).is_err());
+        assert_eq!(bc.get_tip_hash(), tip);
+        assert!(!bc.has_block(&block.get_hash()).unwrap());
+    }
+
+    #[test]
+    fn prints_the_chain_into_a_writer() {
+        let mut chain = TestChain::new();
+        let first = chain.mine(Vec::new());
+        let second = chain.mine(Vec::new());
+
+        let mut out = Vec::new();
+        assert_eq!(chain.bc().print_chain(&mut out).unwrap(), 3);
+        let printed = String::from_utf8(out).unwrap();
+        assert_eq!(printed.matches("\nBlock ").count() + 1, 3);
+
+        // Blocks are printed from the tip down to the genesis block
+        let tip = printed
+            .find(&format!("Block {}", second.get_hash()))
+            .unwrap();
+        let parent = printed
+            .find(&format!("Block {}", first.get_hash()))
+            .unwrap();
+        assert!(printed.starts_with("Block ") && tip < parent);
+        assert!(printed.contains("  height:    0"));
+        assert!(printed.contains(&format!("  prev hash: {}", first.get_hash())));
+        assert!(printed.contains(GENESIS_COINBASE_DATA));
+    }
+
+    #[test]
+    fn retargets_the_difficulty_by_one_step() {
+        let target = 9 * 60_000;
+        assert_eq!(retarget(4, target / 2 - 1, target, 1), 5);
+        assert_eq!(retarget(4, target / 2, target, 1), 4);
+        assert_eq!(retarget(4, target * 2, target, 1), 4);
+        assert_eq!(retarget(4, target * 2 + 1, target, 1), 3);
+        assert_eq!(retarget(4, u128::MAX, target, 4), 4);
+    }
+
+    #[test]
+    fn retargets_according_to_the_target_block_time() {
+        // Blocks mined within milliseconds are far too fast for an hour per block
+        let slow = ChainParams {
+            target_block_time: 3600,
+            ..ChainParams::devnet()
+        };
+        let mut chain = TestChain::with_params(slow);
+        chain.mine_empty(RETARGET_INTERVAL as usize - 2);
+        let difficulty = chain.bc().get_params().difficulty;
+        let tip = chain.bc().get_tip_hash();
+        assert_eq!(chain.bc().next_difficulty(&tip).unwrap(), difficulty);
+
+        // The first block of the next interval must be harder
+        let coinbase = chain.coinbase(&chain.miner);
+        let easy = Block::new_block(
+            vec![coinbase],
+            tip.clone(),
+            RETARGET_INTERVAL - 1,
+            difficulty,
+        )
+        .unwrap();
+        assert!(chain.utxo.blockchain.add_block(easy).is_ok());
+        let tip = chain.bc().get_tip_hash();
+        assert_eq!(chain.bc().next_difficulty(&tip).unwrap(), difficulty + 1);
+
+        let coinbase = chain.coinbase(&chain.miner);
+        let easy = Block::new_block(vec![coinbase], tip, RETARGET_INTERVAL, difficulty).unwrap();
+        assert!(matches!(
+            chain
+                .utxo
+                .blockchain
+                .add_block(easy)
+                .unwrap_err()
+                .downcast_ref(),
+            Some(BlockchainError::InvalidProofOfWork(_))
+        ));
+        let block = chain.mine(Vec::new());
+        assert_eq!(block.get_difficulty(), difficulty + 1);
+
+        // Without a target block time the difficulty stays at the minimum
+        let mut chain = TestChain::new();
+        chain.mine_empty(RETARGET_INTERVAL as usize);
+        let difficulty = chain.bc().get_params().difficulty;
+        assert_eq!(chain.mine(Vec::new()).get_difficulty(), difficulty);
     }
 }
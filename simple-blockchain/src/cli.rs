@@ -1,11 +1,13 @@
-use crate::blockchain::Blockchain;
+use crate::block::{leading_zeros, verify_merkle_proof};
+use crate::blockchain::{Blockchain, DATA_DIR};
 use crate::errors::Result;
-use crate::server::Server;
-use crate::transaction::Transaction;
+use crate::server::{Server, KNOWN_NODE, MAX_BLOCKS_BEHIND};
+use crate::transaction::{ChangePolicy, Transaction};
 use crate::utxoset::UTXOSet;
 use crate::wallet::Wallets;
-use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use anyhow::anyhow;
+use clap::{arg, ArgMatches, Command};
+use std::io;
 use std::process::exit;
 
 pub struct Cli {}
@@ -20,6 +22,10 @@ impl Cli {
             .author("piatoss3612")
             .about("blockchain in rust: a simple blockchain for learning")
             .arg_required_else_help(true)
+            .arg(
+                arg!(--passphrase <PASSPHRASE> " 'the passphrase the wallet database is encrypted with'")
+                    .global(true),
+            )
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
             .subcommand(Command::new("createwallet").about("create a wallet"))
             .subcommand(Command::new("listaddresses").about("list all addresses"))
@@ -29,10 +35,22 @@ impl Cli {
                     .about("get balance in the blochain")
                     .arg(arg!(<ADDRESS>"'The Address it get balance for'")),
             )
+            .subcommand(
+                Command::new("checkpow")
+                    .about("check the proof of work of a block")
+                    .arg(arg!(<BLOCK_HASH>"'The hash of the block to check'")),
+            )
+            .subcommand(
+                Command::new("txproof")
+                    .about("print the merkle proof of a confirmed transaction")
+                    .arg(arg!(<TXID>"'The ID of the transaction to prove'")),
+            )
             .subcommand(
                 Command::new("startnode")
                     .about("start the node server")
-                    .arg(arg!(<PORT>"'the port server bind to locally'")),
+                    .arg(arg!(<PORT>"'the port server bind to locally'"))
+                    .arg(arg!(--host <HOST> " 'the host the server binds to (default: localhost)'"))
+                    .arg(arg!(--seed <ADDR> " 'the host:port of the node to join the network through'")),
             )
             .subcommand(
                 Command::new("create")
@@ -45,16 +63,25 @@ impl Cli {
                     .arg(arg!(<FROM>" 'Source wallet address'"))
                     .arg(arg!(<TO>" 'Destination wallet address'"))
                     .arg(arg!(<AMOUNT>" 'Destination wallet address'"))
-                    .arg(arg!(-m --mine " 'the from address mine immediately'")),
+                    .arg(arg!(-m --mine " 'the from address mine immediately'"))
+                    .arg(arg!(-c --freshchange " 'send the change to a new address'"))
+                    .arg(arg!(-f --force " 'send even if the node is behind the network or the payment looks duplicate'"))
+                    .arg(
+                        arg!(--maxbehind <BLOCKS> " 'the number of blocks the node may be behind'"),
+                    ),
             )
             .subcommand(
                 Command::new("startminer")
                     .about("start the minner server")
                     .arg(arg!(<PORT>" 'the port server bind to locally'"))
-                    .arg(arg!(<ADDRESS>" 'wallet address'")),
+                    .arg(arg!(<ADDRESS>" 'wallet address'"))
+                    .arg(arg!(--host <HOST> " 'the host the server binds to (default: localhost)'"))
+                    .arg(arg!(--seed <ADDR> " 'the host:port of the node to join the network through'")),
             )
             .get_matches();
 
+        let passphrase = matches.get_one::<String>("passphrase").map(|p| p.as_str());
+
         if let Some(ref matches) = matches.subcommand_matches("startminer") {
             let port = if let Some(port) = matches.get_one::<String>("PORT") {
                 port
@@ -69,23 +96,25 @@ impl Cli {
                 println!("ADDRESS not supply!: usage");
                 exit(1)
             };
+            let (listen_addr, seed_node) = node_addrs(matches, port);
             let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            let utxo_set = UTXOSet::new(bc)?;
+            let server = Server::new(&listen_addr, &seed_node, address, utxo_set)?;
             server.start_server()?;
         }
 
         if let Some(ref matches) = matches.subcommand_matches("startnode") {
             if let Some(port) = matches.get_one::<String>("PORT") {
+                let (listen_addr, seed_node) = node_addrs(matches, port);
                 let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
+                let utxo_set = UTXOSet::new(bc)?;
+                let server = Server::new(&listen_addr, &seed_node, "", utxo_set)?;
                 server.start_server()?;
             }
         }
 
         if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
+            println!("address: {}", cmd_create_wallet(passphrase)?);
         }
         if let Some(_) = matches.subcommand_matches("reindex") {
             let count = cmd_reindex()?;
@@ -93,7 +122,7 @@ impl Cli {
         }
 
         if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
+            cmd_list_address(passphrase)?;
         }
 
         if let Some(ref matches) = matches.subcommand_matches("create") {
@@ -131,17 +160,46 @@ impl Cli {
                 exit(1)
             };
 
-            if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+            let change_policy = if matches.get_flag("freshchange") {
+                ChangePolicy::FreshAddress
             } else {
-                cmd_send(from, to, amount, false)?;
-            }
+                ChangePolicy::SameAddress
+            };
+
+            let max_behind = if matches.get_flag("force") {
+                None
+            } else if let Some(blocks) = matches.get_one::<String>("maxbehind") {
+                Some(blocks.parse()?)
+            } else {
+                Some(MAX_BLOCKS_BEHIND)
+            };
+
+            let options = SendOptions {
+                mine_now: matches.contains_id("mine"),
+                change_policy,
+                max_behind,
+                allow_duplicate: matches.get_flag("force"),
+                passphrase,
+            };
+            cmd_send(from, to, amount, options)?;
 
             /*else {
                 println!("Not printing testing lists...");
             }*/
         }
 
+        if let Some(matches) = matches.subcommand_matches("checkpow") {
+            if let Some(hash) = matches.get_one::<String>("BLOCK_HASH") {
+                cmd_check_pow(hash)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("txproof") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                cmd_tx_proof(txid)?;
+            }
+        }
+
         if let Some(_) = matches.subcommand_matches("printchain") {
             cmd_print_chain()?;
         }
@@ -150,27 +208,79 @@ impl Cli {
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
+// Get the listen address and the seed node of a node from the --host and --seed options
+fn node_addrs(matches: &ArgMatches, port: &str) -> (String, String) {
+    let host = matches
+        .get_one::<String>("host")
+        .map_or("localhost", |h| h.as_str());
+    let seed_node = matches
+        .get_one::<String>("seed")
+        .map_or(KNOWN_NODE, |s| s.as_str());
+
+    (format!("{}:{}", host, port), seed_node.to_string())
+}
+
+// SendOptions struct holds the options of the send command besides the payment itself
+struct SendOptions<'a> {
+    mine_now: bool, // mine the payment right away instead of sending it to a node
+    change_policy: ChangePolicy, // where the change of the payment goes
+    max_behind: Option<i64>, // how many blocks the node may lag behind, None to skip the check
+    allow_duplicate: bool, // send the payment even if an identical one was sent recently
+    passphrase: Option<&'a str>, // passphrase of the wallet database, None if it's not encrypted
+}
+
+fn cmd_send(from: &str, to: &str, amount: i32, options: SendOptions) -> Result<()> {
     let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_utxo(wallet, to, amount, &utxo_set)?;
-    if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
+    let mut utxo_set = UTXOSet::new(bc)?;
+    let mut wallets = Wallets::open_with_passphrase(DATA_DIR, options.passphrase)?;
+
+    // Refuse a payment identical to a recent one, it is likely sent twice by accident
+    if !options.allow_duplicate {
+        check_duplicate_send(&wallets, from, to, amount)?;
+    }
+
+    let tx = Transaction::new_utxo_with_change_in(
+        &mut wallets,
+        from,
+        to,
+        amount,
+        &utxo_set,
+        options.change_policy,
+    )?;
+    if options.mine_now {
+        let height = utxo_set.blockchain.get_best_height()? as usize + 1;
+        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"), height)?;
         let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
 
         utxo_set.update(&new_block)?;
     } else {
-        Server::send_transaction(&tx, utxo_set)?;
+        Server::send_transaction(&tx, utxo_set, options.max_behind)?;
     }
+    wallets.record_send(from, to, amount)?;
 
     println!("success!");
     Ok(())
 }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut ws = Wallets::new()?;
+// Return an error if a payment with the same recipient and amount was sent recently
+// wallets: the wallets remembering the payments
+// from: the address of the sender
+// to: the address of the recipient
+// amount: the amount to send
+fn check_duplicate_send(wallets: &Wallets, from: &str, to: &str, amount: i32) -> Result<()> {
+    if let Some(prev) = wallets.find_duplicate_send(from, to, amount)? {
+        return Err(anyhow!(
+            "payment #{} of {} to {} was already sent recently, use --force to send it again",
+            prev.sequence,
+            amount,
+            to
+        ));
+    }
+    Ok(())
+}
+
+fn cmd_create_wallet(passphrase: Option<&str>) -> Result<String> {
+    let mut ws = Wallets::open_with_passphrase(DATA_DIR, passphrase)?;
     let address = ws.create_wallet();
     ws.save_all()?;
     Ok(address)
@@ -178,7 +288,7 @@ fn cmd_create_wallet() -> Result<String> {
 
 fn cmd_reindex() -> Result<i32> {
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     utxo_set.count_transactions()
 }
@@ -187,35 +297,103 @@ fn cmd_create_blockchain(address: &str) -> Result<()> {
     let address = String::from(address);
     let bc = Blockchain::create_blockchain(address)?;
 
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     println!("create blockchain");
     Ok(())
 }
 
 fn cmd_get_balance(address: &str) -> Result<i32> {
-    let pub_key_hash = Address::decode(address).unwrap().body;
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_utxo(&pub_key_hash)?;
-
-    let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
-    }
-    Ok(balance)
+    let utxo_set = UTXOSet::new(bc)?;
+    utxo_set.get_balance(address)
 }
 
 fn cmd_print_chain() -> Result<()> {
     let bc = Blockchain::new()?;
-    for b in bc.iter() {
-        println!("{:#?}", b);
+    bc.print_chain(&mut io::stdout().lock())?;
+    Ok(())
+}
+
+fn cmd_check_pow(hash: &str) -> Result<()> {
+    let bc = Blockchain::new()?;
+    check_pow(&bc, hash)?;
+    Ok(())
+}
+
+// Print the proof of work of a stored block and return whether it is valid
+// bc: the blockchain storing the block
+// hash: the hash the block is stored under
+fn check_pow(bc: &Blockchain, hash: &str) -> Result<bool> {
+    let block = bc.get_block(hash)?;
+    let computed = block.calculate_hash()?;
+
+    println!("stored hash:   {}", block.get_hash());
+    println!("computed hash: {}", computed);
+    println!(
+        "leading zeros: {} (target: {})",
+        leading_zeros(&computed),
+        block.get_difficulty()
+    );
+    let valid = block.verify_pow()?;
+    if valid {
+        println!("proof of work is valid");
+    } else {
+        println!("proof of work is invalid");
     }
+    Ok(valid)
+}
+
+fn cmd_tx_proof(txid: &str) -> Result<()> {
+    let bc = Blockchain::new()?;
+    tx_proof(&bc, txid)?;
     Ok(())
 }
 
-fn cmd_list_address() -> Result<()> {
-    let ws = Wallets::new()?;
+// Print the merkle proof of a confirmed transaction and return whether it is valid
+// bc: the blockchain the transaction is confirmed in
+// txid: the ID of the transaction
+fn tx_proof(bc: &Blockchain, txid: &str) -> Result<bool> {
+    let block = bc.find_transaction_block(txid)?;
+    let proof = block.merkle_proof(txid)?;
+    let leaf = match block.get_transactions().iter().find(|tx| tx.id == txid) {
+        Some(tx) => tx.hash()?,
+        None => return Err(anyhow!("Transaction {} is not in block", txid)),
+    };
+
+    println!(
+        "block:       {} (height {})",
+        block.get_hash(),
+        block.get_height()
+    );
+    println!("merkle root: {}", to_hex(&block.hash_transactions()?));
+    println!("leaf:        {}", to_hex(leaf.as_bytes()));
+    println!("indices:     {:?}", proof.indices());
+    println!("lemmas:");
+    for lemma in proof.lemmas() {
+        println!("  {}", to_hex(lemma));
+    }
+    let valid = verify_merkle_proof(
+        &block.get_merkle_root(),
+        leaf.as_bytes(),
+        proof.indices()[0],
+        proof.lemmas(),
+    );
+    if valid {
+        println!("merkle proof is valid");
+    } else {
+        println!("merkle proof is invalid");
+    }
+    Ok(valid)
+}
+
+// Encode bytes as a lowercase hex string
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn cmd_list_address(passphrase: Option<&str>) -> Result<()> {
+    let ws = Wallets::open_with_passphrase(DATA_DIR, passphrase)?;
     let addresses = ws.get_all_address();
     println!("addresses: ");
     for ad in addresses {
@@ -223,3 +401,102 @@ fn cmd_list_address() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_duplicate_send, check_pow, tx_proof};
+    use crate::block::Block;
+    use crate::blockchain::VerificationLevel;
+    use crate::test_utils::TestChain;
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn checks_the_proof_of_work_of_stored_blocks() {
+        let mut chain = TestChain::new();
+        let block = chain.mine(Vec::new());
+        assert!(check_pow(chain.bc(), &block.get_hash()).unwrap());
+
+        // Change the timestamp, the first field of a serialized block, keeping the hash
+        let coinbase = chain.coinbase(&chain.miner);
+        let mut next = chain.bc().new_block_template(vec![coinbase]).unwrap();
+        next.mine(&AtomicBool::new(false)).unwrap();
+        let mut data = bincode::serialize(&next).unwrap();
+        data[0] ^= 1;
+        let tampered: Block = bincode::deserialize(&data).unwrap();
+        assert_eq!(tampered.get_hash(), next.get_hash());
+        chain
+            .utxo
+            .blockchain
+            .import(vec![tampered], VerificationLevel::TrustedNoVerify)
+            .unwrap();
+
+        assert!(!check_pow(chain.bc(), &next.get_hash()).unwrap());
+        assert!(check_pow(chain.bc(), "unknown").is_err());
+    }
+
+    #[test]
+    fn proves_the_inclusion_of_a_confirmed_transaction() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        chain.mine_empty(3);
+
+        // Spend the coinbases of the first blocks, so the merkle tree has a few levels
+        let spend = |height: usize| {
+            let block = chain.bc().get_block_by_height(height).unwrap();
+            chain.spend(&block.get_transactions()[0], 0, &chain.miner, &alice, 30)
+        };
+        let txs: Vec<_> = (0..4).map(spend).collect();
+        let paid = txs[1].clone();
+        let block = chain.mine(txs[..3].to_vec());
+        assert!(tx_proof(chain.bc(), &paid.id).unwrap());
+
+        // Fold the proof up to the root without the merkle tree crate: the leaf sits at a node
+        // of the complete binary tree, odd nodes being left children
+        let proof = block.merkle_proof(&paid.id).unwrap();
+        let mut node = proof.indices()[0];
+        let mut hash = paid.hash().unwrap().into_bytes();
+        for lemma in proof.lemmas() {
+            let (left, right) = if node % 2 == 1 {
+                (&hash, lemma)
+            } else {
+                (lemma, &hash)
+            };
+            let mut hasher = Sha256::new();
+            hasher.input(&[left.as_slice(), right.as_slice()].concat());
+            let mut parent = [0; 32];
+            hasher.result(&mut parent);
+            hash = parent.to_vec();
+            node = (node - 1) / 2;
+        }
+        assert_eq!(node, 0);
+        assert_eq!(hash, block.get_merkle_root());
+
+        // Transactions not confirmed in a block have no proof
+        assert!(tx_proof(chain.bc(), &txs[3].id).is_err());
+        assert!(tx_proof(chain.bc(), "unknown").is_err());
+    }
+
+    #[test]
+    fn refuses_a_duplicate_looking_send_unless_forced() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let bob = chain.address();
+        let from = chain.miner.clone();
+        check_duplicate_send(&chain.wallets, &from, &alice, 10).unwrap();
+        assert_eq!(chain.wallets.record_send(&from, &alice, 10).unwrap(), 0);
+
+        // The same recipient and amount again look like a double-send
+        let err = check_duplicate_send(&chain.wallets, &from, &alice, 10).unwrap_err();
+        assert!(err.to_string().contains("--force"), "{}", err);
+
+        // Another amount or recipient is a different payment
+        check_duplicate_send(&chain.wallets, &from, &alice, 11).unwrap();
+        check_duplicate_send(&chain.wallets, &from, &bob, 10).unwrap();
+
+        // A forced send goes through and is recorded with the next sequence number
+        assert_eq!(chain.wallets.record_send(&from, &alice, 10).unwrap(), 1);
+    }
+}
@@ -1 +1,68 @@
+use std::fmt;
+
 pub type Result<T> = anyhow::Result<T>;
+
+// StorageError enum tells why a database could not be opened
+#[derive(Debug)]
+pub enum StorageError {
+    Locked(String),       // The database at the path is locked by another process
+    Corrupt(String),      // The database at the path is corrupt
+    WalletLocked(String), // The wallet database at the path stayed locked by another process
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Locked(path) => {
+                write!(f, "another node is using this data directory: {}", path)
+            }
+            StorageError::WalletLocked(path) => {
+                write!(f, "wallet db is locked by another process: {}", path)
+            }
+            StorageError::Corrupt(path) => write!(
+                f,
+                "database corrupt, remove {} and resync the chain to recover",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+// BlockchainError enum tells why a chain or wallet operation failed
+// It is returned through Result, callers can match on it with downcast_ref
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockchainError {
+    InsufficientBalance { have: i32, need: i32 }, // The spendable outputs don't cover the amount
+    TransactionNotFound(String),                  // No block contains the transaction
+    BlockNotFound(String),                        // The block is not in the database
+    InvalidAddress(String),                       // The address could not be decoded
+    InvalidProofOfWork(String),                   // The hash of the block is wrong or too easy
+    InvalidBlock { hash: String, reason: String }, // The block can't be connected to the chain
+    MiningCancelled, // Mining was stopped before a valid hash was found
+}
+
+impl fmt::Display for BlockchainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockchainError::InsufficientBalance { have, need } => {
+                write!(f, "Not Enough balance for transaction: {} < {}", have, need)
+            }
+            BlockchainError::TransactionNotFound(id) => {
+                write!(f, "Transaction is not found: {}", id)
+            }
+            BlockchainError::BlockNotFound(hash) => write!(f, "Block not found: {}", hash),
+            BlockchainError::InvalidAddress(address) => write!(f, "invalid address: {}", address),
+            BlockchainError::InvalidProofOfWork(hash) => {
+                write!(f, "Invalid proof of work: {}", hash)
+            }
+            BlockchainError::InvalidBlock { hash, reason } => {
+                write!(f, "Invalid block {}: {}", hash, reason)
+            }
+            BlockchainError::MiningCancelled => write!(f, "Mining was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for BlockchainError {}
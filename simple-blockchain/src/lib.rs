@@ -2,8 +2,13 @@ pub mod block;
 pub mod blockchain;
 pub mod cli;
 pub mod errors;
+pub mod mnemonic;
+pub mod params;
 pub mod server;
 pub mod transaction;
 pub mod utils;
 pub mod utxoset;
 pub mod wallet;
+
+#[cfg(test)]
+mod test_utils;
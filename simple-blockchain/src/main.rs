@@ -1,6 +1,18 @@
-use simple_blockchain::{cli, errors::Result};
+use simple_blockchain::{
+    cli,
+    errors::{Result, StorageError},
+};
+use std::process::exit;
 
 fn main() -> Result<()> {
     let mut cli = cli::Cli::new().unwrap();
-    cli.run()
+    if let Err(e) = cli.run() {
+        // Storage errors are expected conditions, print them without the error chain
+        if let Some(err) = e.downcast_ref::<StorageError>() {
+            eprintln!("{}", err);
+            exit(1);
+        }
+        return Err(e);
+    }
+    Ok(())
 }
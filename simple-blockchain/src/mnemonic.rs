@@ -0,0 +1,124 @@
+use crate::errors::Result;
+use anyhow::anyhow;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+const WORDLIST: &str = include_str!("english.txt"); // BIP39 English word list
+const WORD_COUNT: usize = 2048;
+const BITS_PER_WORD: usize = 11;
+const SEED_LENGTH: usize = 32; // length of the seed of a wallet
+const CHECKSUM_BITS: usize = SEED_LENGTH * 8 / 32; // one checksum bit for every 32 bits of seed
+const PHRASE_LENGTH: usize = (SEED_LENGTH * 8 + CHECKSUM_BITS) / BITS_PER_WORD; // 24 words
+
+// Get the words of the word list
+fn words() -> Vec<&'static str> {
+    WORDLIST.lines().collect()
+}
+
+// Get the checksum byte of a seed, the first byte of its SHA-256 hash
+fn checksum(seed: &[u8]) -> u8 {
+    let mut hasher = Sha256::new();
+    hasher.input(seed);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    hash[0]
+}
+
+// Encode a seed into a BIP39 mnemonic phrase
+// The bits of the seed followed by the checksum are split into 11-bit word indices
+// seed: the 32-byte seed of a wallet
+pub fn to_mnemonic(seed: &[u8; SEED_LENGTH]) -> String {
+    let words = words();
+
+    let mut bits = Vec::with_capacity(PHRASE_LENGTH * BITS_PER_WORD);
+    for byte in seed.iter().chain(std::iter::once(&checksum(seed))) {
+        for i in (0..8).rev() {
+            bits.push((byte >> i) & 1 == 1);
+        }
+    }
+
+    bits.chunks(BITS_PER_WORD)
+        .map(|chunk| {
+            let index = chunk
+                .iter()
+                .fold(0usize, |acc, &bit| (acc << 1) | bit as usize);
+            words[index]
+        })
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Decode a BIP39 mnemonic phrase into the seed it encodes
+// phrase: the words of the phrase separated by whitespace
+pub fn from_mnemonic(phrase: &str) -> Result<[u8; SEED_LENGTH]> {
+    let words = words();
+    debug_assert_eq!(words.len(), WORD_COUNT);
+
+    let phrase: Vec<&str> = phrase.split_whitespace().collect();
+    if phrase.len() != PHRASE_LENGTH {
+        return Err(anyhow!(
+            "invalid mnemonic length: {} words (expected {})",
+            phrase.len(),
+            PHRASE_LENGTH
+        ));
+    }
+
+    let mut bits = Vec::with_capacity(PHRASE_LENGTH * BITS_PER_WORD);
+    for word in phrase {
+        // The word list is sorted, so a word can be found by binary search
+        let index = words
+            .binary_search(&word.to_lowercase().as_str())
+            .map_err(|_| anyhow!("unknown mnemonic word: {}", word))?;
+        for i in (0..BITS_PER_WORD).rev() {
+            bits.push((index >> i) & 1 == 1);
+        }
+    }
+
+    let mut bytes = bits
+        .chunks(8)
+        .map(|chunk| chunk.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8));
+
+    let mut seed = [0; SEED_LENGTH];
+    for byte in seed.iter_mut() {
+        *byte = bytes.next().unwrap_or_default();
+    }
+
+    if bytes.next() != Some(checksum(&seed)) {
+        return Err(anyhow!("invalid mnemonic checksum"));
+    }
+
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_mnemonic, to_mnemonic, words};
+
+    #[test]
+    fn encodes_seeds_as_the_bip39_test_vectors() {
+        let phrase = to_mnemonic(&[0; 32]);
+        assert_eq!(phrase, format!("{}art", "abandon ".repeat(23)));
+        assert_eq!(from_mnemonic(&phrase).unwrap(), [0; 32]);
+
+        let phrase = to_mnemonic(&[0x7f; 32]);
+        assert_eq!(
+            phrase,
+            "legal winner thank year wave sausage worth useful legal winner thank year wave \
+             sausage worth useful legal winner thank year wave sausage worth title"
+        );
+        assert_eq!(from_mnemonic(&phrase).unwrap(), [0x7f; 32]);
+    }
+
+    #[test]
+    fn rejects_a_phrase_whose_checksum_misses() {
+        let words = words();
+        let phrase = to_mnemonic(&[0x7f; 32]);
+        let mut typed: Vec<&str> = phrase.split_whitespace().collect();
+
+        // The last word carries the 8 checksum bits, flip the lowest one
+        let last = words.iter().position(|w| *w == typed[23]).unwrap();
+        typed[23] = words[last ^ 1];
+        let err = from_mnemonic(&typed.join(" ")).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+    }
+}
@@ -0,0 +1,58 @@
+use crate::block::TARGET_HEXT;
+use crate::transaction::{DUST_THRESHOLD, MAX_INPUTS, MAX_OUTPUTS};
+
+// ChainParams struct holds the parameters that distinguish one network from another
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    pub magic: [u8; 4],         // Magic bytes prefixed to every network message
+    pub difficulty: usize,      // Number of leading zeros new blocks are mined with
+    pub max_inputs: usize,      // Maximum number of inputs of a transaction
+    pub max_outputs: usize,     // Maximum number of outputs of a transaction
+    pub target_block_time: u64, // Desired number of seconds between two blocks
+    pub dust_threshold: i32,    // Smallest value a non-coinbase output may carry
+}
+
+impl ChainParams {
+    // Parameters of the main network
+    pub fn mainnet() -> Self {
+        Self {
+            magic: [0xf9, 0xbe, 0xb4, 0xd9],
+            difficulty: TARGET_HEXT,
+            max_inputs: MAX_INPUTS,
+            max_outputs: MAX_OUTPUTS,
+            dust_threshold: DUST_THRESHOLD,
+            target_block_time: 60,
+        }
+    }
+
+    // Parameters of the test network
+    pub fn testnet() -> Self {
+        Self {
+            magic: [0x0b, 0x11, 0x09, 0x07],
+            difficulty: 2,
+            max_inputs: MAX_INPUTS,
+            max_outputs: MAX_OUTPUTS,
+            dust_threshold: DUST_THRESHOLD,
+            target_block_time: 30,
+        }
+    }
+
+    // Parameters of a local development network with trivial mining
+    // Blocks are mined as fast as possible, so no target block time raises the difficulty
+    pub fn devnet() -> Self {
+        Self {
+            magic: [0xfa, 0xbf, 0xb5, 0xda],
+            difficulty: 1,
+            max_inputs: MAX_INPUTS,
+            max_outputs: MAX_OUTPUTS,
+            dust_threshold: DUST_THRESHOLD,
+            target_block_time: 0,
+        }
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
@@ -1,31 +1,116 @@
 use anyhow::anyhow;
 use bincode::{deserialize, serialize};
+use log::{error, info, warn};
+use rand::random;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::{block::Block, errors::Result, transaction::Transaction, utxoset::UTXOSet};
+use crate::{
+    block::{Block, BlockHeader},
+    blockchain::{difficulty_after, BlockAcceptResult, RETARGET_INTERVAL},
+    errors::{BlockchainError, Result},
+    params::ChainParams,
+    transaction::{OutPoint, Transaction},
+    utxoset::UTXOSet,
+};
 
-const KNOWN_NODE: &str = "localhost:3000";
+pub const KNOWN_NODE: &str = "localhost:3000"; // default seed node
 const CMD_LENGTH: usize = 12;
+const MAGIC_LENGTH: usize = 4;
+const MAX_FRAME_LENGTH: usize = 32 * 1024 * 1024; // largest message accepted from a peer
+pub const MAX_BLOCKS_BEHIND: i64 = 1; // default number of blocks a node may be behind to send
 const VERSION: u32 = 1;
-
+const VERSION_TOLERANCE: u32 = 0; // how far the version of a peer may be from VERSION
+const WORKER_COUNT: usize = 8; // number of threads handling connections
+const WORKER_NAME: &str = "worker"; // name of the threads handling connections, followed by the port
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10); // time a peer may stay silent or stop reading
+const SEND_PORT: &str = "7000"; // port of the temporary node broadcasting a transaction
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1); // time to wait for the version of the known node
+const PING_INTERVAL: Duration = Duration::from_secs(30); // delay between two rounds of pings
+const PING_TIMEOUT: u128 = 60_000; // milliseconds a peer has to answer a ping before it's dropped
+pub const MAX_BLOCK_BYTES: usize = 1_000_000; // default size limit of the transactions of a mined block
+const MAX_ORPHANS: usize = 100; // largest number of orphan transactions kept
+const ORPHAN_EXPIRY: u128 = 20 * 60 * 1000; // milliseconds an orphan transaction is kept
+
+#[derive(Clone)]
 pub struct Server {
     node_addr: String,
+    seed_node: String,
     miner_addr: String,
+    params: ChainParams,
     inner: Arc<Mutex<ServerInner>>,
 }
 
 struct ServerInner {
-    known_nodes: HashSet<String>,
+    known_nodes: HashMap<String, PeerInfo>,
     utxo: UTXOSet,
     blocks_in_transit: Vec<String>,
     mempool: HashMap<String, Transaction>,
+    mempool_spends: HashMap<(String, OutPoint), String>, // output -> mempool transaction spending it
+    orphans: HashMap<String, (Transaction, u128)>, // orphan transactions and their time (ms) of arrival
+    addrs_sent: HashMap<String, HashSet<String>>,  // node -> addresses already sent to it
+    pings: HashMap<String, (u64, u128)>, // node -> nonce and time (ms) of the unanswered ping
+    headers: HashMap<String, Vec<BlockHeader>>, // peer socket address -> headers of its chain, from the genesis block
+    mining: Option<Arc<AtomicBool>>,            // cancel flag of the block being mined, if any
+}
+
+impl ServerInner {
+    // Remove a transaction from the mempool along with the outputs it spends
+    fn remove_mempool(&mut self, txid: &str) {
+        if let Some(tx) = self.mempool.remove(txid) {
+            for vin in &tx.vin {
+                self.mempool_spends.remove(&(vin.txid.clone(), vin.vout));
+            }
+        }
+    }
+
+    // Check if every transaction the inputs refer to is in the mempool or the blockchain
+    fn parents_known(&self, tx: &Transaction) -> bool {
+        tx.vin.iter().all(|vin| {
+            self.mempool.contains_key(&vin.txid)
+                || self.utxo.blockchain.find_transaction(&vin.txid).is_ok()
+        })
+    }
+}
+
+// MempoolAcceptResult enum describes the outcome of submitting a transaction to the mempool
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolAcceptResult {
+    Accepted,         // The transaction was added to the mempool
+    AlreadyKnown,     // The transaction is already in the mempool
+    Rejected(String), // The transaction is invalid, with the reason
+    Orphan,           // The transaction spends outputs of unknown transactions
+}
+
+// PeerInfo struct holds the metadata of a known node
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub addr: String,             // Address of the node
+    pub last_seen: Option<u128>, // Time of the last version message in milliseconds since the Unix Epoch
+    pub version: Option<u32>,    // Protocol version reported by the node
+    pub best_height: Option<u32>, // Best height reported by the node
+}
+
+impl PeerInfo {
+    fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            last_seen: None,
+            version: None,
+            best_height: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -66,6 +151,34 @@ struct VersionMsg {
     best_height: u32,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PingMsg {
+    addr_from: String,
+    nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PongMsg {
+    addr_from: String,
+    nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MemPoolMsg {
+    addr_from: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetHeadersMsg {
+    addr_from: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HeadersMsg {
+    addr_from: String,
+    headers: Vec<BlockHeader>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum ServerMessage {
     Addr(Vec<String>),
@@ -75,67 +188,416 @@ enum ServerMessage {
     GetBlocks(GetBlocksMsg),
     Inv(InvMsg),
     Block(BlockMsg),
+    MemPool(MemPoolMsg),
+    Ping(PingMsg),
+    Pong(PongMsg),
+    GetHeaders(GetHeadersMsg),
+    Headers(HeadersMsg),
 }
 
 impl Server {
-    pub fn new(port: &str, miner_addr: &str, utxo: UTXOSet) -> Result<Self> {
-        let mut node_set = HashSet::new();
-        node_set.insert(KNOWN_NODE.to_string());
+    // Create a server
+    // listen_addr: the host:port the node binds to, which is also its address for peers
+    // seed_node: the host:port of the node to join the network through
+    // miner_addr: the address mining rewards are sent to, empty if the node doesn't mine
+    pub fn new(
+        listen_addr: &str,
+        seed_node: &str,
+        miner_addr: &str,
+        utxo: UTXOSet,
+    ) -> Result<Self> {
+        let params = utxo.blockchain.get_params().clone();
+        Self::new_with_params(listen_addr, seed_node, miner_addr, utxo, params)
+    }
+
+    // Create a server listening on localhost and joining through KNOWN_NODE
+    // port: the port the node binds to
+    pub fn new_local(port: &str, miner_addr: &str, utxo: UTXOSet) -> Result<Self> {
+        Self::new(&format!("localhost:{}", port), KNOWN_NODE, miner_addr, utxo)
+    }
+
+    pub fn new_with_params(
+        listen_addr: &str,
+        seed_node: &str,
+        miner_addr: &str,
+        utxo: UTXOSet,
+        params: ChainParams,
+    ) -> Result<Self> {
+        let mut node_set = HashMap::new();
+        node_set.insert(seed_node.to_string(), PeerInfo::new(seed_node));
+
+        // Resume the blocks that were in transit before the node stopped
+        let blocks_in_transit = utxo.blockchain.load_in_transit()?;
 
         Ok(Self {
-            node_addr: format!("localhost:{}", port),
+            node_addr: listen_addr.to_string(),
+            seed_node: seed_node.to_string(),
             miner_addr: miner_addr.to_string(),
+            params,
             inner: Arc::new(Mutex::new(ServerInner {
                 known_nodes: node_set,
                 utxo,
-                blocks_in_transit: Vec::new(),
+                blocks_in_transit,
                 mempool: HashMap::new(),
+                mempool_spends: HashMap::new(),
+                orphans: HashMap::new(),
+                addrs_sent: HashMap::new(),
+                pings: HashMap::new(),
+                headers: HashMap::new(),
+                mining: None,
             })),
         })
     }
 
     pub fn start_server(&self) -> Result<()> {
-        let srv = Self {
-            node_addr: self.node_addr.clone(),
-            miner_addr: self.miner_addr.clone(),
-            inner: self.inner.clone(),
-        };
+        let listener = TcpListener::bind(&self.node_addr)?;
+
+        // Keep the known nodes healthy by pinging them periodically
+        let srv = self.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(PING_INTERVAL);
+            if let Err(e) = srv.ping_peers() {
+                warn!("failed to ping peers: {}", e);
+            }
+        });
+
+        // Talk to the network once the node can receive the answers
+        let srv = self.clone();
 
         thread::spawn(move || {
-            // TODO
+            // Resume downloading the blocks that were in transit before a restart
+            if let Err(e) = srv.resume_sync() {
+                warn!("failed to resume block download: {}", e);
+            }
+
+            if !srv.is_self(&srv.seed_node) {
+                // Handshake with the seed node, which starts the block sync if needed
+                if let Err(e) = srv.send_version(&srv.seed_node) {
+                    warn!("failed to send version to {}: {}", srv.seed_node, e);
+                }
+
+                // Pull the pending transactions of the network
+                if let Err(e) = srv.request_mempool(&srv.seed_node) {
+                    warn!("failed to request the mempool: {}", e);
+                }
+            }
         });
 
-        let listener = TcpListener::bind(&self.node_addr)?;
+        self.serve(listener)
+    }
+
+    // Handle the connections accepted by the listener until it fails
+    // Accepted connections are handed to a fixed number of workers through a bounded
+    // channel, so accepting waits while all workers are busy and the queue is full
+    // listener: the listener bound to the address of the node
+    fn serve(&self, listener: TcpListener) -> Result<()> {
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(WORKER_COUNT);
+        let worker_name = format!("{}-{}", WORKER_NAME, listener.local_addr()?.port());
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..WORKER_COUNT {
+            let receiver = receiver.clone();
+            let srv = self.clone();
+
+            thread::Builder::new()
+                .name(worker_name.clone())
+                .spawn(move || loop {
+                    // Wait for the next connection, stop once the listener is gone
+                    let stream = match receiver.lock().unwrap().recv() {
+                        Ok(stream) => stream,
+                        Err(_) => break,
+                    };
+
+                    // A panic while handling a connection must not take the worker down
+                    match panic::catch_unwind(AssertUnwindSafe(|| srv.handle_connection(stream))) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => warn!("failed to handle connection: {}", e),
+                        Err(_) => error!("panicked while handling a connection"),
+                    }
+                })?;
+        }
 
         for stream in listener.incoming() {
             let stream = stream?;
-            let srv = Self {
-                node_addr: self.node_addr.clone(),
-                miner_addr: self.miner_addr.clone(),
-                inner: self.inner.clone(),
+            sender.send(stream)?;
+        }
+
+        Ok(())
+    }
+
+    // Broadcast a transaction to the network
+    // max_behind: the number of blocks the node may be behind the network, None to send anyway
+    pub fn send_transaction(
+        tx: &Transaction,
+        utxoset: UTXOSet,
+        max_behind: Option<i64>,
+    ) -> Result<()> {
+        let listen_addr = format!("localhost:{}", SEND_PORT);
+        Self::send_transaction_to(tx, utxoset, &listen_addr, KNOWN_NODE, max_behind)
+    }
+
+    // Broadcast a transaction to the network through the given node
+    // listen_addr: the host:port of the temporary node broadcasting the transaction
+    // seed_node: the host:port of the node to send the transaction to
+    // max_behind: the number of blocks the node may be behind the network, None to send anyway
+    pub fn send_transaction_to(
+        tx: &Transaction,
+        utxoset: UTXOSet,
+        listen_addr: &str,
+        seed_node: &str,
+        max_behind: Option<i64>,
+    ) -> Result<()> {
+        let srv = Self::new(listen_addr, seed_node, "", utxoset)?;
+        let listener = TcpListener::bind(&srv.node_addr)?;
+        listener.set_nonblocking(true)?;
+
+        // Handshake with the known node, sending to an unreachable node drops it
+        srv.send_version(&srv.seed_node)?;
+        if !srv.node_is_known(&srv.seed_node) {
+            return Err(anyhow!("no peer is reachable at {}", srv.seed_node));
+        }
+
+        // The known node answers with its version, it doesn't know this node yet
+        srv.await_version(&listener, HANDSHAKE_TIMEOUT)?;
+
+        // Refuse to send a transaction built on a stale chain
+        if let Some(max_behind) = max_behind {
+            srv.ensure_synced(max_behind)?;
+        }
+
+        srv.send_tx(&srv.seed_node, tx)
+    }
+
+    // Wait for a version message and record the height of its node
+    // Other messages are ignored, and nothing happens if none arrives before the timeout
+    // listener: the non-blocking listener of the node
+    // timeout: how long to wait
+    fn await_version(&self, listener: &TcpListener, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
             };
 
-            thread::spawn(move || {
-                // TODO
-            });
+            // A peer that connects without sending anything can't hold the node past the deadline
+            stream.set_nonblocking(false)?;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(());
+                }
+                stream.set_read_timeout(Some(remaining))?;
+
+                let buffer = match read_frame(&mut stream) {
+                    Ok(Some(buffer)) => buffer,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!(
+                            "failed to read a message while waiting for a version: {}",
+                            e
+                        );
+                        break;
+                    }
+                };
+                if !buffer.starts_with(&self.params.magic) {
+                    continue;
+                }
+                if let Ok(ServerMessage::Version(msg)) = bytes_to_cmd(&buffer[MAGIC_LENGTH..]) {
+                    self.update_peer(&msg);
+                    return Ok(());
+                }
+            }
         }
 
         Ok(())
     }
 
-    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
-        let srv = Self::new("7000", "", utxoset)?;
-        // TODO
+    // Check the node is at most max_behind blocks behind the best height reported by peers
+    pub fn ensure_synced(&self, max_behind: i64) -> Result<()> {
+        let behind = self.blocks_behind();
+        if behind > max_behind {
+            return Err(anyhow!(
+                "node is {} blocks behind the network (max {}), use --force to send anyway",
+                behind,
+                max_behind
+            ));
+        }
+        Ok(())
+    }
+
+    // Submit a transaction to the mempool and report whether it was accepted
+    // The orphan transactions spending its outputs are submitted again once it's accepted
+    pub fn accept_to_mempool(&self, tx: &Transaction) -> Result<MempoolAcceptResult> {
+        let result = self.accept_tx(tx)?;
+        if result == MempoolAcceptResult::Accepted {
+            self.retry_orphans()?;
+        }
+        Ok(result)
+    }
+
+    fn accept_tx(&self, tx: &Transaction) -> Result<MempoolAcceptResult> {
+        // Transactions already in the mempool are not accepted twice
+        if self.get_mempool_tx(&tx.id).is_some() {
+            return Ok(MempoolAcceptResult::AlreadyKnown);
+        }
+
+        // Coinbase transactions are only valid as the first transaction of a block
+        if tx.is_coinbase() {
+            return Ok(MempoolAcceptResult::Rejected(String::from(
+                "coinbase transactions can't enter the mempool",
+            )));
+        }
+
+        // Expired transactions are never accepted into the mempool
+        if tx.is_expired(self.get_best_height()? + 1) {
+            return Ok(MempoolAcceptResult::Rejected(String::from(
+                "transaction is expired",
+            )));
+        }
+
+        // Oversized transactions are rejected before looking up any of their inputs
+        if !tx.within_limits(self.params.max_inputs, self.params.max_outputs) {
+            return Ok(MempoolAcceptResult::Rejected(String::from(
+                "transaction has too many inputs or outputs",
+            )));
+        }
+
+        // Transactions spending outputs of unknown transactions are queued as orphans
+        if !self.parents_known(tx) {
+            self.insert_orphan(tx.clone());
+            return Ok(MempoolAcceptResult::Orphan);
+        }
+
+        // Outputs of confirmed transactions must still be unspent
+        let pending = self.mempool_parents(tx);
+        for vin in tx.vin.iter().filter(|vin| !pending.contains_key(&vin.txid)) {
+            if !self.has_output(&vin.txid, vin.vout)? {
+                return Ok(MempoolAcceptResult::Rejected(format!(
+                    "output {}:{} is already spent",
+                    vin.txid, vin.vout
+                )));
+            }
+        }
+
+        // Verify the transaction against the blockchain and its parents in the mempool
+        match self.verify_tx_with(tx, &pending) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Ok(MempoolAcceptResult::Rejected(String::from(
+                    "transaction verification failed",
+                )))
+            }
+            Err(e) => {
+                return Ok(MempoolAcceptResult::Rejected(format!(
+                    "transaction verification failed: {}",
+                    e
+                )))
+            }
+        }
+
+        // Transactions spending an output already spent in the mempool are double-spends
+        if let Some(conflict) = self.insert_mempool(tx.clone()) {
+            return Ok(MempoolAcceptResult::Rejected(format!(
+                "transaction conflicts with {} in the mempool",
+                conflict
+            )));
+        }
+        Ok(MempoolAcceptResult::Accepted)
+    }
+
+    // Announce a block mined by this node to all known nodes
+    // block: the newly mined block
+    pub fn announce_block(&self, block: &Block) -> Result<()> {
+        self.broadcast_inv("block", vec![block.get_hash()], &self.node_addr)
+    }
+
+    // Get the metadata of all known nodes
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        self.inner
+            .lock()
+            .unwrap()
+            .known_nodes
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    // Get the number of known nodes, which are dropped once they stop answering pings
+    pub fn get_peer_count(&self) -> usize {
+        self.inner.lock().unwrap().known_nodes.len()
+    }
+
+    // Drop the known nodes that didn't answer their last ping in time and ping the others
+    // A node is pinged again only once it answered the previous ping
+    pub fn ping_peers(&self) -> Result<()> {
+        let now = now_millis();
+
+        let mut to_ping = Vec::new();
+        for node in self.get_known_nodes() {
+            let pending = self.inner.lock().unwrap().pings.get(&node).cloned();
+            match pending {
+                Some((_, sent)) if now.saturating_sub(sent) > PING_TIMEOUT => {
+                    warn!("dropping node {}, it didn't answer a ping", node);
+                    self.remove_node(&node);
+                }
+                Some(_) => {}
+                None => to_ping.push(node),
+            }
+        }
+
+        for node in to_ping {
+            let nonce = random();
+            self.inner
+                .lock()
+                .unwrap()
+                .pings
+                .insert(node.clone(), (nonce, now));
+
+            // A failure to reach one node must not keep the others from being pinged
+            if let Err(e) = self.send_ping(&node, nonce) {
+                warn!("failed to ping node {}: {}", node, e);
+            }
+        }
+
         Ok(())
     }
 
+    // Get how many blocks the local chain is behind the best height reported by any peer
+    pub fn blocks_behind(&self) -> i64 {
+        let local_height = self.get_best_height().unwrap_or(0) as i64;
+
+        // Find the maximum best height reported by the known nodes
+        let peer_height = self
+            .inner
+            .lock()
+            .unwrap()
+            .known_nodes
+            .values()
+            .filter_map(|peer| peer.best_height)
+            .max();
+
+        match peer_height {
+            Some(height) => height as i64 - local_height,
+            None => 0,
+        }
+    }
+
     /*
        ====================
         internal functions
        ====================
     */
     fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
+        let mut inner = self.inner.lock().unwrap();
+        inner.known_nodes.remove(addr);
+        inner.addrs_sent.remove(addr);
+        inner.pings.remove(addr);
     }
 
     fn add_nodes(&self, addr: &str) {
@@ -143,20 +605,55 @@ impl Server {
             .lock()
             .unwrap()
             .known_nodes
-            .insert(String::from(addr));
+            .entry(String::from(addr))
+            .or_insert_with(|| PeerInfo::new(addr));
+    }
+
+    fn update_peer(&self, msg: &VersionMsg) {
+        let last_seen = Some(now_millis());
+
+        let mut inner = self.inner.lock().unwrap();
+        let peer = inner
+            .known_nodes
+            .entry(msg.addr_from.clone())
+            .or_insert_with(|| PeerInfo::new(&msg.addr_from));
+        peer.last_seen = last_seen;
+        peer.version = Some(msg.version);
+        peer.best_height = Some(msg.best_height);
+    }
+
+    // Get the known nodes that were not sent to the node yet and mark them as sent
+    fn take_unsent_addrs(&self, addr: &str) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let known: Vec<String> = inner.known_nodes.keys().cloned().collect();
+        let sent = inner.addrs_sent.entry(addr.to_string()).or_default();
+
+        known
+            .into_iter()
+            .filter(|node| sent.insert(node.clone()))
+            .collect()
     }
 
     fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_nodes.clone()
+        self.inner
+            .lock()
+            .unwrap()
+            .known_nodes
+            .keys()
+            .cloned()
+            .collect()
     }
 
     fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+        self.inner.lock().unwrap().known_nodes.contains_key(addr)
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&hashs);
+    fn replace_in_transit(&self, hashs: Vec<String>) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.blocks_in_transit.clone_from(&hashs);
+
+        // Persist the blocks in transit so the download can resume after a restart
+        inner.utxo.blockchain.save_in_transit(&hashs)
     }
 
     fn get_in_transit(&self) -> Vec<String> {
@@ -174,19 +671,105 @@ impl Server {
         self.inner.lock().unwrap().mempool.clone()
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    // Insert a transaction into the mempool unless it spends an output already spent there
+    // Return the ID of the conflicting transaction if it was not inserted
+    fn insert_mempool(&self, tx: Transaction) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // Coinbase inputs don't spend any output
+        for vin in tx.vin.iter().filter(|vin| !vin.vout.is_coinbase()) {
+            if let Some(txid) = inner.mempool_spends.get(&(vin.txid.clone(), vin.vout)) {
+                return Some(txid.clone());
+            }
+        }
+
+        for vin in tx.vin.iter().filter(|vin| !vin.vout.is_coinbase()) {
+            inner
+                .mempool_spends
+                .insert((vin.txid.clone(), vin.vout), tx.id.clone());
+        }
+        inner.mempool.insert(tx.id.clone(), tx);
+        None
+    }
+
+    // Queue an orphan transaction, dropping expired orphans and the oldest one if the pool is full
+    fn insert_orphan(&self, tx: Transaction) {
+        let now = now_millis();
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .orphans
+            .retain(|_, (_, received)| now.saturating_sub(*received) < ORPHAN_EXPIRY);
+
+        if inner.orphans.len() >= MAX_ORPHANS && !inner.orphans.contains_key(&tx.id) {
+            let oldest = inner
+                .orphans
+                .iter()
+                .min_by_key(|(_, (_, received))| *received)
+                .map(|(txid, _)| txid.clone());
+            if let Some(txid) = oldest {
+                inner.orphans.remove(&txid);
+            }
+        }
+        inner.orphans.insert(tx.id.clone(), (tx, now));
+    }
+
+    // Submit again the orphan transactions whose inputs are all known now,
+    // until no more orphan is accepted
+    fn retry_orphans(&self) -> Result<()> {
+        loop {
+            let ready: Vec<Transaction> = {
+                let mut inner = self.inner.lock().unwrap();
+                let orphans: Vec<Transaction> =
+                    inner.orphans.values().map(|(tx, _)| tx.clone()).collect();
+                let ready: Vec<Transaction> = orphans
+                    .into_iter()
+                    .filter(|tx| inner.parents_known(tx))
+                    .collect();
+                for tx in &ready {
+                    inner.orphans.remove(&tx.id);
+                }
+                ready
+            };
+
+            let mut accepted = false;
+            for tx in &ready {
+                match self.accept_tx(tx)? {
+                    MempoolAcceptResult::Accepted => accepted = true,
+                    MempoolAcceptResult::Rejected(reason) => {
+                        warn!("dropping orphan transaction {}: {}", tx.id, reason)
+                    }
+                    MempoolAcceptResult::AlreadyKnown | MempoolAcceptResult::Orphan => {}
+                }
+            }
+            if !accepted {
+                return Ok(());
+            }
+        }
+    }
+
+    fn parents_known(&self, tx: &Transaction) -> bool {
+        self.inner.lock().unwrap().parents_known(tx)
+    }
+
+    // Get the transactions of the mempool the inputs refer to, by ID
+    fn mempool_parents(&self, tx: &Transaction) -> HashMap<String, Transaction> {
+        let inner = self.inner.lock().unwrap();
+        tx.vin
+            .iter()
+            .filter_map(|vin| inner.mempool.get(&vin.txid))
+            .map(|prev_tx| (prev_tx.id.clone(), prev_tx.clone()))
+            .collect()
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    fn remove_mempool(&self, txid: &str) {
+        self.inner.lock().unwrap().remove_mempool(txid);
     }
 
     fn get_best_height(&self) -> Result<u32> {
         self.inner.lock().unwrap().utxo.blockchain.get_best_height()
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
+    fn get_block_hashs(&self) -> Result<Vec<String>> {
         self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
     }
 
@@ -199,6 +782,10 @@ impl Server {
             .get_block(block_hash)
     }
 
+    fn has_output(&self, txid: &str, vout: OutPoint) -> Result<bool> {
+        self.inner.lock().unwrap().utxo.has_output(txid, vout)
+    }
+
     fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
         self.inner
             .lock()
@@ -208,22 +795,159 @@ impl Server {
             .verify_transaction(tx)
     }
 
-    fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    fn verify_tx_with(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<bool> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .verify_transaction_with(tx, pending)
+    }
+
+    fn add_block(&self, block: Block) -> Result<BlockAcceptResult> {
+        let mut inner = self.inner.lock().unwrap();
+        let result = inner.utxo.blockchain.add_block(block.clone())?;
+        match result {
+            // Apply the new tip to the UTXO set incrementally
+            BlockAcceptResult::Extended => inner.utxo.update(&block)?,
+            // The reorganization rebuilt the UTXO set underneath the cache
+            BlockAcceptResult::Reorganized => inner.utxo.clear_cache(),
+            BlockAcceptResult::AlreadyKnown | BlockAcceptResult::SideChain => {}
+        }
+        Ok(result)
+    }
+
+    // Remove the transactions confirmed by a block from the mempool,
+    // along with the pending transactions spending the same outputs
+    fn evict_confirmed(&self, block: &Block) {
+        let mut inner = self.inner.lock().unwrap();
+        for tx in block.get_transactions() {
+            let mut evicted = vec![tx.id.clone()];
+            for vin in tx.vin.iter().filter(|vin| !vin.vout.is_coinbase()) {
+                if let Some(txid) = inner.mempool_spends.get(&(vin.txid.clone(), vin.vout)) {
+                    evicted.push(txid.clone());
+                }
+            }
+
+            for txid in evicted {
+                inner.remove_mempool(&txid);
+            }
+        }
+    }
+
+    // Update the mempool after the last reorganization: remove the transactions confirmed by the
+    // connected blocks and submit again the ones of the disconnected blocks, oldest first,
+    // so they can be mined on the new chain
+    fn resubmit_disconnected(&self) -> Result<()> {
+        let (connected, disconnected) = {
+            let inner = self.inner.lock().unwrap();
+            let bc = &inner.utxo.blockchain;
+            let event = match bc.reorg_history()?.pop() {
+                Some(event) => event,
+                None => return Ok(()),
+            };
+            let connected = event
+                .connected
+                .iter()
+                .map(|hash| bc.get_block(hash))
+                .collect::<Result<Vec<Block>>>()?;
+            let disconnected = event
+                .disconnected
+                .iter()
+                .rev()
+                .map(|hash| bc.get_block(hash))
+                .collect::<Result<Vec<Block>>>()?;
+            (connected, disconnected)
+        };
+
+        for block in &connected {
+            self.evict_confirmed(block);
+        }
+        for block in &disconnected {
+            for tx in block
+                .get_transactions()
+                .iter()
+                .filter(|tx| !tx.is_coinbase())
+            {
+                match self.accept_to_mempool(tx)? {
+                    MempoolAcceptResult::Rejected(reason) => {
+                        info!("dropping disconnected transaction {}: {}", tx.id, reason)
+                    }
+                    MempoolAcceptResult::Accepted
+                    | MempoolAcceptResult::AlreadyKnown
+                    | MempoolAcceptResult::Orphan => {}
+                }
+            }
+        }
+        Ok(())
     }
 
+    // Mine a block with the transactions on top of the current tip
+    // The proof of work runs without holding the lock, so a block received meanwhile can
+    // cancel it through the mining flag
     fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut block = {
+            let mut inner = self.inner.lock().unwrap();
+            let block = inner.utxo.blockchain.new_block_template(txs)?;
+            inner.mining = Some(cancel.clone());
+            block
+        };
+
+        let mined = block.mine(&cancel);
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner
+                .mining
+                .as_ref()
+                .is_some_and(|m| Arc::ptr_eq(m, &cancel))
+            {
+                inner.mining = None;
+            }
+        }
+        mined?;
+
+        match self.add_block(block.clone())? {
+            BlockAcceptResult::Extended => Ok(block),
+            result => Err(anyhow!(
+                "mined block {} didn't extend the chain: {:?}",
+                block.get_hash(),
+                result
+            )),
+        }
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    // Stop mining the current block, it no longer builds on the tip
+    fn cancel_mining(&self) {
+        if let Some(cancel) = self.inner.lock().unwrap().mining.take() {
+            cancel.store(true, Ordering::Relaxed);
+        }
     }
 
     /* -----------------------------------------------------*/
 
+    // Check if an address refers to this node, even when written differently
+    // (e.g. localhost:3000 and 127.0.0.1:3000)
+    fn is_self(&self, addr: &str) -> bool {
+        if addr == self.node_addr {
+            return true;
+        }
+
+        match (self.node_addr.to_socket_addrs(), addr.to_socket_addrs()) {
+            (Ok(own), Ok(other)) => {
+                let own: Vec<SocketAddr> = own.collect();
+                other.into_iter().any(|a| own.contains(&a))
+            }
+            _ => false,
+        }
+    }
+
     fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_addr {
+        if self.is_self(addr) {
             return Ok(());
         }
         let mut stream = match TcpStream::connect(addr) {
@@ -234,9 +958,22 @@ impl Server {
             }
         };
 
-        stream.write(data)?;
+        // Prefix the message with the network magic bytes
+        let mut msg = self.params.magic.to_vec();
+        msg.extend_from_slice(data);
+        write_frame(&mut stream, &msg)
+    }
 
-        Ok(())
+    // Request the next block that was in transit before the node stopped
+    fn resume_sync(&self) -> Result<()> {
+        let mut in_transit = self.get_in_transit();
+        if in_transit.is_empty() {
+            return Ok(());
+        }
+
+        let block_hash = in_transit.remove(0);
+        self.send_get_data(&self.seed_node, "block", &block_hash)?;
+        self.replace_in_transit(in_transit)
     }
 
     fn request_blocks(&self) -> Result<()> {
@@ -256,7 +993,13 @@ impl Server {
     }
 
     fn send_addr(&self, addr: &str) -> Result<()> {
-        let nodes = self.get_known_nodes();
+        let nodes = self.take_unsent_addrs(addr);
+
+        // Nothing new to tell the node
+        if nodes.is_empty() {
+            return Ok(());
+        }
+
         let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
         self.send_data(addr, &data)
     }
@@ -271,6 +1014,17 @@ impl Server {
         self.send_data(addr, &data)
     }
 
+    // Send an inv to every known node except this node and the given one
+    // except: the node to skip, e.g. the one the items came from
+    fn broadcast_inv(&self, kind: &str, items: Vec<String>, except: &str) -> Result<()> {
+        for node in self.get_known_nodes() {
+            if !self.is_self(&node) && node != except {
+                self.send_inv(&node, kind, items.clone())?;
+            }
+        }
+        Ok(())
+    }
+
     fn send_get_blocks(&self, addr: &str) -> Result<()> {
         let data = GetBlocksMsg {
             addr_from: self.node_addr.clone(),
@@ -298,33 +1052,118 @@ impl Server {
         self.send_data(addr, &data)
     }
 
-    fn send_version(&self, addr: &str) -> Result<()> {
-        let data = VersionMsg {
+    fn send_ping(&self, addr: &str, nonce: u64) -> Result<()> {
+        let data = PingMsg {
             addr_from: self.node_addr.clone(),
-            best_height: self.get_best_height()?,
-            version: VERSION,
+            nonce,
         };
-        let data = serialize(&(cmd_to_bytes("version"), data))?;
+        let data = serialize(&(cmd_to_bytes("ping"), data))?;
         self.send_data(addr, &data)
     }
 
-    fn handle_version(&self, msg: VersionMsg) -> Result<()> {
-        let my_best_height = self.get_best_height()?;
-        if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
-        } else if my_best_height > msg.best_height {
-            self.send_version(&msg.addr_from)?;
-        }
+    fn send_pong(&self, addr: &str, nonce: u64) -> Result<()> {
+        let data = PongMsg {
+            addr_from: self.node_addr.clone(),
+            nonce,
+        };
+        let data = serialize(&(cmd_to_bytes("pong"), data))?;
+        self.send_data(addr, &data)
+    }
 
-        self.send_addr(&msg.addr_from)?;
+    // Request the transactions in the mempool of a node
+    // The node answers with an inv of their IDs, which are then fetched with getdata
+    // addr: the address of the node
+    pub fn request_mempool(&self, addr: &str) -> Result<()> {
+        let data = MemPoolMsg {
+            addr_from: self.node_addr.clone(),
+        };
+        let data = serialize(&(cmd_to_bytes("mempool"), data))?;
+        self.send_data(addr, &data)
+    }
 
-        if !self.node_is_known(&msg.addr_from) {
-            self.add_nodes(&msg.addr_from);
-        }
-        Ok(())
+    // Request the headers of the chain of a node, e.g. for a light client
+    // The node answers with the headers only, without any transaction
+    // addr: the address of the node
+    pub fn request_headers(&self, addr: &str) -> Result<()> {
+        let data = GetHeadersMsg {
+            addr_from: self.node_addr.clone(),
+        };
+        let data = serialize(&(cmd_to_bytes("getheaders"), data))?;
+        self.send_data(addr, &data)
     }
 
-    fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
+    // Get the headers received from each peer, from the genesis block to its tip
+    // The peers are told apart by the socket address of the connection the headers came over
+    pub fn peer_headers(&self) -> HashMap<String, Vec<BlockHeader>> {
+        self.inner.lock().unwrap().headers.clone()
+    }
+
+    fn send_headers(&self, addr: &str) -> Result<()> {
+        // Collect the headers from the tip and send them from the genesis block
+        let mut headers = Vec::new();
+        {
+            let inner = self.inner.lock().unwrap();
+            let bc = &inner.utxo.blockchain;
+            for hash in bc.iter_hashes() {
+                headers.push(bc.get_block_header(&hash?)?);
+            }
+        }
+        headers.reverse();
+
+        let data = HeadersMsg {
+            addr_from: self.node_addr.clone(),
+            headers,
+        };
+        let data = serialize(&(cmd_to_bytes("headers"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_version(&self, addr: &str) -> Result<()> {
+        let data = VersionMsg {
+            addr_from: self.node_addr.clone(),
+            best_height: self.get_best_height()?,
+            version: VERSION,
+        };
+        let data = serialize(&(cmd_to_bytes("version"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn handle_version(&self, msg: VersionMsg) -> Result<()> {
+        // Don't talk to nodes speaking an incompatible protocol
+        if msg.version.abs_diff(VERSION) > VERSION_TOLERANCE {
+            warn!(
+                "ignoring node {} with incompatible version {} (local {})",
+                msg.addr_from, msg.version, VERSION
+            );
+            self.remove_node(&msg.addr_from);
+            return Ok(());
+        }
+
+        // A node we don't know yet waits for our version to complete the handshake
+        let my_best_height = self.get_best_height()?;
+        if my_best_height < msg.best_height {
+            self.send_get_blocks(&msg.addr_from)?;
+        } else if my_best_height > msg.best_height || !self.node_is_known(&msg.addr_from) {
+            self.send_version(&msg.addr_from)?;
+        }
+
+        self.send_addr(&msg.addr_from)?;
+
+        if !self.node_is_known(&msg.addr_from) {
+            self.add_nodes(&msg.addr_from);
+        }
+
+        // Cache the version and best height reported by the node
+        self.update_peer(&msg);
+
+        let behind = self.blocks_behind();
+        if behind > 0 {
+            warn!("local chain is {} blocks behind the network", behind);
+        }
+        Ok(())
+    }
+
+    fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
         for node in msg {
             self.add_nodes(&node);
         }
@@ -333,122 +1172,320 @@ impl Server {
     }
 
     fn handle_block(&self, msg: BlockMsg) -> Result<()> {
-        self.add_block(msg.block)?;
+        match self.add_block(msg.block.clone())? {
+            // The block is part of the best chain, its transactions are confirmed
+            BlockAcceptResult::Extended => {
+                self.cancel_mining();
+                self.evict_confirmed(&msg.block);
+                self.retry_orphans()?;
+            }
+            // The blocks of the new chain are confirmed, the ones of the old chain are not anymore
+            BlockAcceptResult::Reorganized => {
+                self.cancel_mining();
+                self.resubmit_disconnected()?;
+                self.retry_orphans()?;
+            }
+            BlockAcceptResult::AlreadyKnown | BlockAcceptResult::SideChain => {}
+        }
 
         let mut in_transit = self.get_in_transit();
         if in_transit.len() > 0 {
             let block_hash = &in_transit[0];
             self.send_get_data(&msg.addr_from, "block", block_hash)?;
             in_transit.remove(0);
-            self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
+            self.replace_in_transit(in_transit)?;
         }
 
         Ok(())
     }
 
     fn handle_inv(&self, msg: InvMsg) -> Result<()> {
+        // An empty inv announces nothing to fetch
+        if msg.items.is_empty() {
+            return Ok(());
+        }
+
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
+            // Blocks are announced from the tip, request them from the oldest so that
+            // each block's previous block is known when it arrives
+            let items: Vec<String> = msg.items.iter().rev().cloned().collect();
+            let block_hash = &items[0];
             self.send_get_data(&msg.addr_from, "block", block_hash)?;
 
             let mut new_in_transit = Vec::new();
-            for b in &msg.items {
+            for b in &items {
                 if b != block_hash {
                     new_in_transit.push(b.clone());
                 }
             }
-            self.replace_in_transit(new_in_transit);
+            self.replace_in_transit(new_in_transit)?;
         } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
+            // Fetch every announced transaction that isn't in the mempool yet
+            for txid in &msg.items {
+                match self.get_mempool_tx(txid) {
+                    Some(tx) => {
+                        if tx.id.is_empty() {
+                            self.send_get_data(&msg.addr_from, "tx", txid)?
+                        }
                     }
+                    None => self.send_get_data(&msg.addr_from, "tx", txid)?,
                 }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
             }
+        } else {
+            return Err(anyhow!(
+                "unknown inv kind {} from {}",
+                msg.kind,
+                msg.addr_from
+            ));
+        }
+        Ok(())
+    }
+
+    fn handle_ping(&self, msg: PingMsg) -> Result<()> {
+        self.send_pong(&msg.addr_from, msg.nonce)
+    }
+
+    fn handle_pong(&self, msg: PongMsg) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // Only the answer to the pending ping proves the node is alive
+        if inner.pings.get(&msg.addr_from).map(|(nonce, _)| *nonce) != Some(msg.nonce) {
+            return Ok(());
+        }
+        inner.pings.remove(&msg.addr_from);
+
+        if let Some(peer) = inner.known_nodes.get_mut(&msg.addr_from) {
+            peer.last_seen = Some(now_millis());
         }
         Ok(())
     }
 
+    fn handle_mempool(&self, msg: MemPoolMsg) -> Result<()> {
+        let txids: Vec<String> = self.get_mempool().into_keys().collect();
+        if txids.is_empty() {
+            return Ok(());
+        }
+        self.send_inv(&msg.addr_from, "tx", txids)
+    }
+
+    fn handle_get_headers(&self, msg: GetHeadersMsg) -> Result<()> {
+        self.send_headers(&msg.addr_from)
+    }
+
+    // Check and store the headers of the chain of a peer
+    // They are stored under the socket address they came from, a peer can't report the
+    // address of another node to overwrite its headers
+    // peer: the socket address of the connection the headers came over
+    fn handle_headers(&self, msg: HeadersMsg, peer: &str) -> Result<()> {
+        // The headers must form a chain from the genesis block, each with a valid proof of work
+        // at the difficulty its previous header requires
+        let headers = &msg.headers;
+        for (i, header) in headers.iter().enumerate() {
+            let (prev_hash, height, difficulty) = match i.checked_sub(1) {
+                Some(p) => {
+                    let prev = &headers[p];
+                    let difficulty = difficulty_after(
+                        prev,
+                        || Ok(headers[p + 1 - RETARGET_INTERVAL as usize].clone()),
+                        &self.params,
+                    )?;
+                    (prev.get_hash(), prev.get_height() + 1, difficulty)
+                }
+                None => (String::new(), 0, self.params.difficulty),
+            };
+            if header.get_prev_hash() != prev_hash || header.get_height() != height {
+                return Err(anyhow!(
+                    "headers from {} don't form a chain at {}",
+                    peer,
+                    header.get_hash()
+                ));
+            }
+            if header.get_difficulty() < difficulty || !header.verify_pow()? {
+                return Err(anyhow!(
+                    "invalid proof of work in the headers from {}: {}",
+                    peer,
+                    header.get_hash()
+                ));
+            }
+        }
+
+        self.inner
+            .lock()
+            .unwrap()
+            .headers
+            .insert(peer.to_string(), msg.headers);
+        Ok(())
+    }
+
     fn handle_get_blocks(&self, msg: GetBlocksMsg) -> Result<()> {
-        let block_hashs = self.get_block_hashs();
+        let block_hashs = self.get_block_hashs()?;
         self.send_inv(&msg.addr_from, "block", block_hashs)?;
         Ok(())
     }
 
     fn handle_get_data(&self, msg: GetDataMsg) -> Result<()> {
+        // Data we don't have (anymore) is simply not sent
         if msg.kind == "block" {
-            let block = self.get_block(&msg.id)?;
-            self.send_block(&msg.addr_from, &block)?;
+            match self.get_block(&msg.id) {
+                Ok(block) => self.send_block(&msg.addr_from, &block)?,
+                Err(e) => warn!(
+                    "{} requested unknown block {}: {}",
+                    msg.addr_from, msg.id, e
+                ),
+            }
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
+            match self.get_mempool_tx(&msg.id) {
+                Some(tx) => self.send_tx(&msg.addr_from, &tx)?,
+                None => warn!("{} requested unknown transaction {}", msg.addr_from, msg.id),
+            }
         }
         Ok(())
     }
 
     fn handle_tx(&self, msg: TxMsg) -> Result<()> {
-        self.insert_mempool(msg.transaction.clone());
+        // Only relay or mine transactions that were accepted into the mempool
+        if self.accept_to_mempool(&msg.transaction)? != MempoolAcceptResult::Accepted {
+            return Ok(());
+        }
+
+        if self.is_self(&self.seed_node) {
+            self.broadcast_inv("tx", vec![msg.transaction.id.clone()], &msg.addr_from)?;
+        } else if !self.miner_addr.is_empty() {
+            self.mine_mempool()?;
+        }
 
-        let known_nodes = self.get_known_nodes();
-        if self.node_addr == KNOWN_NODE {
-            for node in known_nodes {
-                if node != self.node_addr && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+        Ok(())
+    }
+
+    // Mine the transactions of the mempool until it is empty
+    // Mined transactions are removed from the mempool, and transactions that no longer
+    // verify are dropped so they can't keep the loop going
+    fn mine_mempool(&self) -> Result<()> {
+        loop {
+            // Transactions spending outputs of the mempool wait for their parents to be mined
+            for (txid, tx) in self.get_mempool() {
+                if !self.mempool_parents(&tx).is_empty() {
+                    continue;
                 }
-            }
-        } else {
-            let mut mempool = self.get_mempool();
-            if mempool.len() >= 1 && !self.miner_addr.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
+                match self.verify_tx(&tx) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("dropping invalid transaction {} from the mempool", txid);
+                        self.remove_mempool(&txid);
                     }
-
-                    if txs.is_empty() {
-                        return Ok(());
+                    Err(e) => {
+                        warn!("dropping transaction {} from the mempool: {}", txid, e);
+                        self.remove_mempool(&txid);
                     }
+                }
+            }
+
+            // Transactions left out of the block stay in the mempool for the next one
+            let mut txs = self.select_mempool_txs(MAX_BLOCK_BYTES)?;
+            if txs.is_empty() {
+                return Ok(());
+            }
 
-                    let cbtx = Transaction::new_coinbase(self.miner_addr.clone(), String::new())?;
-                    txs.push(cbtx);
+            let height = self.get_best_height()? as usize + 1;
+            let cbtx = Transaction::new_coinbase(self.miner_addr.clone(), String::new(), height)?;
+            txs.insert(0, cbtx);
 
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+            let new_block = match self.mine_block(txs) {
+                Ok(block) => block,
+                // A new tip arrived, mine what is left of the mempool on top of it
+                Err(e) if matches!(e.downcast_ref(), Some(BlockchainError::MiningCancelled)) => {
+                    info!("mining cancelled by a new block");
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            for tx in new_block.get_transactions() {
+                self.remove_mempool(&tx.id);
+            }
+            self.announce_block(&new_block)?;
+        }
+    }
 
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    // Select the transactions of the mempool to mine, by fee per byte from the highest
+    // Invalid transactions and transactions spending an output already spent by a selected
+    // transaction are left out
+    // max_bytes: the maximum total serialized size of the selected transactions
+    pub fn select_mempool_txs(&self, max_bytes: usize) -> Result<Vec<Transaction>> {
+        // (transaction, fee, size) of every valid transaction
+        let mut candidates = Vec::new();
+        for (_, tx) in self.get_mempool() {
+            if !matches!(self.verify_tx(&tx), Ok(true)) {
+                continue;
+            }
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_addr {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
+            let fee = {
+                let inner = self.inner.lock().unwrap();
+                let prev_txs = inner.utxo.blockchain.get_prev_txs(&tx)?;
+                tx.fee(&prev_txs)?
+            };
+            let size = serialize(&tx)?.len();
+            candidates.push((tx, fee, size));
+        }
 
-                    if mempool.len() == 0 {
-                        break;
-                    }
-                }
-                self.clear_mempool();
+        // Compare fee_a / size_a and fee_b / size_b without dividing
+        candidates.sort_by(|(_, fee_a, size_a), (_, fee_b, size_b)| {
+            (*fee_b as i64 * *size_a as i64).cmp(&(*fee_a as i64 * *size_b as i64))
+        });
+
+        let mut selected = Vec::new();
+        let mut spent = HashSet::new();
+        let mut total = 0;
+        for (tx, _, size) in candidates {
+            if total + size > max_bytes {
+                continue;
+            }
+
+            // A higher-priority transaction already spends one of the inputs
+            if tx
+                .vin
+                .iter()
+                .any(|vin| spent.contains(&(vin.txid.clone(), vin.vout)))
+            {
+                continue;
             }
+
+            for vin in &tx.vin {
+                spent.insert((vin.txid.clone(), vin.vout));
+            }
+            total += size;
+            selected.push(tx);
         }
 
-        Ok(())
+        Ok(selected)
     }
 
+    // Handle every message sent over a connection until the peer closes it
+    // A peer staying silent or not reading for CONNECTION_TIMEOUT is disconnected, so it
+    // can't hold a worker forever
     fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let peer = stream.peer_addr()?.to_string();
+        while let Some(buffer) = read_frame(&mut stream)? {
+            self.handle_message(&buffer, &peer)?;
+        }
+
+        Ok(())
+    }
+
+    // Handle a message received from a peer
+    // buffer: the frame of the message
+    // peer: the socket address of the connection the message came over
+    fn handle_message(&self, buffer: &[u8], peer: &str) -> Result<()> {
+        // Drop messages from other networks before deserializing them
+        if buffer.len() < MAGIC_LENGTH || buffer[..MAGIC_LENGTH] != self.params.magic {
+            return Err(anyhow!("message from a different network"));
+        }
+        if buffer.len() < MAGIC_LENGTH + CMD_LENGTH {
+            return Err(anyhow!("message too short: {} bytes", buffer.len()));
+        }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+        let cmd = bytes_to_cmd(&buffer[MAGIC_LENGTH..])?;
 
         match cmd {
             ServerMessage::Addr(data) => self.handle_addr(data)?,
@@ -458,12 +1495,63 @@ impl Server {
             ServerMessage::GetData(data) => self.handle_get_data(data)?,
             ServerMessage::Tx(data) => self.handle_tx(data)?,
             ServerMessage::Version(data) => self.handle_version(data)?,
+            ServerMessage::MemPool(data) => self.handle_mempool(data)?,
+            ServerMessage::Ping(data) => self.handle_ping(data)?,
+            ServerMessage::Pong(data) => self.handle_pong(data)?,
+            ServerMessage::GetHeaders(data) => self.handle_get_headers(data)?,
+            ServerMessage::Headers(data) => self.handle_headers(data, peer)?,
         }
 
         Ok(())
     }
 }
 
+// Get the current time in milliseconds since the Unix Epoch
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+// Write a message prefixed with its length as 4 bytes in big-endian order
+// stream: the stream to write to
+// payload: the message
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> Result<()> {
+    let length = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_FRAME_LENGTH)
+        .ok_or_else(|| anyhow!("message too large: {} bytes", payload.len()))?;
+
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Read the next length-prefixed message, None if the stream ended between two messages
+// stream: the stream to read from
+fn read_frame(stream: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    let mut filled = 0;
+    while filled < length.len() {
+        match stream.read(&mut length[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(anyhow!("connection closed inside a message length")),
+            n => filled += n,
+        }
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(anyhow!("message too large: {} bytes", length));
+    }
+
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
 fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LENGTH] {
     let mut data = [0; CMD_LENGTH];
     for (i, d) in cmd.as_bytes().iter().enumerate() {
@@ -473,6 +1561,11 @@ fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LENGTH] {
 }
 
 fn bytes_to_cmd(bytes: &[u8]) -> Result<ServerMessage> {
+    // A message starts with its command, a shorter frame can't be a message
+    if bytes.len() < CMD_LENGTH {
+        return Err(anyhow!("message too short: {} bytes", bytes.len()));
+    }
+
     let mut cmd = Vec::new();
     let cmd_bytes = &bytes[..CMD_LENGTH];
     let data = &bytes[CMD_LENGTH..];
@@ -503,7 +1596,1100 @@ fn bytes_to_cmd(bytes: &[u8]) -> Result<ServerMessage> {
     } else if cmd == "version".as_bytes() {
         let data: VersionMsg = deserialize(data)?;
         Ok(ServerMessage::Version(data))
+    } else if cmd == "mempool".as_bytes() {
+        let data: MemPoolMsg = deserialize(data)?;
+        Ok(ServerMessage::MemPool(data))
+    } else if cmd == "ping".as_bytes() {
+        let data: PingMsg = deserialize(data)?;
+        Ok(ServerMessage::Ping(data))
+    } else if cmd == "pong".as_bytes() {
+        let data: PongMsg = deserialize(data)?;
+        Ok(ServerMessage::Pong(data))
+    } else if cmd == "getheaders".as_bytes() {
+        let data: GetHeadersMsg = deserialize(data)?;
+        Ok(ServerMessage::GetHeaders(data))
+    } else if cmd == "headers".as_bytes() {
+        let data: HeadersMsg = deserialize(data)?;
+        Ok(ServerMessage::Headers(data))
     } else {
         Err(anyhow!("unknown command"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Blockchain, VerificationLevel};
+    use crate::test_utils::{TestChain, TestNode};
+
+    // Get the address of a local port nothing listens on
+    fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    // Run a node serving connections on a local port, and return it along with its address
+    // chain: the chain of the node
+    // miner_addr: the address mining rewards are sent to, empty if the node doesn't mine
+    fn serve_chain(chain: TestChain, miner_addr: &str) -> (Arc<TestNode>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let node = Arc::new(chain.into_node_at(&addr, "127.0.0.1:1", miner_addr));
+        let serving = node.clone();
+        thread::spawn(move || serving.server.serve(listener));
+        (node, addr)
+    }
+
+    // Wait until the condition holds, failing the test after a few seconds
+    // what: what is waited for, reported on failure
+    fn wait_until(what: &str, condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn rejects_expired_transactions_from_the_mempool() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let to = chain.address();
+        let tip_height = chain.bc().get_best_height().unwrap();
+
+        let mut expired = chain.send(&chain.miner, &to, 10);
+        expired.expiry_height = Some(tip_height);
+        chain.resign(&chain.miner, &mut expired);
+
+        // Expiring with the next block is still fine
+        let mut expiring = chain.send(&chain.miner, &to, 20);
+        expiring.expiry_height = Some(tip_height + 1);
+        chain.resign(&chain.miner, &mut expiring);
+
+        let node = chain.into_node();
+        assert_eq!(
+            node.server.accept_to_mempool(&expired).unwrap(),
+            MempoolAcceptResult::Rejected(String::from("transaction is expired"))
+        );
+        assert!(node.server.get_mempool_tx(&expired.id).is_none());
+        assert_eq!(
+            node.server.accept_to_mempool(&expiring).unwrap(),
+            MempoolAcceptResult::Accepted
+        );
+    }
+
+    #[test]
+    fn records_the_height_reported_in_a_version_message() {
+        let node = TestChain::new().into_node();
+        let peer = free_addr();
+
+        node.server
+            .handle_version(VersionMsg {
+                addr_from: peer.clone(),
+                version: VERSION,
+                best_height: 7,
+            })
+            .unwrap();
+
+        let info = node
+            .server
+            .peer_info()
+            .into_iter()
+            .find(|info| info.addr == peer)
+            .unwrap();
+        assert_eq!(info.version, Some(VERSION));
+        assert_eq!(info.best_height, Some(7));
+        assert!(info.last_seen.is_some());
+        assert_eq!(node.server.blocks_behind(), 7);
+    }
+
+    #[test]
+    fn reports_each_mempool_accept_outcome() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let mut invalid = chain.send(&chain.miner, &alice, 40);
+        invalid.vout[0].value = 50;
+        let orphan = chain.spend(&chain.send(&chain.miner, &alice, 20), 0, &alice, &alice, 10);
+        let coinbase = chain.coinbase(&alice);
+
+        let node = chain.into_node();
+        let server = &node.server;
+        assert_eq!(
+            server.accept_to_mempool(&tx).unwrap(),
+            MempoolAcceptResult::Accepted
+        );
+        assert_eq!(
+            server.accept_to_mempool(&tx).unwrap(),
+            MempoolAcceptResult::AlreadyKnown
+        );
+        assert!(matches!(
+            server.accept_to_mempool(&invalid).unwrap(),
+            MempoolAcceptResult::Rejected(_)
+        ));
+        assert_eq!(
+            server.accept_to_mempool(&orphan).unwrap(),
+            MempoolAcceptResult::Orphan
+        );
+        assert!(matches!(
+            server.accept_to_mempool(&coinbase).unwrap(),
+            MempoolAcceptResult::Rejected(_)
+        ));
+
+        let mempool = server.get_mempool();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains_key(&tx.id));
+    }
+
+    #[test]
+    fn accepts_transactions_spending_the_mempool() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let bob = chain.address();
+
+        let parent = chain.send(&chain.miner, &alice, 30);
+        let child = chain.spend(&parent, 0, &alice, &bob, 20);
+        let overspend = chain.spend(&parent, 0, &alice, &bob, 40);
+
+        let node = chain.into_node();
+        let server = &node.server;
+        assert_eq!(
+            server.accept_to_mempool(&parent).unwrap(),
+            MempoolAcceptResult::Accepted
+        );
+        assert!(matches!(
+            server.accept_to_mempool(&overspend).unwrap(),
+            MempoolAcceptResult::Rejected(_)
+        ));
+        assert_eq!(
+            server.accept_to_mempool(&child).unwrap(),
+            MempoolAcceptResult::Accepted
+        );
+
+        // The child waits for its parent to be mined
+        let selected = server.select_mempool_txs(MAX_BLOCK_BYTES).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].id, parent.id);
+    }
+
+    #[test]
+    fn accepts_orphans_once_their_parent_arrives() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let bob = chain.address();
+
+        let parent = chain.send(&chain.miner, &alice, 30);
+        let child = chain.spend(&parent, 0, &alice, &bob, 20);
+        let grandchild = chain.spend(&child, 0, &bob, &alice, 10);
+
+        let node = chain.into_node();
+        let server = &node.server;
+        assert_eq!(
+            server.accept_to_mempool(&grandchild).unwrap(),
+            MempoolAcceptResult::Orphan
+        );
+        assert_eq!(
+            server.accept_to_mempool(&child).unwrap(),
+            MempoolAcceptResult::Orphan
+        );
+        assert_eq!(
+            server.accept_to_mempool(&parent).unwrap(),
+            MempoolAcceptResult::Accepted
+        );
+
+        let mempool = server.get_mempool();
+        assert_eq!(mempool.len(), 3);
+        assert!(mempool.contains_key(&child.id) && mempool.contains_key(&grandchild.id));
+        assert!(server.inner.lock().unwrap().orphans.is_empty());
+    }
+
+    #[test]
+    fn rejects_transactions_spending_confirmed_outputs_twice() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let double_spend = chain.send(&chain.miner, &alice, 40);
+        chain.mine(vec![tx.clone()]);
+
+        let node = chain.into_node();
+        let server = &node.server;
+        for tx in [&tx, &double_spend] {
+            assert!(matches!(
+                server.accept_to_mempool(tx).unwrap(),
+                MempoolAcceptResult::Rejected(_)
+            ));
+        }
+        assert!(server.get_mempool().is_empty());
+    }
+
+    #[test]
+    fn bounds_the_orphan_pool() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        let parent = chain.send(&chain.miner, &alice, 200);
+        let orphans: Vec<Transaction> = (0..=MAX_ORPHANS as i32)
+            .map(|i| chain.spend(&parent, 0, &alice, &alice, 2 + i))
+            .collect();
+
+        let node = chain.into_node();
+        let server = &node.server;
+        let start = now_millis();
+        for (i, orphan) in orphans.iter().enumerate() {
+            assert_eq!(
+                server.accept_to_mempool(orphan).unwrap(),
+                MempoolAcceptResult::Orphan
+            );
+
+            // Date the orphans one millisecond apart, from the oldest
+            let mut inner = server.inner.lock().unwrap();
+            inner.orphans.get_mut(&orphan.id).unwrap().1 = start + i as u128;
+        }
+        {
+            let inner = server.inner.lock().unwrap();
+            assert_eq!(inner.orphans.len(), MAX_ORPHANS);
+            assert!(!inner.orphans.contains_key(&orphans[0].id));
+        }
+
+        // Expired orphans are dropped when the next one arrives
+        let late = {
+            let mut inner = server.inner.lock().unwrap();
+            for (_, received) in inner.orphans.values_mut() {
+                *received = now_millis() - ORPHAN_EXPIRY;
+            }
+            inner.orphans.remove(&orphans[1].id).unwrap().0
+        };
+        assert_eq!(
+            server.accept_to_mempool(&late).unwrap(),
+            MempoolAcceptResult::Orphan
+        );
+        assert_eq!(server.inner.lock().unwrap().orphans.len(), 1);
+    }
+
+    #[test]
+    fn measures_the_gap_to_the_highest_peer() {
+        let mut chain = TestChain::new();
+        chain.mine_empty(4);
+        let node = chain.into_node();
+        assert_eq!(node.server.blocks_behind(), 0);
+
+        for best_height in [2, 9] {
+            node.server
+                .handle_version(VersionMsg {
+                    addr_from: free_addr(),
+                    version: VERSION,
+                    best_height,
+                })
+                .unwrap();
+        }
+
+        assert_eq!(node.server.blocks_behind(), 5);
+        assert!(node.server.ensure_synced(4).is_err());
+        assert!(node.server.ensure_synced(5).is_ok());
+    }
+
+    // Read the next message sent to a listener standing in for a peer
+    fn receive(listener: &TcpListener) -> ServerMessage {
+        let (mut stream, _) = listener.accept().unwrap();
+        let frame = read_frame(&mut stream).unwrap().unwrap();
+        bytes_to_cmd(&frame[MAGIC_LENGTH..]).unwrap()
+    }
+
+    // Frame a version message the way send_version does, with the given magic bytes
+    fn version_frame(magic: &[u8], addr_from: &str, best_height: u32) -> Vec<u8> {
+        let data = VersionMsg {
+            addr_from: addr_from.to_string(),
+            version: VERSION,
+            best_height,
+        };
+        let mut frame = magic.to_vec();
+        frame.extend(serialize(&(cmd_to_bytes("version"), data)).unwrap());
+        frame
+    }
+
+    #[test]
+    fn drops_messages_from_other_networks() {
+        let node = TestChain::new().into_node();
+        let devnet = ChainParams::devnet().magic;
+        let mainnet = ChainParams::mainnet().magic;
+        let peer = free_addr();
+
+        let err = node
+            .server
+            .handle_message(&version_frame(&mainnet, &peer, 5), &peer)
+            .unwrap_err();
+        assert!(err.to_string().contains("different network"), "{}", err);
+        assert!(node.server.peer_info().iter().all(|info| info.addr != peer));
+
+        // Frames too short to hold the magic bytes or a command are errors, not panics
+        assert!(node.server.handle_message(&devnet[..2], &peer).is_err());
+        let mut short = devnet.to_vec();
+        short.extend_from_slice(b"ver");
+        assert!(node.server.handle_message(&short, &peer).is_err());
+        assert!(bytes_to_cmd(b"ver").is_err());
+
+        node.server
+            .handle_message(&version_frame(&devnet, &peer, 5), &peer)
+            .unwrap();
+        assert!(node.server.peer_info().iter().any(|info| info.addr == peer));
+    }
+
+    #[test]
+    fn sends_each_address_to_a_peer_once() {
+        let node = TestChain::new().into_node();
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap().to_string();
+        node.server.add_nodes(&peer_addr);
+
+        node.server.send_addr(&peer_addr).unwrap();
+        let first = match receive(&peer) {
+            ServerMessage::Addr(nodes) => nodes,
+            _ => panic!("expected an addr message"),
+        };
+        assert!(first.contains(&peer_addr));
+
+        // Only the address learned since the first exchange is sent
+        node.server.add_nodes("127.0.0.1:1234");
+        node.server.send_addr(&peer_addr).unwrap();
+        match receive(&peer) {
+            ServerMessage::Addr(nodes) => assert_eq!(nodes, vec!["127.0.0.1:1234".to_string()]),
+            _ => panic!("expected an addr message"),
+        }
+
+        // Nothing is sent when the peer knows every address
+        peer.set_nonblocking(true).unwrap();
+        node.server.send_addr(&peer_addr).unwrap();
+        assert!(peer.accept().is_err());
+    }
+
+    #[test]
+    fn announces_a_mined_block_to_each_peer() {
+        let mut chain = TestChain::new();
+        let block = chain.mine(Vec::new());
+        let node = chain.into_node();
+
+        let peers: Vec<TcpListener> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        for peer in &peers {
+            node.server
+                .add_nodes(&peer.local_addr().unwrap().to_string());
+        }
+
+        node.server.announce_block(&block).unwrap();
+        for peer in &peers {
+            match receive(peer) {
+                ServerMessage::Inv(inv) => {
+                    assert_eq!(inv.addr_from, node.server.node_addr);
+                    assert_eq!(inv.kind, "block");
+                    assert_eq!(inv.items, vec![block.get_hash()]);
+                }
+                _ => panic!("expected an inv message"),
+            }
+        }
+    }
+
+    // Run a peer answering the version of the next node to connect with the given height,
+    // then return the command of the following message it receives, if any
+    fn answering_peer(best_height: u32) -> (String, thread::JoinHandle<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peer_addr = addr.clone();
+        let handle = thread::spawn(move || {
+            let sender = match receive(&listener) {
+                ServerMessage::Version(msg) => msg.addr_from,
+                _ => panic!("expected a version message"),
+            };
+            let magic = ChainParams::devnet().magic;
+            let mut stream = TcpStream::connect(&sender).unwrap();
+            write_frame(&mut stream, &version_frame(&magic, &peer_addr, best_height)).unwrap();
+
+            listener.set_nonblocking(true).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while Instant::now() < deadline {
+                if let Ok((mut stream, _)) = listener.accept() {
+                    stream.set_nonblocking(false).unwrap();
+                    let frame = read_frame(&mut stream).unwrap().unwrap();
+                    let cmd = frame[MAGIC_LENGTH..MAGIC_LENGTH + CMD_LENGTH].to_vec();
+                    let cmd = String::from_utf8(cmd).unwrap();
+                    return Some(cmd.trim_end_matches('\0').to_string());
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            None
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn refuses_to_send_when_behind_the_network() {
+        for (ahead, sent) in [(5, false), (0, true)] {
+            let mut chain = TestChain::new();
+            chain.mature();
+            let alice = chain.address();
+            let tx = chain.send(&chain.miner, &alice, 30);
+            let height = chain.bc().get_best_height().unwrap();
+
+            let (seed, peer) = answering_peer(height + ahead);
+            let result = Server::send_transaction_to(&tx, chain.utxo, &free_addr(), &seed, Some(1));
+            assert_eq!(result.is_ok(), sent, "{:?}", result);
+            if !sent {
+                assert!(result.unwrap_err().to_string().contains("blocks behind"));
+            }
+
+            let next = peer.join().unwrap();
+            assert_eq!(next.as_deref(), if sent { Some("tx") } else { None });
+        }
+    }
+
+    #[test]
+    fn resumes_the_download_with_the_missing_blocks() {
+        let mut source = TestChain::new();
+        source.mine_empty(4);
+        let blocks: Vec<Block> = (0..=4)
+            .map(|height| source.bc().get_block_by_height(height).unwrap())
+            .collect();
+        let hashes: Vec<String> = blocks.iter().map(Block::get_hash).collect();
+
+        // The node got the first blocks, was downloading the rest and received one more
+        // before crashing
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut bc = Blockchain::open(dir.path(), ChainParams::devnet()).unwrap();
+            bc.import(blocks[..2].to_vec(), VerificationLevel::TrustedNoVerify)
+                .unwrap();
+            bc.save_in_transit(&hashes[2..]).unwrap();
+            bc.import(vec![blocks[2].clone()], VerificationLevel::TrustedNoVerify)
+                .unwrap();
+        }
+
+        let seed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let seed_addr = seed.local_addr().unwrap().to_string();
+        // sled's background flusher may hold the lock of the old handle for a moment
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let bc = loop {
+            match Blockchain::open(dir.path(), ChainParams::devnet()) {
+                Ok(bc) => break bc,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        let server = Server::new(&free_addr(), &seed_addr, "", UTXOSet::new(bc).unwrap()).unwrap();
+        assert_eq!(server.get_in_transit(), hashes[3..].to_vec());
+
+        server.resume_sync().unwrap();
+        match receive(&seed) {
+            ServerMessage::GetData(msg) => {
+                assert_eq!(msg.kind, "block");
+                assert_eq!(msg.id, hashes[3]);
+            }
+            _ => panic!("expected a getdata message"),
+        }
+        assert_eq!(server.get_in_transit(), vec![hashes[4].clone()]);
+    }
+
+    #[test]
+    fn resubmits_transactions_of_disconnected_blocks() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let fork_point = chain.bc().get_tip_hash();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx.clone()]);
+
+        // A longer competing chain without the payment
+        let coinbase = |height: usize| {
+            Transaction::new_coinbase_in(&chain.wallets, chain.miner.clone(), "fork".into(), height)
+                .unwrap()
+        };
+        let difficulty = chain.bc().get_params().difficulty;
+        let first = Block::new_block(vec![coinbase(11)], fork_point, 11, difficulty).unwrap();
+        let second =
+            Block::new_block(vec![coinbase(12)], first.get_hash(), 12, difficulty).unwrap();
+
+        let node = chain.into_node();
+        for block in [first, second.clone()] {
+            node.server
+                .handle_block(BlockMsg {
+                    addr_from: free_addr(),
+                    block,
+                })
+                .unwrap();
+        }
+
+        assert_eq!(node.server.get_best_height().unwrap(), 12);
+        assert_eq!(
+            node.server
+                .inner
+                .lock()
+                .unwrap()
+                .utxo
+                .blockchain
+                .get_tip_hash(),
+            second.get_hash()
+        );
+        assert!(node.server.get_mempool_tx(&tx.id).is_some());
+    }
+
+    // Count the threads of the process with the given name
+    fn threads_named(name: &str) -> usize {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.unwrap().path().join("comm")).ok())
+            .filter(|comm| comm.trim_end() == name)
+            .count()
+    }
+
+    #[test]
+    fn handles_a_connection_flood_with_a_bounded_number_of_workers() {
+        let node = Arc::new(TestChain::new().into_node());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = node.clone();
+        thread::spawn(move || serving.server.serve(listener));
+
+        // Every connection announces a different peer, then closes
+        let magic = ChainParams::devnet().magic;
+        let peers: Vec<String> = (0..50).map(|_| free_addr()).collect();
+        for peer in &peers {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write_frame(&mut stream, &version_frame(&magic, peer, 0)).unwrap();
+        }
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(30);
+        loop {
+            let known = node.server.peer_info();
+            let handled = peers
+                .iter()
+                .filter(|peer| known.iter().any(|info| &info.addr == *peer))
+                .count();
+            if handled == peers.len() {
+                break;
+            }
+            assert!(std::time::Instant::now() < deadline, "{} handled", handled);
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        if cfg!(target_os = "linux") {
+            // Other tests serve nodes in the same process, the port tells the workers apart
+            let name = format!("{}-{}", WORKER_NAME, addr.port());
+            assert_eq!(threads_named(&name), WORKER_COUNT);
+        }
+    }
+
+    #[test]
+    fn mines_only_the_valid_transactions_of_the_mempool() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        chain.mine_empty(1);
+        let alice = chain.address();
+        let coinbase = |height: usize| {
+            let block = chain.bc().get_block_by_height(height).unwrap();
+            block.get_transactions()[0].clone()
+        };
+        let valid = chain.spend(&coinbase(0), 0, &chain.miner, &alice, 30);
+        let mut forged = chain.spend(&coinbase(1), 0, &chain.miner, &alice, 30);
+        forged.vin[0].signature[0] ^= 1;
+        let tip_height = chain.bc().get_best_height().unwrap();
+
+        let node = chain.into_node();
+        assert!(node.server.insert_mempool(valid.clone()).is_none());
+        assert!(node.server.insert_mempool(forged.clone()).is_none());
+        node.server.mine_mempool().unwrap();
+
+        // A single block holds the coinbase and the valid transaction
+        assert_eq!(node.server.get_best_height().unwrap(), tip_height + 1);
+        let tip = node.server.get_block_hashs().unwrap()[0].clone();
+        let block = node.server.get_block(&tip).unwrap();
+        let ids: Vec<&str> = block
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.id.as_str())
+            .collect();
+        assert_eq!(ids[1..], [valid.id.as_str()]);
+
+        // The invalid transaction was dropped rather than kept for the next block
+        assert!(node.server.get_mempool().is_empty());
+    }
+
+    #[test]
+    fn rejects_transactions_over_the_input_limit_before_looking_them_up() {
+        let params = ChainParams {
+            max_inputs: 2,
+            ..ChainParams::devnet()
+        };
+        let mut chain = TestChain::with_params(params);
+        chain.mature();
+        let alice = chain.address();
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        let mut tx = chain.spend(&genesis.get_transactions()[0], 0, &chain.miner, &alice, 30);
+
+        // Inputs of unknown transactions would fail any lookup
+        for unknown in ["unknown-1", "unknown-2"] {
+            let mut vin = tx.vin[0].clone();
+            vin.txid = unknown.to_string();
+            tx.vin.push(vin);
+        }
+        tx.id = tx.hash().unwrap();
+
+        assert!(!chain.bc().verify_transaction(&tx).unwrap());
+        let node = chain.into_node();
+        assert_eq!(
+            node.server.accept_to_mempool(&tx).unwrap(),
+            MempoolAcceptResult::Rejected(String::from(
+                "transaction has too many inputs or outputs"
+            ))
+        );
+        assert!(node.server.inner.lock().unwrap().orphans.is_empty());
+    }
+
+    #[test]
+    fn pulls_the_mempool_of_a_peer_when_joining() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let joining = chain.replicate();
+
+        let (peer, peer_addr) = serve_chain(chain, "");
+        assert_eq!(
+            peer.server.accept_to_mempool(&tx).unwrap(),
+            MempoolAcceptResult::Accepted
+        );
+        let (node, _) = serve_chain(joining, "");
+        assert!(node.server.get_mempool().is_empty());
+
+        // mempool -> inv -> getdata -> tx
+        node.server.request_mempool(&peer_addr).unwrap();
+        wait_until("the pulled transaction", || {
+            node.server.get_mempool_tx(&tx.id).is_some()
+        });
+    }
+
+    #[test]
+    fn ignores_inv_messages_without_items() {
+        let node = TestChain::new().into_node();
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap().to_string();
+
+        for kind in ["block", "tx"] {
+            let msg = InvMsg {
+                addr_from: peer_addr.clone(),
+                kind: kind.to_string(),
+                items: vec![],
+            };
+            node.server.handle_inv(msg).unwrap();
+        }
+
+        // Nothing was requested from the peer
+        peer.set_nonblocking(true).unwrap();
+        assert!(peer.accept().is_err());
+        assert!(node.server.get_in_transit().is_empty());
+    }
+
+    #[test]
+    fn rejects_transactions_conflicting_with_the_mempool() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let bob = chain.address();
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        let coinbase = genesis.get_transactions()[0].clone();
+        let first = chain.spend(&coinbase, 0, &chain.miner, &alice, 30);
+        let second = chain.spend(&coinbase, 0, &chain.miner, &bob, 40);
+
+        let node = chain.into_node();
+        assert!(node.server.insert_mempool(first.clone()).is_none());
+        assert_eq!(
+            node.server.insert_mempool(second.clone()),
+            Some(first.id.clone())
+        );
+        assert_eq!(
+            node.server.accept_to_mempool(&second).unwrap(),
+            MempoolAcceptResult::Rejected(format!(
+                "transaction conflicts with {} in the mempool",
+                first.id
+            ))
+        );
+
+        let mempool = node.server.get_mempool();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains_key(&first.id));
+    }
+
+    #[test]
+    fn decodes_two_framed_messages_from_one_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+
+        let magic = ChainParams::devnet().magic;
+        write_frame(&mut sender, &version_frame(&magic, "127.0.0.1:1", 3)).unwrap();
+        write_frame(&mut sender, &version_frame(&magic, "127.0.0.1:2", 7)).unwrap();
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Some(frame) = read_frame(&mut receiver).unwrap() {
+            match bytes_to_cmd(&frame[MAGIC_LENGTH..]).unwrap() {
+                ServerMessage::Version(msg) => received.push((msg.addr_from, msg.best_height)),
+                _ => panic!("expected a version message"),
+            }
+        }
+        assert_eq!(
+            received,
+            vec![
+                ("127.0.0.1:1".to_string(), 3),
+                ("127.0.0.1:2".to_string(), 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_waiting_for_a_version_at_the_deadline() {
+        let node = TestChain::new().into_node();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        // The peer connects but never sends anything
+        let _silent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let start = Instant::now();
+        node.server
+            .await_version(&listener, Duration::from_millis(300))
+            .unwrap();
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn answers_the_version_of_an_unknown_node_with_the_same_height() {
+        let node = TestChain::new().into_node();
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap().to_string();
+        let best_height = node.server.get_best_height().unwrap();
+        let version = |addr_from: &str| VersionMsg {
+            addr_from: addr_from.to_string(),
+            version: VERSION,
+            best_height,
+        };
+
+        node.server.handle_version(version(&peer_addr)).unwrap();
+        match receive(&peer) {
+            ServerMessage::Version(msg) => assert_eq!(msg.best_height, best_height),
+            _ => panic!("expected a version message"),
+        }
+        assert!(matches!(receive(&peer), ServerMessage::Addr(_)));
+
+        // A known node at the same height doesn't get an answer again
+        node.server.handle_version(version(&peer_addr)).unwrap();
+        assert!(matches!(receive(&peer), ServerMessage::Addr(_)));
+    }
+
+    #[test]
+    fn syncs_the_blocks_of_the_seed_node_on_startup() {
+        let mut chain = TestChain::new();
+        let joining = chain.replicate();
+        chain.mine_empty(3);
+        let tip = chain.bc().get_tip_hash();
+        let (_seed, seed_addr) = serve_chain(chain, "");
+
+        // version -> version -> getblocks -> inv -> getdata -> block, for each block
+        let node = Arc::new(joining.into_node_at(&free_addr(), &seed_addr, ""));
+        let starting = node.clone();
+        thread::spawn(move || starting.server.start_server());
+        wait_until("the blocks of the seed node", || {
+            node.server.get_block_hashs().unwrap()[0] == tip
+        });
+        assert_eq!(node.server.get_best_height().unwrap(), 3);
+    }
+
+    #[test]
+    fn drops_peers_that_dont_answer_pings() {
+        let (node, _) = serve_chain(TestChain::new(), "");
+        let (_responsive, responsive_addr) = serve_chain(TestChain::new(), "");
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap().to_string();
+        node.server.add_nodes(&responsive_addr);
+        node.server.add_nodes(&silent_addr);
+
+        node.server.ping_peers().unwrap();
+        wait_until("the pong of the responsive peer", || {
+            !node
+                .server
+                .inner
+                .lock()
+                .unwrap()
+                .pings
+                .contains_key(&responsive_addr)
+        });
+
+        // Let the ping of the silent peer time out
+        let mut inner = node.server.inner.lock().unwrap();
+        let (_, sent) = inner.pings.get_mut(&silent_addr).unwrap();
+        *sent -= PING_TIMEOUT + 1;
+        drop(inner);
+
+        node.server.ping_peers().unwrap();
+        assert!(!node.server.node_is_known(&silent_addr));
+        assert!(node.server.node_is_known(&responsive_addr));
+    }
+
+    #[test]
+    fn broadcasts_a_transaction_to_a_listening_node() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let wallet = chain.replicate();
+        let (node, node_addr) = serve_chain(chain, "");
+
+        Server::send_transaction_to(&tx, wallet.utxo, &free_addr(), &node_addr, Some(0)).unwrap();
+        wait_until("the broadcast transaction", || {
+            node.server.get_mempool_tx(&tx.id).is_some()
+        });
+
+        // Nobody listens on the seed node
+        let offline = TestChain::new();
+        assert!(
+            Server::send_transaction_to(&tx, offline.utxo, &free_addr(), &free_addr(), None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn reports_inv_messages_of_unknown_kinds() {
+        let node = TestChain::new().into_node();
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let inv = |kind: &str, items: Vec<String>| InvMsg {
+            addr_from: peer.local_addr().unwrap().to_string(),
+            kind: kind.to_string(),
+            items,
+        };
+
+        let err = node
+            .server
+            .handle_inv(inv("filter", vec!["item".to_string()]))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown inv kind filter"),
+            "{}",
+            err
+        );
+        node.server.handle_inv(inv("block", vec![])).unwrap();
+
+        // Nothing was requested from the peer
+        peer.set_nonblocking(true).unwrap();
+        assert!(peer.accept().is_err());
+    }
+
+    #[test]
+    fn skips_getdata_requests_for_unknown_data() {
+        let node = TestChain::new().into_node();
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let get_data = |kind: &str| GetDataMsg {
+            addr_from: peer.local_addr().unwrap().to_string(),
+            kind: kind.to_string(),
+            id: "unknown".to_string(),
+        };
+
+        node.server.handle_get_data(get_data("tx")).unwrap();
+        node.server.handle_get_data(get_data("block")).unwrap();
+
+        // Nothing was sent back
+        peer.set_nonblocking(true).unwrap();
+        assert!(peer.accept().is_err());
+    }
+
+    #[test]
+    fn rejects_peers_with_an_incompatible_version() {
+        let node = TestChain::new().into_node();
+        let version = |addr_from: &str, version: u32| VersionMsg {
+            addr_from: addr_from.to_string(),
+            version,
+            best_height: 0,
+        };
+
+        let incompatible = free_addr();
+        node.server
+            .handle_version(version(&incompatible, 99))
+            .unwrap();
+        assert!(!node.server.node_is_known(&incompatible));
+
+        // A known node switching to an incompatible version is dropped
+        let peer = free_addr();
+        node.server.handle_version(version(&peer, VERSION)).unwrap();
+        assert!(node.server.node_is_known(&peer));
+        node.server.handle_version(version(&peer, 99)).unwrap();
+        assert!(!node.server.node_is_known(&peer));
+    }
+
+    #[test]
+    fn evicts_the_transactions_of_a_block_mined_by_a_peer() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        let listening = chain.replicate();
+        let miner = chain.miner.clone();
+        let (mining, _) = serve_chain(chain, &miner);
+        let (node, node_addr) = serve_chain(listening, "");
+
+        // Both nodes know the transaction, the mining node mines it
+        assert!(node.server.insert_mempool(tx.clone()).is_none());
+        mining.server.add_nodes(&node_addr);
+        mining
+            .server
+            .handle_tx(TxMsg {
+                addr_from: free_addr(),
+                transaction: tx.clone(),
+            })
+            .unwrap();
+        assert!(mining.server.get_mempool_tx(&tx.id).is_none());
+
+        // inv -> getdata -> block
+        wait_until("the block of the mining node", || {
+            node.server.get_mempool_tx(&tx.id).is_none()
+        });
+        assert_eq!(
+            node.server.get_best_height().unwrap(),
+            mining.server.get_best_height().unwrap()
+        );
+    }
+
+    #[test]
+    fn listens_on_localhost_with_the_convenience_constructor() {
+        let TestChain {
+            dir: _dir, utxo, ..
+        } = TestChain::new();
+        let server = Server::new_local("3999", "", utxo).unwrap();
+        assert_eq!(server.node_addr, "localhost:3999");
+        assert_eq!(server.seed_node, KNOWN_NODE);
+    }
+
+    #[test]
+    fn skips_sending_to_itself_under_another_host_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let node = TestChain::new().into_node_at(&format!("127.0.0.1:{}", port), "127.0.0.1:1", "");
+
+        assert!(node.server.is_self(&format!("localhost:{}", port)));
+        assert!(!node.server.is_self(&free_addr()));
+        node.server
+            .send_data(&format!("localhost:{}", port), b"data")
+            .unwrap();
+
+        // Nothing was sent to the node's own listener
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn exchanges_headers_between_two_nodes() {
+        let (node, _) = serve_chain(TestChain::new(), "");
+        let mut chain = TestChain::new();
+        chain.mine_empty(3);
+        let mut hashes = chain.bc().get_block_hashs().unwrap();
+        hashes.reverse();
+        let (_peer, peer_addr) = serve_chain(chain, "");
+
+        // getheaders -> headers, answered over a connection of its own
+        node.server.request_headers(&peer_addr).unwrap();
+        wait_until("the headers of the peer", || {
+            !node.server.peer_headers().is_empty()
+        });
+        let received = node.server.peer_headers();
+        let headers = received.values().next().unwrap();
+        let received: Vec<String> = headers.iter().map(|h| h.get_hash()).collect();
+        assert_eq!(received, hashes);
+    }
+
+    #[test]
+    fn checks_the_difficulty_of_headers_and_keys_them_by_connection() {
+        let mut chain = TestChain::new();
+        // Past RETARGET_INTERVAL, so a retarget is checked as well
+        chain.mine_empty(RETARGET_INTERVAL as usize + 2);
+        let bc = chain.bc();
+        let mut headers: Vec<BlockHeader> = bc
+            .iter_hashes()
+            .map(|h| bc.get_block_header(&h.unwrap()).unwrap())
+            .collect();
+        headers.reverse();
+        let claimed = free_addr();
+        let msg = || HeadersMsg {
+            addr_from: claimed.clone(),
+            headers: headers.clone(),
+        };
+
+        // The peer is known by its connection, not by the address it claims
+        let node = TestChain::new().into_node();
+        node.server.handle_headers(msg(), "127.0.0.1:4000").unwrap();
+        let stored = node.server.peer_headers();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored["127.0.0.1:4000"], headers);
+
+        // The same headers miss the difficulty of a harder network
+        let harder = ChainParams {
+            difficulty: ChainParams::devnet().difficulty + 1,
+            ..ChainParams::devnet()
+        };
+        let node = TestChain::with_params(harder).into_node();
+        let err = node
+            .server
+            .handle_headers(msg(), "127.0.0.1:4000")
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid proof of work"), "{}", err);
+        assert!(node.server.peer_headers().is_empty());
+    }
+
+    #[test]
+    fn removes_a_peer_once_it_stops_answering() {
+        let node = TestChain::new().into_node();
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap().to_string();
+        node.server.add_nodes(&peer_addr);
+        let last_seen = |node: &TestNode| {
+            let info = node.server.peer_info();
+            info.into_iter()
+                .find(|p| p.addr == peer_addr)
+                .unwrap()
+                .last_seen
+        };
+        assert_eq!(last_seen(&node), None);
+
+        // The peer answers the first ping
+        node.server.ping_peers().unwrap();
+        let nonce = match receive(&peer) {
+            ServerMessage::Ping(msg) => msg.nonce,
+            _ => panic!("expected a ping message"),
+        };
+        node.server
+            .handle_pong(PongMsg {
+                addr_from: peer_addr.clone(),
+                nonce,
+            })
+            .unwrap();
+        assert!(last_seen(&node).is_some());
+
+        // Then stays silent past the ping timeout
+        node.server.ping_peers().unwrap();
+        assert!(matches!(receive(&peer), ServerMessage::Ping(_)));
+        let mut inner = node.server.inner.lock().unwrap();
+        let (_, sent) = inner.pings.get_mut(&peer_addr).unwrap();
+        *sent -= PING_TIMEOUT + 1;
+        drop(inner);
+
+        let count = node.server.get_peer_count();
+        node.server.ping_peers().unwrap();
+        assert!(!node.server.node_is_known(&peer_addr));
+        assert_eq!(node.server.get_peer_count(), count - 1);
+    }
+}
@@ -0,0 +1,216 @@
+// Helpers shared by the unit tests
+// Every chain lives in its own temporary directory, so tests can run in parallel
+use crate::block::Block;
+use crate::blockchain::{Blockchain, VerificationLevel};
+use crate::params::ChainParams;
+use crate::server::Server;
+use crate::transaction::{OutPoint, TXInput, TXOutput, Transaction, COINBASE_MATURITY};
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallets;
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+// TestChain struct holds a chain on the development network along with its wallets
+// The genesis reward and every block mined with mine are paid to the miner address
+pub struct TestChain {
+    pub dir: TempDir, // directory of the databases, removed when the chain is dropped
+    pub wallets: Wallets, // wallets of the chain, saved in the same directory
+    pub miner: String, // address the block rewards are sent to
+    pub utxo: UTXOSet, // UTXO set built on the chain
+}
+
+impl TestChain {
+    // Create a chain on the development network
+    pub fn new() -> Self {
+        Self::with_params(ChainParams::devnet())
+    }
+
+    // Create a chain with the given parameters
+    // params: the parameters of the network
+    pub fn with_params(params: ChainParams) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_with_path(dir.path()).unwrap();
+        let miner = wallets.create_wallet();
+        wallets.save_all().unwrap();
+
+        let bc = Blockchain::create_blockchain_at(dir.path(), miner.clone(), params).unwrap();
+        let utxo = UTXOSet::new(bc).unwrap();
+
+        Self {
+            dir,
+            wallets,
+            miner,
+            utxo,
+        }
+    }
+
+    // Create and save a new wallet and return its address
+    pub fn address(&mut self) -> String {
+        let address = self.wallets.create_wallet();
+        self.wallets.save_all().unwrap();
+        address
+    }
+
+    // Get the blockchain
+    pub fn bc(&self) -> &Blockchain {
+        &self.utxo.blockchain
+    }
+
+    // Get the height of the next block
+    pub fn next_height(&self) -> usize {
+        self.bc().get_best_height().unwrap() as usize + 1
+    }
+
+    // Create the coinbase transaction of the next block
+    // to: the address of the receiver
+    pub fn coinbase(&self, to: &str) -> Transaction {
+        Transaction::new_coinbase_in(
+            &self.wallets,
+            to.to_string(),
+            String::new(),
+            self.next_height(),
+        )
+        .unwrap()
+    }
+
+    // Mine a block paying the reward to the miner and apply it to the UTXO set
+    // txs: the transactions to include after the coinbase
+    pub fn mine(&mut self, txs: Vec<Transaction>) -> Block {
+        let miner = self.miner.clone();
+        self.mine_to(&miner, txs)
+    }
+
+    // Mine a block paying the reward to the given address and apply it to the UTXO set
+    // to: the address of the receiver of the reward
+    // txs: the transactions to include after the coinbase
+    pub fn mine_to(&mut self, to: &str, txs: Vec<Transaction>) -> Block {
+        let mut block_txs = vec![self.coinbase(to)];
+        block_txs.extend(txs);
+
+        let block = self.utxo.blockchain.mine_block(block_txs).unwrap();
+        self.utxo.update(&block).unwrap();
+        block
+    }
+
+    // Mine n blocks without transactions
+    pub fn mine_empty(&mut self, n: usize) {
+        for _ in 0..n {
+            self.mine(Vec::new());
+        }
+    }
+
+    // Mine enough blocks for the genesis reward to be spendable
+    pub fn mature(&mut self) {
+        self.mine_empty(COINBASE_MATURITY as usize);
+    }
+
+    // Build a signed transaction between two wallets of the chain
+    // from: the address of the sender
+    // to: the address of the receiver
+    // amount: the amount to send
+    pub fn send(&self, from: &str, to: &str, amount: i32) -> Transaction {
+        let wallet = self.wallets.get_wallet(from).unwrap();
+        Transaction::new_utxo(wallet, to, amount, &self.utxo).unwrap()
+    }
+
+    // Build a signed transaction spending one output of a transaction, confirmed or not
+    // The rest of the output's value is left as fee
+    // prev: the transaction whose output is spent
+    // vout: the index of the spent output
+    // from: the address the output is locked to
+    // to: the address of the receiver
+    // amount: the amount to send
+    pub fn spend(
+        &self,
+        prev: &Transaction,
+        vout: u32,
+        from: &str,
+        to: &str,
+        amount: i32,
+    ) -> Transaction {
+        let wallet = self.wallets.get_wallet(from).unwrap();
+        let mut tx = Transaction {
+            id: String::new(),
+            version: prev.version,
+            expiry_height: None,
+            time_lock: None,
+            lock_time: 0,
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: OutPoint::Index(vout),
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(amount, to.to_string()).unwrap()],
+        };
+        tx.id = tx.hash().unwrap();
+
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        tx.sign(wallet, prev_txs).unwrap();
+        tx
+    }
+
+    // Sign a modified transaction again so it stays valid
+    // from: the address of the sender
+    // tx: the transaction to sign
+    pub fn resign(&self, from: &str, tx: &mut Transaction) {
+        tx.id = tx.hash().unwrap();
+        let wallet = self.wallets.get_wallet(from).unwrap();
+        self.bc().sign_transaction(tx, wallet).unwrap();
+    }
+
+    // Create a chain in another directory holding the same blocks and wallets, e.g. for a peer
+    pub fn replicate(&self) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_with_path(dir.path()).unwrap();
+        for address in self.wallets.get_all_address() {
+            let wallet = self.wallets.get_wallet(&address).unwrap();
+            wallets.import(&wallet.secret_key).unwrap();
+        }
+        wallets.save_all().unwrap();
+
+        // An empty chain imports the blocks from the genesis block
+        let params = self.bc().get_params().clone();
+        let mut bc = Blockchain::open(dir.path(), params).unwrap();
+        let best_height = self.bc().get_best_height().unwrap() as usize;
+        let blocks = (0..=best_height)
+            .map(|height| self.bc().get_block_by_height(height).unwrap())
+            .collect();
+        bc.import(blocks, VerificationLevel::TrustedNoVerify)
+            .unwrap();
+        let utxo = UTXOSet::new(bc).unwrap();
+        utxo.reindex().unwrap();
+
+        Self {
+            dir,
+            wallets,
+            miner: self.miner.clone(),
+            utxo,
+        }
+    }
+
+    // Run a node on the chain, mining to the miner address
+    // The node isn't started, its handlers are called directly by the tests
+    pub fn into_node(self) -> TestNode {
+        let miner = self.miner.clone();
+        self.into_node_at("127.0.0.1:0", "127.0.0.1:1", &miner)
+    }
+
+    // Run a node on the chain with the given address
+    // addr: the address of the node
+    // seed_node: the address of the node to join the network through
+    // miner_addr: the address mining rewards are sent to, empty if the node doesn't mine
+    pub fn into_node_at(self, addr: &str, seed_node: &str, miner_addr: &str) -> TestNode {
+        let server = Server::new(addr, seed_node, miner_addr, self.utxo).unwrap();
+        TestNode {
+            _dir: self.dir,
+            server,
+        }
+    }
+}
+
+// TestNode struct holds a node built on a TestChain
+pub struct TestNode {
+    _dir: TempDir,      // directory of the databases, removed when the node is dropped
+    pub server: Server, // the node
+}
@@ -1,26 +1,104 @@
-use crate::utils::hash_pub_key;
-use crate::utxoset::UTXOSet;
-use crate::wallet::Wallets;
-use crate::{errors::Result, wallet::Wallet};
+use crate::utils::{hash_pub_key, pub_key_hash_from_address};
+use crate::utxoset::{CoinSelection, UTXOSet};
+use crate::wallet::{Signer, Wallets};
+use crate::{
+    errors::{BlockchainError, Result},
+    wallet::Wallet,
+};
 use anyhow::anyhow;
-use bitcoincash_addr::Address;
 use crypto::digest::Digest;
 use crypto::ed25519;
 use crypto::sha2::Sha256;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+const TX_VERSION: u32 = 1; // highest transaction version this node understands
+pub const SUBSIDY: i32 = 100; // reward for mining a block before any halving
+pub const HALVING_INTERVAL: usize = 210; // number of blocks after which the reward halves
+pub const COINBASE_MATURITY: u32 = 10; // confirmations before a coinbase output can be spent
+pub const MAX_INPUTS: usize = 1000; // default maximum number of inputs of a transaction
+pub const MAX_OUTPUTS: usize = 1000; // default maximum number of outputs of a transaction
+pub const DUST_THRESHOLD: i32 = 2; // default smallest value a non-coinbase output may carry
 
 // Transaction struct that holds the data of the transaction (mimics the Bitcoin transaction)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
-    pub id: String,          // Hash of the transaction
-    pub vin: Vec<TXInput>,   // Inputs of the transaction
+    pub id: String,                 // Hash of the transaction
+    pub version: u32,               // Version of the transaction format
+    pub expiry_height: Option<u32>, // Height after which the transaction is no longer valid
+    pub time_lock: Option<u128>, // Median time past (ms) the chain must reach before the transaction is valid
+    pub lock_time: u32, // Best height the chain must reach before the transaction is valid, 0 for none
+    pub vin: Vec<TXInput>, // Inputs of the transaction
     pub vout: Vec<TXOutput>, // Outputs of the transaction
 }
 
+// ChangePolicy enum decides where the change of a transaction is sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangePolicy {
+    SameAddress,  // Send the change back to the sender's address
+    FreshAddress, // Send the change to a newly created and saved wallet address
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutputs {
     pub outputs: Vec<TXOutput>,
+    pub indices: Vec<u32>, // Index in the transaction of each output, spent outputs are removed
+    pub height: u32,       // Height of the block that created the outputs
+    pub coinbase: bool,    // Whether the outputs were created by a coinbase transaction
+}
+
+impl TXOutputs {
+    // Create the unspent outputs of a new transaction
+    // tx: the transaction creating the outputs
+    // height: the height of the block including the transaction
+    pub fn from_transaction(tx: &Transaction, height: u32) -> Self {
+        Self {
+            outputs: tx.vout.clone(),
+            indices: (0..tx.vout.len() as u32).collect(),
+            height,
+            coinbase: tx.is_coinbase(),
+        }
+    }
+
+    // Iterate over the unspent outputs along with their index in the transaction
+    pub fn indexed(&self) -> impl Iterator<Item = (u32, &TXOutput)> {
+        self.indices.iter().copied().zip(self.outputs.iter())
+    }
+
+    // Check if the output at the index of the transaction is unspent
+    // index: the index of the output in the transaction
+    pub fn contains(&self, index: u32) -> bool {
+        self.indices.contains(&index)
+    }
+
+    // Remove the output at the index of the transaction and return it, None if it was already spent
+    // index: the index of the output in the transaction
+    pub fn spend(&mut self, index: u32) -> Option<TXOutput> {
+        let pos = self.indices.iter().position(|idx| *idx == index)?;
+        self.indices.remove(pos);
+        Some(self.outputs.remove(pos))
+    }
+
+    // Check if the outputs can be spent in the block following the given best height
+    // Coinbase outputs need COINBASE_MATURITY confirmations, other outputs are always mature
+    // best_height: the height of the tip of the chain
+    pub fn is_mature(&self, best_height: u32) -> bool {
+        !self.coinbase || best_height.saturating_sub(self.height) + 1 >= COINBASE_MATURITY
+    }
+}
+
+// Get the reward of the coinbase transaction of the block at the given height
+// The reward halves every HALVING_INTERVAL blocks and ends at zero
+// height: the height of the block
+pub fn coinbase_reward(height: usize) -> i32 {
+    let halvings = height / HALVING_INTERVAL;
+
+    // Shifting by the bit width or more would overflow, the reward is long gone by then
+    if halvings >= i32::BITS as usize {
+        return 0;
+    }
+    SUBSIDY >> halvings
 }
 
 impl Transaction {
@@ -30,34 +108,210 @@ impl Transaction {
     // amount: the amount to be sent
     // utxoset: the UTXO set of from address
     pub fn new_utxo(from: &Wallet, to: &str, amount: i32, utxoset: &UTXOSet) -> Result<Self> {
+        Self::new_utxo_with_change(from, to, amount, utxoset, ChangePolicy::SameAddress)
+    }
+
+    // Create a new transaction sending the change according to the change policy
+    // from: the wallet of the sender
+    // to: the address of the receiver
+    // amount: the amount to be sent
+    // utxoset: the UTXO set of from address
+    // change_policy: where to send the change
+    pub fn new_utxo_with_change(
+        from: &Wallet,
+        to: &str,
+        amount: i32,
+        utxoset: &UTXOSet,
+        change_policy: ChangePolicy,
+    ) -> Result<Self> {
+        Self::new_utxo_to(
+            from,
+            &[(to.to_string(), amount)],
+            utxoset,
+            change_policy,
+            None,
+        )
+    }
+
+    // Create a new transaction from a wallet of the given wallets, sending the change according
+    // to the change policy
+    // A fresh change address is created in these wallets, so it is saved with their passphrase
+    // wallets: the wallets of the sender
+    // from: the address of the sender
+    // to: the address of the receiver
+    // amount: the amount to be sent
+    // utxoset: the UTXO set of from address
+    // change_policy: where to send the change
+    pub fn new_utxo_with_change_in(
+        wallets: &mut Wallets,
+        from: &str,
+        to: &str,
+        amount: i32,
+        utxoset: &UTXOSet,
+        change_policy: ChangePolicy,
+    ) -> Result<Self> {
+        let wallet = match wallets.get_wallet(from) {
+            Some(wallet) => wallet.clone(),
+            None => return Err(anyhow!("wallet not found for address: {}", from)),
+        };
+        Self::new_utxo_to(
+            &wallet,
+            &[(to.to_string(), amount)],
+            utxoset,
+            change_policy,
+            Some(wallets),
+        )
+    }
+
+    // Create a new transaction paying several receivers in one go
+    // Every recipient gets its own output, even when an address appears more than once
+    // from: the wallet of the sender
+    // recipients: the address and the amount of each receiver
+    // utxoset: the UTXO set of from address
+    pub fn new_utxo_multi(
+        from: &Wallet,
+        recipients: &[(String, i32)],
+        utxoset: &UTXOSet,
+    ) -> Result<Self> {
+        if recipients.is_empty() {
+            return Err(anyhow!("transaction needs at least one recipient"));
+        }
+
+        // Outputs without value would only bloat the UTXO set
+        for (to, amount) in recipients {
+            if *amount <= 0 {
+                return Err(anyhow!("invalid amount for recipient {}: {}", to, amount));
+            }
+        }
+
+        Self::new_utxo_to(from, recipients, utxoset, ChangePolicy::SameAddress, None)
+    }
+
+    // Create a new transaction paying the recipients and sending the change according to the change policy
+    // from: the wallet of the sender
+    // recipients: the address and the amount of each receiver
+    // utxoset: the UTXO set of from address
+    // change_policy: where to send the change
+    // wallets: the wallets to create a fresh change address in, the wallets database of the
+    // chain is opened if None
+    fn new_utxo_to(
+        from: &Wallet,
+        recipients: &[(String, i32)],
+        utxoset: &UTXOSet,
+        change_policy: ChangePolicy,
+        wallets: Option<&mut Wallets>,
+    ) -> Result<Self> {
+        // The spendable outputs must cover the payments to all recipients
+        let amount = recipients
+            .iter()
+            .try_fold(0i32, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or_else(|| anyhow!("amounts to send overflow"))?;
+
         // Get the public key hash of the sender
         let mut pub_key_hash = from.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
 
         // Find the spendable outputs of the sender and the total amount
-        let acc_v: (i32, HashMap<String, Vec<i32>>) =
+        let acc_v: (i32, HashMap<String, Vec<u32>>) =
             utxoset.find_spendable_outputs(&pub_key_hash, amount)?;
 
         // Check if the sender has enough balance
         if acc_v.0 < amount {
-            return Err(anyhow!(
-                "Not Enough balance for transaction: {} < {}",
-                acc_v.0,
-                amount
-            ));
+            return Err(BlockchainError::InsufficientBalance {
+                have: acc_v.0,
+                need: amount,
+            }
+            .into());
         }
 
-        // Create the inputs and outputs of the transaction
-        let mut vin = Vec::new();
+        // Create the outputs of the transaction
+        // vout[0..recipients.len()] are for the receivers, in order
+        let dust_threshold = utxoset.blockchain.get_params().dust_threshold;
+        let mut vout = Vec::new();
+        for (to, amount) in recipients {
+            if *amount < dust_threshold {
+                return Err(anyhow!(
+                    "amount for recipient {} is below the dust threshold: {} < {}",
+                    to,
+                    amount,
+                    dust_threshold
+                ));
+            }
+            vout.push(TXOutput::new(*amount, to.clone())?);
+        }
+
+        // the last output is for the sender (change)
+        // change below the dust threshold is left to the miner as fee instead
+        if acc_v.0 - amount >= dust_threshold {
+            let change_address = match change_policy {
+                ChangePolicy::SameAddress => from.get_address(),
+                ChangePolicy::FreshAddress => {
+                    // Save the new wallet so the change is spendable later
+                    let mut opened;
+                    let wallets = match wallets {
+                        Some(wallets) => wallets,
+                        None => {
+                            opened = Wallets::new_with_path(utxoset.blockchain.get_path())?;
+                            &mut opened
+                        }
+                    };
+                    let address = wallets.create_wallet();
+                    wallets.save_all()?;
+                    address
+                }
+            };
+            vout.push(TXOutput::new(acc_v.0 - amount, change_address)?);
+        }
+
+        Self::new_signed(from, acc_v.1, vout, utxoset)
+    }
+
+    // Create a new transaction paying the whole spendable balance of the sender to one receiver
+    // No change output is created, so the wallet is left empty
+    // from: the wallet of the sender
+    // to: the address of the receiver
+    // utxoset: the UTXO set of from address
+    pub fn new_utxo_send_all(from: &Wallet, to: &str, utxoset: &UTXOSet) -> Result<Self> {
+        let mut pub_key_hash = from.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        // Select every spendable output of the sender
+        let (total, inputs) =
+            utxoset.find_spendable_outputs_with(&pub_key_hash, 0, CoinSelection::All)?;
+
+        let dust_threshold = utxoset.blockchain.get_params().dust_threshold;
+        if total < dust_threshold {
+            return Err(BlockchainError::InsufficientBalance {
+                have: total,
+                need: dust_threshold,
+            }
+            .into());
+        }
+
+        let vout = vec![TXOutput::new(total, to.to_string())?];
+        Self::new_signed(from, inputs, vout, utxoset)
+    }
 
+    // Create and sign a transaction spending the given outputs of the sender
+    // from: the wallet of the sender
+    // inputs: the output indexes to spend, by transaction id
+    // vout: the outputs of the transaction
+    // utxoset: the UTXO set of from address
+    fn new_signed(
+        from: &Wallet,
+        inputs: HashMap<String, Vec<u32>>,
+        vout: Vec<TXOutput>,
+        utxoset: &UTXOSet,
+    ) -> Result<Self> {
         // Create the inputs of the transaction
-        for tx in acc_v.1 {
+        let mut vin = Vec::new();
+        for tx in inputs {
             // tx.0 is the transaction id
             // tx.1 is the list of output indexes
             for out in tx.1 {
                 let input = TXInput {
                     txid: tx.0.clone(),
-                    vout: out,
+                    vout: OutPoint::Index(out),
                     signature: Vec::new(),
                     pub_key: from.public_key.clone(),
                 };
@@ -65,18 +319,13 @@ impl Transaction {
             }
         }
 
-        // Create the outputs of the transaction
-        // vout[0] is for the receiver
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
-
-        // vout[1] is for the sender (change)
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, from.get_address())?);
-        }
-
         // Create the transaction
         let mut tx = Transaction {
             id: String::new(),
+            version: TX_VERSION,
+            expiry_height: None,
+            time_lock: None,
+            lock_time: 0,
             vin,
             vout,
         };
@@ -84,43 +333,138 @@ impl Transaction {
         // Set the id of the transaction
         tx.id = tx.hash()?;
 
-        // Sign the transaction with the private key of the sender
-        utxoset
-            .blockchain
-            .sign_transaction(&mut tx, &from.secret_key)?;
+        // Check the selected outputs are still unspent right before signing
+        for vin in &tx.vin {
+            if !utxoset.has_output(&vin.txid, vin.vout)? {
+                return Err(anyhow!(
+                    "selected output is no longer unspent: {}:{}",
+                    vin.txid,
+                    vin.vout
+                ));
+            }
+        }
+
+        // Sign the transaction with the wallet of the sender
+        utxoset.blockchain.sign_transaction(&mut tx, from)?;
 
         // Return the transaction
         Ok(tx)
     }
 
     // Create a new coinbase transaction
+    // The receiver doesn't need a local wallet, only a valid address
     // to: the address of the receiver
     // data: the data of the transaction
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Self> {
+    // height: the height of the block the transaction is mined in
+    pub fn new_coinbase(to: String, mut data: String, height: usize) -> Result<Self> {
         // If the data is empty, set the default data
         if data.is_empty() {
             data = format!("Reward to '{}'", to);
         }
 
-        // Find the wallet of the receiver
-        let wallets = Wallets::new()?;
+        // Create the transaction
+        // tx.vin[0] is the coinbase input (no previous transaction)
+        // tx.vout[0] is for the receiver (the reward)
+        let mut tx = Transaction {
+            id: String::new(),
+            version: TX_VERSION,
+            expiry_height: None,
+            time_lock: None,
+            lock_time: 0,
+            vin: vec![TXInput::coinbase(&data, height)],
+            vout: vec![TXOutput::new(coinbase_reward(height), to)?],
+        };
 
+        // Set the id of the transaction
+        tx.id = tx.hash()?;
+
+        // Return the transaction
+        Ok(tx)
+    }
+
+    // Create a new coinbase transaction paying a wallet of the given wallets
+    // wallets: the wallets the receiver must belong to
+    // to: the address of the receiver
+    // data: the data of the transaction
+    // height: the height of the block the transaction is mined in
+    pub fn new_coinbase_in(
+        wallets: &Wallets,
+        to: String,
+        data: String,
+        height: usize,
+    ) -> Result<Self> {
+        // Find the wallet of the receiver
         if let None = wallets.get_wallet(&to) {
             return Err(anyhow!("wallet not found for address: {}", to));
         }
 
+        Self::new_coinbase(to, data, height)
+    }
+
+    // Create a new coinbase transaction paying several receivers
+    // outputs: the address and the amount of each receiver
+    // data: the data of the transaction
+    // height: the height of the block the transaction is mined in
+    // fees: the fees of the other transactions of the block
+    pub fn new_coinbase_multi(
+        outputs: &[(String, i32)],
+        mut data: String,
+        height: usize,
+        fees: i32,
+    ) -> Result<Self> {
+        if outputs.is_empty() {
+            return Err(anyhow!("coinbase needs at least one output"));
+        }
+
+        // Every output must be worth spending, a negative one would hide an oversized one
+        for (to, amount) in outputs {
+            if *amount <= 0 || *amount < DUST_THRESHOLD {
+                return Err(anyhow!(
+                    "coinbase output to {} is below the dust threshold: {} < {}",
+                    to,
+                    amount,
+                    DUST_THRESHOLD
+                ));
+            }
+        }
+
+        // The outputs must share exactly the reward and the fees of the block
+        let total = outputs
+            .iter()
+            .try_fold(0i32, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or_else(|| anyhow!("coinbase outputs overflow"))?;
+        let allowed = coinbase_reward(height)
+            .checked_add(fees)
+            .ok_or_else(|| anyhow!("coinbase reward and fees overflow"))?;
+        if total != allowed {
+            return Err(anyhow!(
+                "coinbase outputs must total the reward and fees: {} != {}",
+                total,
+                allowed
+            ));
+        }
+
+        // If the data is empty, set the default data
+        if data.is_empty() {
+            data = format!("Reward to {} receivers", outputs.len());
+        }
+
+        // Create the outputs of the transaction
+        let mut vout = Vec::new();
+        for (to, amount) in outputs {
+            vout.push(TXOutput::new(*amount, to.clone())?);
+        }
+
         // Create the transaction
         // tx.vin[0] is the coinbase input (no previous transaction)
-        // tx.vout[0] is for the receiver (the reward)
         let mut tx = Transaction {
             id: String::new(),
-            vin: vec![TXInput {
-                txid: String::new(),
-                vout: -1,
-                signature: Vec::new(),
-                pub_key: Vec::from(data.as_bytes()),
-            }],
-            vout: vec![TXOutput::new(100, to)?],
+            version: TX_VERSION,
+            expiry_height: None,
+            time_lock: None,
+            lock_time: 0,
+            vin: vec![TXInput::coinbase(&data, height)],
+            vout,
         };
 
         // Set the id of the transaction
@@ -130,13 +474,47 @@ impl Transaction {
         Ok(tx)
     }
 
+    // Get the total value of the outputs, failing if it overflows
+    pub fn output_value(&self) -> Result<i32> {
+        self.vout
+            .iter()
+            .try_fold(0i32, |total, out| total.checked_add(out.value))
+            .ok_or_else(|| anyhow!("output values of transaction {} overflow", self.id))
+    }
+
+    // Get the fee paid by the transaction: what the inputs provide beyond the outputs
+    // prev_txs: the previous transactions referenced by the inputs
+    pub fn fee(&self, prev_txs: &HashMap<String, Transaction>) -> Result<i32> {
+        // coinbase transactions don't pay fees
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+
+        // resolve the values of the outputs spent by the inputs
+        let mut input_value: i32 = 0;
+        for vin in &self.vin {
+            let out = prev_txs
+                .get(&vin.txid)
+                .and_then(|prev_tx| vin.vout.index().and_then(|idx| prev_tx.vout.get(idx)))
+                .ok_or_else(|| anyhow!("Output not found: {}:{}", vin.txid, vin.vout))?;
+            input_value = input_value
+                .checked_add(out.value)
+                .ok_or_else(|| anyhow!("input values of transaction {} overflow", self.id))?;
+        }
+
+        input_value
+            .checked_sub(self.output_value()?)
+            .ok_or_else(|| anyhow!("fee of transaction {} overflows", self.id))
+    }
+
     // Get the transaction id (hash)
-    pub(crate) fn hash(&mut self) -> Result<String> {
-        // Clear the id of the transaction
-        self.id = String::new();
+    pub fn hash(&self) -> Result<String> {
+        // Clear the id of a copy of the transaction, the id is not part of the hash
+        let mut tx = self.clone();
+        tx.id = String::new();
 
         // Serialize the transaction
-        let data = bincode::serialize(self)?;
+        let data = bincode::serialize(&tx)?;
 
         // Hash the serialized transaction
         let mut hasher = Sha256::new();
@@ -145,19 +523,83 @@ impl Transaction {
         // Return the hash
         Ok(hasher.result_str())
     }
+    // Check if the transaction version is understood by this node
+    pub fn is_supported_version(&self) -> bool {
+        self.version >= 1 && self.version <= TX_VERSION
+    }
+
+    // Check if the transaction has no more inputs and outputs than the limits
+    // Checking it is cheap, so it runs before any lookup or signature check
+    // max_inputs: the maximum number of inputs
+    // max_outputs: the maximum number of outputs
+    pub fn within_limits(&self, max_inputs: usize, max_outputs: usize) -> bool {
+        self.vin.len() <= max_inputs && self.vout.len() <= max_outputs
+    }
+
+    // Check if the transaction creates outputs too small to be worth spending
+    // Coinbase outputs are exempt, the reward itself shrinks with every halving
+    // threshold: the smallest value an output may carry
+    pub fn has_dust(&self, threshold: i32) -> bool {
+        !self.is_coinbase() && self.vout.iter().any(|out| out.value < threshold)
+    }
+
+    // Check if the transaction has expired
+    // height: the height of the block that would include the transaction
+    pub fn is_expired(&self, height: u32) -> bool {
+        match self.expiry_height {
+            Some(expiry) => height > expiry,
+            None => false,
+        }
+    }
+
+    // Check if the transaction is still locked by its lock time
+    // height: the height of the block that would include the transaction
+    pub fn is_height_locked(&self, height: u32) -> bool {
+        // The chain must reach lock_time before the block including the transaction
+        self.lock_time != 0 && self.lock_time >= height
+    }
+
+    // Check if the transaction is still locked by its time lock
+    // median_time: the median time past of the chain the transaction would be included in
+    pub fn is_time_locked(&self, median_time: u128) -> bool {
+        match self.time_lock {
+            Some(lock) => median_time < lock,
+            None => false,
+        }
+    }
+
+    // Get the output of the transaction spent by the input
+    // vin: the input spending an output of this transaction
+    fn output_at(&self, vin: &TXInput) -> Result<&TXOutput> {
+        match vin.vout.index().and_then(|idx| self.vout.get(idx)) {
+            Some(out) => Ok(out),
+            None => Err(anyhow!("Output not found: {}:{}", vin.txid, vin.vout)),
+        }
+    }
+
     // Check if the transaction is a coinbase transaction
     pub fn is_coinbase(&self) -> bool {
         // A coinbase transaction has only one input with no previous transaction
         // and the vout is -1
-        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
+        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout.is_coinbase()
+    }
+
+    // Get the total amount sent to the public key hash
+    // pub_key_hash: the public key hash of the receiver
+    pub fn amount_to(&self, pub_key_hash: &[u8]) -> i32 {
+        self.vout
+            .iter()
+            .filter(|out| out.is_locked_with_key(pub_key_hash))
+            .map(|out| out.value)
+            .sum()
     }
 
     // Sign the transaction
-    // private_key: the private key of the sender
+    // signer: the signer holding the private key of the sender
     // prev_txs: has output transactions of the inputs of current transaction
     pub fn sign(
         &mut self,
-        private_key: &[u8],
+        signer: &dyn Signer,
         prev_txs: HashMap<String, Transaction>,
     ) -> Result<()> {
         // If the transaction is a coinbase transaction, return true
@@ -192,9 +634,7 @@ impl Transaction {
             tx_copy.vin[idx].signature.clear();
 
             // set the public key hash of previous transaction as public key of copied transaction
-            tx_copy.vin[idx].pub_key = prev_tx.vout[tx_copy.vin[idx].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[idx].pub_key = prev_tx.output_at(&tx_copy.vin[idx])?.pub_key_hash.clone();
 
             // Hash the copied transaction
             tx_copy.id = tx_copy.hash()?;
@@ -202,11 +642,11 @@ impl Transaction {
             // Clear the public key of copied transaction
             tx_copy.vin[idx].pub_key = Vec::new();
 
-            // Generate the signature with the hash of copied transaction and the private key
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
+            // Generate the signature with the hash of copied transaction and the signer
+            let signature = signer.sign(tx_copy.id.as_bytes())?;
 
             // Set the signature of the current transaction
-            self.vin[idx].signature = signature.to_vec();
+            self.vin[idx].signature = signature;
         }
 
         // Return true if the transaction is signed successfully
@@ -216,11 +656,31 @@ impl Transaction {
     // Verify the transaction
     // prev_txs: has output transactions of the inputs of current transaction
     pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
+        // Reject transactions with a version this node doesn't understand
+        if !self.is_supported_version() {
+            return Ok(false);
+        }
+
+        // Negative outputs would offset the value of the others
+        if self.vout.iter().any(|out| out.value < 0) {
+            return Ok(false);
+        }
+
         // If the transaction is a coinbase transaction, return true
         if self.is_coinbase() {
             return Ok(true);
         }
 
+        // An output can't be spent twice by the same transaction
+        let mut spent = HashSet::new();
+        if !self
+            .vin
+            .iter()
+            .all(|vin| spent.insert((&vin.txid, vin.vout)))
+        {
+            return Ok(false);
+        }
+
         // Check if the previous transactions are correct
         for v in &self.vin {
             match prev_txs.get(&v.txid) {
@@ -233,6 +693,11 @@ impl Transaction {
             }
         }
 
+        // The outputs can't create more value than the inputs provide
+        if self.fee(&prev_txs)? < 0 {
+            return Ok(false);
+        }
+
         // Create a copy of the current transaction (clear the signature and public key)
         let mut tx_copy = self.trim_copy();
 
@@ -247,9 +712,7 @@ impl Transaction {
             tx_copy.vin[idx].signature.clear();
 
             // Set the public key hash of previous transaction as public key of copied transaction
-            tx_copy.vin[idx].pub_key = prev_tx.vout[self.vin[idx].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[idx].pub_key = prev_tx.output_at(&self.vin[idx])?.pub_key_hash.clone();
 
             // Hash the copied transaction
             tx_copy.id = tx_copy.hash()?;
@@ -282,7 +745,7 @@ impl Transaction {
         for v in &self.vin {
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
+                vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
             });
@@ -291,27 +754,82 @@ impl Transaction {
         // Return the copy
         Self {
             id: self.id.clone(),
+            version: self.version,
+            expiry_height: self.expiry_height,
+            time_lock: self.time_lock,
+            lock_time: self.lock_time,
             vin,
             vout: self.vout.clone(),
         }
     }
 }
 
+// OutPoint enum tells which output of the previous transaction an input spends
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutPoint {
+    Coinbase,   // The input of a coinbase transaction spends no output
+    Index(u32), // Index of the spent output in the previous transaction
+}
+
+impl OutPoint {
+    // Get the index of the spent output, None for a coinbase input
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            OutPoint::Coinbase => None,
+            OutPoint::Index(idx) => Some(*idx as usize),
+        }
+    }
+
+    // Check if the input is the input of a coinbase transaction
+    pub fn is_coinbase(&self) -> bool {
+        *self == OutPoint::Coinbase
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutPoint::Coinbase => write!(f, "coinbase"),
+            OutPoint::Index(idx) => write!(f, "{}", idx),
+        }
+    }
+}
+
 /// TXInput struct for transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub txid: String,       // Transaction id of the previous transaction
-    pub vout: i32,          // Index of the output of the previous transaction
+    pub vout: OutPoint,     // Output of the previous transaction spent by the input
     pub signature: Vec<u8>, // Signature of the transaction (signed by the sender)
     pub pub_key: Vec<u8>,   // Public key of the receiver
 }
 
 impl TXInput {
-    // Check if the public key hash of the input is equal to the public key hash of the sender
-    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
+    // Create the input of a coinbase transaction
+    // The height is kept in the signature field, which coinbase inputs don't use, so that
+    // the coinbase transactions of different blocks never share an id
+    // data: the data of the transaction
+    // height: the height of the block the transaction is mined in
+    pub fn coinbase(data: &str, height: usize) -> Self {
+        Self {
+            txid: String::new(),
+            vout: OutPoint::Coinbase,
+            signature: (height as u64).to_be_bytes().to_vec(),
+            pub_key: Vec::from(data.as_bytes()),
+        }
+    }
+
+    // Get the public key hash of the input
+    // Callers checking many keys against the same input should compute this once
+    pub fn pub_key_hash(&self) -> Vec<u8> {
         let mut pubkeyhash = self.pub_key.clone();
         hash_pub_key(&mut pubkeyhash);
-        pubkeyhash == pub_key_hash
+        pubkeyhash
+    }
+
+    // Check if the public key hash of the input is equal to the public key hash of the sender
+    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
+        self.pub_key_hash().as_slice() == pub_key_hash
     }
 }
 
@@ -339,15 +857,409 @@ impl TXOutput {
 
     // Check if the output is locked with the public key hash
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash == pub_key_hash
+        self.pub_key_hash.as_slice() == pub_key_hash
     }
 
     // Lock the output with the address of the receiver
     fn lock(&mut self, address: &str) -> Result<()> {
-        // Get the public key hash of the receiver from the address
-        let pub_key_hash = Address::decode(address).unwrap().body;
-        self.pub_key_hash = pub_key_hash;
+        // Get the public key hash of the receiver from the address, in either scheme
+        // A malformed address is reported to the caller instead of panicking
+        self.pub_key_hash = pub_key_hash_from_address(address)
+            .map_err(|_| BlockchainError::InvalidAddress(address.to_string()))?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        coinbase_reward, ChangePolicy, OutPoint, TXOutput, Transaction, DUST_THRESHOLD,
+        HALVING_INTERVAL, SUBSIDY,
+    };
+    use crate::errors::BlockchainError;
+    use crate::test_utils::TestChain;
+    use crate::utils::{address_from_pub_key_hash, pub_key_hash_from_address};
+    use crate::wallet::Wallets;
+    use bitcoincash_addr::Scheme;
+
+    #[test]
+    fn rejects_unknown_transaction_version() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let to = chain.address();
+
+        let tx = chain.send(&chain.miner, &to, 10);
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+
+        // A properly signed version 2 transaction is still unknown to this node
+        let mut tx_v2 = tx.clone();
+        tx_v2.version = 2;
+        tx_v2.id = tx_v2.hash().unwrap();
+        let wallet = chain.wallets.get_wallet(&chain.miner).unwrap();
+        chain.bc().sign_transaction(&mut tx_v2, wallet).unwrap();
+
+        assert!(!tx_v2.is_supported_version());
+        assert!(!chain.bc().verify_transaction(&tx_v2).unwrap());
+        assert!(!tx_v2
+            .verify(chain.bc().get_prev_txs(&tx_v2).unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn sends_change_to_a_fresh_spendable_address() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        let wallet = chain.wallets.get_wallet(&chain.miner).unwrap();
+        let tx = Transaction::new_utxo_with_change(
+            wallet,
+            &alice,
+            30,
+            &chain.utxo,
+            ChangePolicy::FreshAddress,
+        )
+        .unwrap();
+        assert_eq!(tx.vout.len(), 2);
+        let change = address_from_pub_key_hash(tx.vout[1].pub_key_hash.clone()).unwrap();
+        assert_ne!(change, chain.miner);
+        assert_ne!(change, alice);
+        let change_value = tx.vout[1].value;
+        chain.mine(vec![tx]);
+
+        // The change wallet was saved with the others and can spend the change
+        chain.wallets = Wallets::new_with_path(chain.dir.path()).unwrap();
+        assert!(chain.wallets.get_wallet(&change).is_some());
+        assert_eq!(chain.utxo.get_balance(&change).unwrap(), change_value);
+
+        let tx = chain.send(&change, &alice, 20);
+        chain.mine(vec![tx]);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 50);
+    }
+
+    #[test]
+    fn sums_the_outputs_paid_to_an_address() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let bob = chain.address();
+
+        let wallet = chain.wallets.get_wallet(&chain.miner).unwrap();
+        let recipients = [(alice.clone(), 10), (bob.clone(), 20), (alice.clone(), 5)];
+        let tx = Transaction::new_utxo_multi(wallet, &recipients, &chain.utxo).unwrap();
+
+        let hash = |address: &str| pub_key_hash_from_address(address).unwrap();
+        assert_eq!(tx.amount_to(&hash(&alice)), 15);
+        assert_eq!(tx.amount_to(&hash(&bob)), 20);
+        assert_eq!(tx.amount_to(&hash(&chain.address())), 0);
+    }
+
+    #[test]
+    fn refuses_to_sign_outputs_spent_after_selection() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        let miner_hash = pub_key_hash_from_address(&chain.miner).unwrap();
+        let (_, inputs) = chain.utxo.find_spendable_outputs(&miner_hash, 30).unwrap();
+
+        // A concurrent payment spends the selected output before signing
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+
+        let wallet = chain.wallets.get_wallet(&chain.miner).unwrap();
+        let vout = vec![TXOutput::new(30, alice.clone()).unwrap()];
+        let err = Transaction::new_signed(wallet, inputs, vout, &chain.utxo).unwrap_err();
+        assert!(err.to_string().contains("no longer unspent"), "{}", err);
+    }
+
+    #[test]
+    fn halves_the_reward_at_each_boundary() {
+        assert_eq!(SUBSIDY, 100);
+        let expected = [100, 50, 25, 12, 6, 3, 1, 0, 0];
+        for (halvings, reward) in expected.iter().enumerate() {
+            let start = halvings * HALVING_INTERVAL;
+            assert_eq!(coinbase_reward(start), *reward, "height {}", start);
+            assert_eq!(coinbase_reward(start + HALVING_INTERVAL - 1), *reward);
+        }
+
+        // Far past the last halving the reward stays at zero instead of overflowing
+        assert_eq!(coinbase_reward(HALVING_INTERVAL * 64), 0);
+        assert_eq!(coinbase_reward(usize::MAX), 0);
+
+        // The genesis block pays the height-0 reward
+        let chain = TestChain::new();
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        assert_eq!(
+            genesis.get_transactions()[0].vout[0].value,
+            coinbase_reward(0)
+        );
+    }
+
+    #[test]
+    fn rejects_outputs_whose_sum_overflows() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        // The values wrap around to 0 with plain additions, which would look like a large fee
+        let mut tx = chain.send(&chain.miner, &alice, 30);
+        let mut out = tx.vout[0].clone();
+        tx.vout.clear();
+        for value in [i32::MAX, i32::MAX, 2] {
+            out.value = value;
+            tx.vout.push(out.clone());
+        }
+        chain.resign(&chain.miner, &mut tx);
+
+        assert!(tx.output_value().is_err());
+        let prev_txs = chain.bc().get_prev_txs(&tx).unwrap();
+        assert!(tx.fee(&prev_txs).is_err());
+        assert!(!matches!(chain.bc().verify_transaction(&tx), Ok(true)));
+        assert!(chain
+            .bc()
+            .new_block_template(vec![chain.coinbase(&chain.miner), tx])
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_transactions_creating_value() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let genesis = chain.bc().get_block_by_height(0).unwrap();
+        let coinbase = &genesis.get_transactions()[0];
+        assert_eq!(coinbase.vout[0].value, 100);
+        let verify = |tx: &Transaction| tx.verify(chain.bc().get_prev_txs(tx).unwrap()).unwrap();
+
+        let valid = chain.spend(coinbase, 0, &chain.miner, &alice, 100);
+        assert!(verify(&valid));
+
+        // Two outputs of 100 out of an input of 100
+        let mut doubled = valid.clone();
+        doubled.vout.push(doubled.vout[0].clone());
+        chain.resign(&chain.miner, &mut doubled);
+        assert!(!verify(&doubled));
+
+        // The same input twice, covering the two outputs
+        let mut spent_twice = doubled.clone();
+        spent_twice.vin.push(spent_twice.vin[0].clone());
+        chain.resign(&chain.miner, &mut spent_twice);
+        assert!(!verify(&spent_twice));
+
+        // A negative output balancing a larger one
+        let mut negative = doubled;
+        negative.vout[0].value = 150;
+        negative.vout[1].value = -50;
+        chain.resign(&chain.miner, &mut negative);
+        assert!(!verify(&negative));
+    }
+
+    #[test]
+    fn tells_coinbase_inputs_apart_from_output_indices() {
+        assert_eq!(OutPoint::Coinbase.index(), None);
+        assert!(OutPoint::Coinbase.is_coinbase());
+        assert_eq!(OutPoint::Index(0).index(), Some(0));
+        assert!(!OutPoint::Index(0).is_coinbase());
+        assert_eq!(OutPoint::Index(u32::MAX).index(), Some(u32::MAX as usize));
+
+        // The encoding keeps the two apart, even for the index the old -1 marker would wrap to
+        for outpoint in [
+            OutPoint::Coinbase,
+            OutPoint::Index(0),
+            OutPoint::Index(u32::MAX),
+        ] {
+            let data = bincode::serialize(&outpoint).unwrap();
+            assert_eq!(bincode::deserialize::<OutPoint>(&data).unwrap(), outpoint);
+        }
+
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let coinbase = chain.coinbase(&alice);
+        assert!(coinbase.is_coinbase());
+        assert!(coinbase.vin[0].vout.is_coinbase());
+
+        let mut tx = chain.send(&chain.miner, &alice, 30);
+        assert!(!tx.is_coinbase());
+        let prev_txs = chain.bc().get_prev_txs(&tx).unwrap();
+        assert!(tx.fee(&prev_txs).is_ok());
+
+        // An index past the outputs of the previous transaction is an error, not a panic
+        tx.vin[0].vout = OutPoint::Index(u32::MAX);
+        assert!(tx.fee(&prev_txs).is_err());
+    }
+
+    #[test]
+    fn sends_change_to_a_fresh_address_of_encrypted_wallets() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        // Keep the miner wallet in an encrypted database of its own
+        let dir = tempfile::tempdir().unwrap();
+        let secret = chain
+            .wallets
+            .get_wallet(&chain.miner)
+            .unwrap()
+            .secret_key
+            .clone();
+        let mut wallets = Wallets::new_encrypted_with_path(dir.path(), "secret").unwrap();
+        wallets.import(&secret).unwrap();
+        wallets.save_all_encrypted("secret").unwrap();
+
+        let tx = Transaction::new_utxo_with_change_in(
+            &mut wallets,
+            &chain.miner,
+            &alice,
+            30,
+            &chain.utxo,
+            ChangePolicy::FreshAddress,
+        )
+        .unwrap();
+        let change = address_from_pub_key_hash(tx.vout[1].pub_key_hash.clone()).unwrap();
+
+        // The change wallet was saved encrypted with the same passphrase
+        assert!(Wallets::new_with_path(dir.path()).is_err());
+        let stored = Wallets::new_encrypted_with_path(dir.path(), "secret").unwrap();
+        assert!(stored.get_wallet(&change).is_some());
+        assert!(chain.wallets.get_wallet(&change).is_none());
+
+        // Unknown senders are reported instead of panicking
+        assert!(Transaction::new_utxo_with_change_in(
+            &mut wallets,
+            &alice,
+            &chain.miner,
+            10,
+            &chain.utxo,
+            ChangePolicy::SameAddress,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn pays_a_coinbase_to_an_address_without_a_local_wallet() {
+        let chain = TestChain::new();
+        let mut others = Wallets::new_with_path(tempfile::tempdir().unwrap().path()).unwrap();
+        let stranger = others.create_wallet();
+
+        let tx = Transaction::new_coinbase(stranger.clone(), String::new(), 1).unwrap();
+        assert!(tx.is_coinbase());
+        assert_eq!(tx.vout[0].value, coinbase_reward(1));
+        assert!(Transaction::new_coinbase("invalid".into(), String::new(), 1).is_err());
+
+        // Only new_coinbase_in asks for the wallet of the receiver
+        assert!(Transaction::new_coinbase_in(&chain.wallets, stranger, String::new(), 1).is_err());
+    }
+
+    #[test]
+    fn locks_outputs_to_the_same_key_in_either_address_scheme() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let wallet = chain.wallets.get_wallet(&alice).unwrap();
+        let cash_addr = wallet.get_address_with_scheme(Scheme::CashAddr);
+        assert_eq!(wallet.get_address_with_scheme(Scheme::Base58), alice);
+        assert_ne!(cash_addr, alice);
+
+        let from_base58 = TXOutput::new(10, alice.clone()).unwrap();
+        let from_cash_addr = TXOutput::new(10, cash_addr).unwrap();
+        assert_eq!(from_base58.pub_key_hash, from_cash_addr.pub_key_hash);
+        assert_eq!(
+            from_base58.pub_key_hash,
+            pub_key_hash_from_address(&alice).unwrap()
+        );
+    }
+
+    #[test]
+    fn reports_a_malformed_recipient_address() {
+        let err = TXOutput::new(10, "not-an-address".into()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(BlockchainError::InvalidAddress(address)) if address == "not-an-address"
+        ));
+    }
+
+    #[test]
+    fn builds_signs_and_verifies_through_one_code_path() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        // Empty coinbase data defaults to naming the receiver
+        let default = Transaction::new_coinbase(alice.clone(), String::new(), 1).unwrap();
+        let named = Transaction::new_coinbase(alice.clone(), format!("Reward to '{}'", alice), 1);
+        assert_eq!(default.id, named.unwrap().id);
+        let custom = Transaction::new_coinbase(alice.clone(), "data".into(), 1).unwrap();
+        assert_ne!(custom.id, default.id);
+
+        // A transaction signed by the wallet verifies through a shared reference
+        let wallet = chain.wallets.get_wallet(&chain.miner).unwrap();
+        let tx = Transaction::new_utxo(wallet, &alice, 30, &chain.utxo).unwrap();
+        let prev_txs = chain.bc().get_prev_txs(&tx).unwrap();
+        assert!(tx.verify(prev_txs.clone()).unwrap());
+
+        let mut tampered = tx.clone();
+        tampered.vout[0].value += 1;
+        assert!(!tampered.verify(prev_txs).unwrap());
+    }
+
+    #[test]
+    fn rejects_outputs_below_the_dust_threshold() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let wallet = chain.wallets.get_wallet(&chain.miner).unwrap();
+        let send = |amount| Transaction::new_utxo(wallet, &alice, amount, &chain.utxo);
+
+        // Payments exactly at the threshold pass, one below is refused
+        let err = send(DUST_THRESHOLD - 1).err().unwrap();
+        assert!(err.to_string().contains("dust threshold"), "{}", err);
+        let tx = send(DUST_THRESHOLD).unwrap();
+        assert_eq!(tx.vout[0].value, DUST_THRESHOLD);
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+
+        // Only the genesis reward is mature, change below the threshold becomes fee
+        let reward = coinbase_reward(0);
+        let tx = send(reward - DUST_THRESHOLD).unwrap();
+        assert_eq!(tx.vout.len(), 2);
+        assert_eq!(tx.vout[1].value, DUST_THRESHOLD);
+        let tx = send(reward - DUST_THRESHOLD + 1).unwrap();
+        assert_eq!(tx.vout.len(), 1);
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+
+        // A signed output below the threshold is refused by the chain
+        let mut dust = send(DUST_THRESHOLD).unwrap();
+        dust.vout[0].value = DUST_THRESHOLD - 1;
+        chain.resign(&chain.miner, &mut dust);
+        assert!(dust.has_dust(DUST_THRESHOLD));
+        assert!(!chain.bc().verify_transaction(&dust).unwrap());
+    }
+
+    #[test]
+    fn sends_the_whole_balance_without_change() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let bob = chain.address();
+        let alice = chain.address();
+
+        // Three outputs locked to bob
+        for amount in [10, 20, 30] {
+            let tx = chain.send(&chain.miner, &bob, amount);
+            chain.mine(vec![tx]);
+        }
+
+        let wallet = chain.wallets.get_wallet(&bob).unwrap();
+        let tx = Transaction::new_utxo_send_all(wallet, &alice, &chain.utxo).unwrap();
+        assert_eq!(tx.vin.len(), 3);
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.vout[0].value, 60);
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+
+        chain.mine(vec![tx]);
+        assert_eq!(chain.utxo.get_balance(&bob).unwrap(), 0);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 60);
+        let wallet = chain.wallets.get_wallet(&bob).unwrap();
+        assert!(Transaction::new_utxo_send_all(wallet, &alice, &chain.utxo).is_err());
+    }
+}
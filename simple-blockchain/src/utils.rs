@@ -1,15 +1,87 @@
+use crate::errors::{BlockchainError, Result};
+use anyhow::anyhow;
+use bitcoincash_addr::{Address, HashType, Scheme};
 use crypto::{digest::Digest, ripemd160::Ripemd160, sha2::Sha256};
 
 // Hashes a public key using SHA256 and RIPEMD160
+// The buffer is reused: it holds the 32-byte SHA256 digest, then the 20-byte RIPEMD160 digest.
+// Shrinking to 20 bytes happens after RIPEMD160 has consumed the SHA256 digest, so the
+// result is the standard HASH160 for the 32-byte ed25519 keys used by wallets.
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     // Hash the public key using SHA256
     let mut hasher1 = Sha256::new();
     hasher1.input(pub_key);
+
+    // The digest must fill the buffer exactly, whatever the length of the key
+    pub_key.resize(hasher1.output_bytes(), 0);
     hasher1.result(pub_key);
 
     // Hash the public key using RIPEMD160
     let mut hasher2 = Ripemd160::new();
     hasher2.input(pub_key);
-    pub_key.resize(20, 0);
+    pub_key.resize(hasher2.output_bytes(), 0);
     hasher2.result(pub_key);
 }
+
+// Encodes a public key hash as a base58 address
+pub fn address_from_pub_key_hash(pub_key_hash: Vec<u8>) -> Result<String> {
+    address_from_pub_key_hash_with_scheme(pub_key_hash, Scheme::Base58)
+}
+
+// Encodes a public key hash as an address of the given scheme
+pub fn address_from_pub_key_hash_with_scheme(
+    pub_key_hash: Vec<u8>,
+    scheme: Scheme,
+) -> Result<String> {
+    let address = Address {
+        body: pub_key_hash,
+        scheme,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+
+    address
+        .encode()
+        .map_err(|e| anyhow!("failed to encode address: {:?}", e))
+}
+
+// Decodes the public key hash of an address in either the Base58 or the CashAddr scheme
+pub fn pub_key_hash_from_address(address: &str) -> Result<Vec<u8>> {
+    Address::decode(address)
+        .map(|address| address.body)
+        .map_err(|_| BlockchainError::InvalidAddress(address.to_string()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{address_from_pub_key_hash, hash_pub_key, pub_key_hash_from_address};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hashes_public_keys_to_pinned_hash160_vectors() {
+        // RIPEMD160(SHA256(key)) computed independently of this crate
+        let vectors: [(Vec<u8>, &str); 3] = [
+            (
+                (0..32).collect(),
+                "ea4beb47def8492389a1e16634795441e1b87245",
+            ),
+            (
+                [2].into_iter().chain(0..32).collect(),
+                "4ee1a90caa1874aa7dd2e0013a6da5cd7844aec5",
+            ),
+            (Vec::new(), "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb"),
+        ];
+
+        for (key, expected) in vectors {
+            let mut hash = key;
+            hash_pub_key(&mut hash);
+            assert_eq!(hex(&hash), expected);
+
+            let address = address_from_pub_key_hash(hash.clone()).unwrap();
+            assert_eq!(pub_key_hash_from_address(&address).unwrap(), hash);
+        }
+    }
+}
@@ -1,35 +1,311 @@
-use crate::block::Block;
+use crate::block::{Block, BlockHeader};
 use crate::blockchain::Blockchain;
 use crate::errors::Result;
-use crate::transaction::TXOutputs;
+use crate::transaction::{coinbase_reward, OutPoint, TXOutput, TXOutputs};
+use crate::utils::{hash_pub_key, pub_key_hash_from_address};
+use crate::wallet::Wallet;
+use anyhow::anyhow;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use log::info;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+const UTXO_CACHE_CAPACITY: usize = 1024; // default number of cached transaction outputs
+
+// CoinSelection enum decides the order in which unspent outputs are spent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoinSelection {
+    Arbitrary,     // Spend the outputs in the order of the UTXO set database
+    LargestFirst,  // Spend the largest outputs first, minimizing the number of inputs
+    SmallestFirst, // Spend the smallest outputs first, consolidating dust
+    All,           // Spend every spendable output whatever the amount, emptying the address
+}
+
+// DiscoveredFunds struct holds the unspent outputs of a wallet found in the UTXO set
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredFunds {
+    pub address: String,               // Address of the wallet
+    pub balance: i32,                  // Total value of the unspent outputs
+    pub outpoints: Vec<(String, u32)>, // Transaction ID and output index of each unspent output
+}
+
+// UTXOSnapshot struct holds the whole UTXO set at a block, e.g. for a node to fast sync
+// It is only adopted if it matches the UTXO set commitment of that block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UTXOSnapshot {
+    pub tip_hash: String, // Hash of the block the UTXO set was taken at
+    pub entries: Vec<(String, TXOutputs)>, // Transaction outputs of the UTXO set in txid order
+}
+
+impl UTXOSnapshot {
+    // Check the entries against the UTXO set commitment of the block the snapshot was taken at
+    // Return the entries keyed by txid, ready to be written
+    // header: the header of the block the snapshot claims to be taken at
+    pub fn verify(&self, header: &BlockHeader) -> Result<BTreeMap<String, TXOutputs>> {
+        if header.get_hash() != self.tip_hash {
+            return Err(anyhow!(
+                "UTXO snapshot was taken at {}, not at {}",
+                self.tip_hash,
+                header.get_hash()
+            ));
+        }
+        if header.get_utxo_root().is_empty() {
+            return Err(anyhow!(
+                "Block {} doesn't commit to its UTXO set",
+                self.tip_hash
+            ));
+        }
+
+        // A txid listed twice would hide one of its entries from the commitment
+        let entries: BTreeMap<String, TXOutputs> = self.entries.iter().cloned().collect();
+        if entries.len() != self.entries.len() {
+            return Err(anyhow!("UTXO snapshot lists a transaction twice"));
+        }
+        if utxo_commitment(&entries)? != header.get_utxo_root() {
+            return Err(anyhow!(
+                "UTXO snapshot doesn't match the commitment of block {}",
+                self.tip_hash
+            ));
+        }
+
+        Ok(entries)
+    }
+}
 
 /// UTXOSet struct contains a Blockchain
 pub struct UTXOSet {
     pub blockchain: Blockchain,
+    db: sled::Db,            // UTXO set database, opened once by the blockchain
+    cache: Mutex<UTXOCache>, // cache of recently accessed transaction outputs
+}
+
+// UTXOCache struct is a LRU cache of txid -> transaction outputs in front of the UTXO set database
+// The entries are kept in a doubly linked list over a slab, so every operation takes constant time
+struct UTXOCache {
+    capacity: usize,
+    slots: HashMap<String, usize>, // txid -> index of its entry in entries
+    entries: Vec<CacheEntry>,
+    free: Vec<usize>,      // indexes of the unused entries
+    oldest: Option<usize>, // least recently used entry
+    newest: Option<usize>, // most recently used entry
+}
+
+// CacheEntry struct is a node of the recency list of the UTXO cache
+struct CacheEntry {
+    txid: String,
+    outs: TXOutputs,
+    prev: Option<usize>, // the entry used just before, towards oldest
+    next: Option<usize>, // the entry used just after, towards newest
+}
+
+impl UTXOCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            slots: HashMap::new(),
+            entries: Vec::new(),
+            free: Vec::new(),
+            oldest: None,
+            newest: None,
+        }
+    }
+
+    // Get the cached outputs and mark them as the most recently used
+    fn get(&mut self, txid: &str) -> Option<TXOutputs> {
+        let idx = *self.slots.get(txid)?;
+        self.unlink(idx);
+        self.push_newest(idx);
+        Some(self.entries[idx].outs.clone())
+    }
+
+    // Cache the outputs, evicting the least recently used entry if the cache is full
+    fn insert(&mut self, txid: &str, outs: TXOutputs) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(&idx) = self.slots.get(txid) {
+            self.entries[idx].outs = outs;
+            self.unlink(idx);
+            self.push_newest(idx);
+            return;
+        }
+
+        if self.slots.len() >= self.capacity {
+            if let Some(oldest) = self.oldest {
+                let evicted = self.entries[oldest].txid.clone();
+                self.remove(&evicted);
+            }
+        }
+
+        let entry = CacheEntry {
+            txid: txid.to_string(),
+            outs,
+            prev: None,
+            next: None,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.entries[idx] = entry;
+                idx
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        self.slots.insert(txid.to_string(), idx);
+        self.push_newest(idx);
+    }
+
+    // Remove the outputs from the cache
+    fn remove(&mut self, txid: &str) {
+        if let Some(idx) = self.slots.remove(txid) {
+            self.unlink(idx);
+            self.free.push(idx);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.entries.clear();
+        self.free.clear();
+        self.oldest = None;
+        self.newest = None;
+    }
+
+    // Take the entry out of the recency list
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = (self.entries[idx].prev, self.entries[idx].next);
+        match prev {
+            Some(p) => self.entries[p].next = next,
+            None => self.oldest = next,
+        }
+        match next {
+            Some(n) => self.entries[n].prev = prev,
+            None => self.newest = prev,
+        }
+        self.entries[idx].prev = None;
+        self.entries[idx].next = None;
+    }
+
+    // Put the entry at the most recently used end of the recency list
+    fn push_newest(&mut self, idx: usize) {
+        self.entries[idx].prev = self.newest;
+        match self.newest {
+            Some(n) => self.entries[n].next = Some(idx),
+            None => self.oldest = Some(idx),
+        }
+        self.newest = Some(idx);
+    }
 }
 
 impl UTXOSet {
+    // Create a UTXO set for the blockchain
+    // If the UTXO set database is missing or empty, it is rebuilt from the blockchain
+    pub fn new(blockchain: Blockchain) -> Result<Self> {
+        Self::with_cache_capacity(blockchain, UTXO_CACHE_CAPACITY)
+    }
+
+    // Create a UTXO set for the blockchain with the given number of cached transaction outputs
+    // blockchain: the blockchain the UTXO set is built from
+    // capacity: the maximum number of cached entries (0 disables the cache)
+    pub fn with_cache_capacity(blockchain: Blockchain, capacity: usize) -> Result<Self> {
+        let utxo_set = Self {
+            db: blockchain.utxos_db().clone(),
+            blockchain,
+            cache: Mutex::new(UTXOCache::new(capacity)),
+        };
+
+        // Rebuild the UTXO set if the blockchain has blocks but the UTXO set is empty
+        if utxo_set.db.is_empty() && utxo_set.blockchain.iter().next().is_some() {
+            info!("UTXO set is missing, reindexing from the blockchain");
+            utxo_set.reindex()?;
+        }
+
+        Ok(utxo_set)
+    }
+
+    // Get the unspent outputs of a transaction
+    // txid: the ID of the transaction
+    // The cache is checked before the database
+    pub fn get_outputs(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        if let Some(outs) = self.cache.lock().unwrap().get(txid) {
+            return Ok(Some(outs));
+        }
+
+        let outs: TXOutputs = match self.db.get(txid)? {
+            Some(v) => bincode::deserialize(&v)?,
+            None => return Ok(None),
+        };
+
+        // Cache the outputs for the next access
+        self.cache.lock().unwrap().insert(txid, outs.clone());
+
+        Ok(Some(outs))
+    }
+
+    // Check if the output is still in the UTXO set
+    // txid: the ID of the transaction
+    // vout: the index of the output
+    pub fn has_output(&self, txid: &str, vout: OutPoint) -> Result<bool> {
+        let outs = match self.get_outputs(txid)? {
+            Some(outs) => outs,
+            None => return Ok(false),
+        };
+
+        match vout.index() {
+            Some(idx) => Ok(outs.contains(idx as u32)),
+            None => Ok(false),
+        }
+    }
+
+    // Drop all cached outputs, e.g. after the UTXO set was rebuilt by a reorganization
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
     // Rebuild the UTXO set from blockchain
     pub fn reindex(&self) -> Result<()> {
-        // Remove old UTXO set if it exists
-        if let Err(e) = std::fs::remove_dir_all("data/utxos") {
-            info!("remove_dir_all error: {}", e);
-        }
+        // Drop all cached outputs, they may be stale
+        self.clear_cache();
+
+        // Replay the blockchain to rebuild the UTXO set
+        self.blockchain.replay()
+    }
 
-        // Create a new UTXO set
-        let db = sled::open("data/utxos")?;
+    // Get the balance of an address, the total value of its unspent outputs
+    // An address without outputs has a balance of 0
+    // address: the Base58 or CashAddr address to get the balance of
+    pub fn get_balance(&self, address: &str) -> Result<i32> {
+        let pub_key_hash = pub_key_hash_from_address(address)?;
+        let utxos = self.find_utxo(&pub_key_hash)?;
 
-        // Find all unspent transaction outputs and add them to UTXO set
-        let utxos = self.blockchain.find_utxo();
+        Ok(utxos.outputs.iter().map(|out| out.value).sum())
+    }
+
+    // Find the unspent outputs of a wallet, e.g. right after importing its secret key
+    // wallet: the wallet to find the funds of
+    pub fn discover_funds(&self, wallet: &Wallet) -> Result<DiscoveredFunds> {
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
 
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
+        let mut funds = DiscoveredFunds {
+            address: wallet.get_address(),
+            balance: 0,
+            outpoints: Vec::new(),
+        };
+        for (txid, idx, out) in self.scan_outputs(&pub_key_hash)? {
+            funds.balance += out.value;
+            funds.outpoints.push((txid, idx));
         }
 
-        Ok(())
+        Ok(funds)
     }
+
     // Find all unspent transaction outputs and return transactions with spent outputs removed
     // address: the address to find unspent transaction outputs for
     // amount: the amount needed
@@ -37,46 +313,69 @@ impl UTXOSet {
         &self,
         address: &[u8],
         amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        // Declare a HashMap to store unspent outputs
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+    ) -> Result<(i32, HashMap<String, Vec<u32>>)> {
+        self.find_spendable_outputs_with(address, amount, CoinSelection::Arbitrary)
+    }
 
-        // Declare a variable to store accumulated amount of unspent outputs
-        let mut accumulated = 0;
+    // Find unspent transaction outputs covering the amount, selected with the given strategy
+    // address: the address to find unspent transaction outputs for
+    // amount: the amount needed, ignored when every output is selected
+    // strategy: the order in which the outputs are selected
+    pub fn find_spendable_outputs_with(
+        &self,
+        address: &[u8],
+        amount: i32,
+        strategy: CoinSelection,
+    ) -> Result<(i32, HashMap<String, Vec<u32>>)> {
+        // Declare a vector to store the candidate outputs (txid, output index, value)
+        let mut candidates: Vec<(String, u32, i32)> = Vec::new();
 
-        // Open the UTXO set database
-        let db = sled::open("data/utxos")?;
+        // Coinbase outputs only become spendable once they are mature
+        let best_height = self.blockchain.get_best_height()?;
 
         // Iterate over all unspent transaction outputs
-        'out: for kv in db.iter() {
+        for kv in self.db.iter() {
             let (k, v) = kv?;
 
             // Parse transaction ID and its outputs
             let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-
-            for idx in 0..outs.outputs.len() {
-                // Check if output is locked with given address and if so, add it to unspent outputs
-                if outs.outputs[idx].is_locked_with_key(address) && accumulated < amount {
-                    accumulated += outs.outputs[idx].value;
-
-                    // Add transaction ID and output index to unspent outputs HashMap
-                    // If transaction ID already exists in HashMap, push output index to its vector
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![idx as i32]);
-                        }
-                    }
-                }
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            if !outs.is_mature(best_height) {
+                continue;
+            }
 
-                // Check if accumulated amount is enough and if so, break out of loop
-                if accumulated >= amount {
-                    break 'out;
+            // Keep the outputs locked with given address
+            for (idx, out) in outs.indexed() {
+                if out.is_locked_with_key(address) {
+                    candidates.push((txid.clone(), idx, out.value));
                 }
             }
         }
 
+        // Order the candidates according to the strategy, the database order is kept otherwise
+        match strategy {
+            CoinSelection::Arbitrary | CoinSelection::All => {}
+            CoinSelection::LargestFirst => candidates.sort_by_key(|c| Reverse(c.2)),
+            CoinSelection::SmallestFirst => candidates.sort_by_key(|c| c.2),
+        }
+
+        // Declare a HashMap to store unspent outputs
+        let mut unspent_outputs: HashMap<String, Vec<u32>> = HashMap::new();
+
+        // Declare a variable to store accumulated amount of unspent outputs
+        let mut accumulated = 0;
+
+        for (txid, idx, value) in candidates {
+            // Check if accumulated amount is enough and if so, stop selecting
+            if strategy != CoinSelection::All && accumulated >= amount {
+                break;
+            }
+            accumulated += value;
+
+            // Add transaction ID and output index to unspent outputs HashMap
+            unspent_outputs.entry(txid).or_default().push(idx);
+        }
+
         // Return accumulated amount and unspent outputs
         Ok((accumulated, unspent_outputs))
     }
@@ -85,95 +384,134 @@ impl UTXOSet {
     // pub_key_hash: the public key hash to find unspent transaction outputs for
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
         // Declare a TXOutputs struct to store unspent outputs
+        // The outputs come from many transactions, so the origin fields are left unset
         let mut utxos = TXOutputs {
             outputs: Vec::new(),
+            indices: Vec::new(),
+            height: 0,
+            coinbase: false,
         };
 
-        // Open the UTXO set database
-        let db = sled::open("data/utxos")?;
+        for (_, idx, out) in self.scan_outputs(pub_key_hash)? {
+            utxos.outputs.push(out);
+            utxos.indices.push(idx);
+        }
+
+        // Return unspent outputs
+        Ok(utxos)
+    }
 
-        for kv in db.iter() {
-            let (_, v) = kv?;
+    // Scan the UTXO set for the unspent outputs locked with a public key hash
+    // Return the transaction ID and the index of each output along with the output
+    // pub_key_hash: the public key hash the outputs are locked with
+    fn scan_outputs(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, u32, TXOutput)>> {
+        let mut found = Vec::new();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
 
-            // Parse transaction outputs
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
+            // Parse transaction ID and its outputs
+            let txid = String::from_utf8(k.to_vec())?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
 
-            // Iterate over transaction outputs and check if they are locked with given public key hash
-            for out in outs.outputs {
+            // Keep the outputs locked with given public key hash
+            for (idx, out) in outs.indexed() {
                 if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+                    found.push((txid.clone(), idx, out.clone()));
                 }
             }
         }
 
-        // Return unspent outputs
-        Ok(utxos)
+        Ok(found)
     }
 
     // Update the UTXO set with transactions from the Block
+    // Every input is checked before anything is written, then the changes are applied
+    // in one batch, so a rejected block leaves the UTXO set untouched
     // block: the Block to update the UTXO set with
-    // TODO - improve this function
     pub fn update(&self, block: &Block) -> Result<()> {
-        // Open the UTXO set database
-        let db = sled::open("data/utxos")?;
-
-        for tx in block.get_transactions() {
-            // If transaction is not a coinbase transaction, iterate over its inputs and remove them from UTXO set
-            if !tx.is_coinbase() {
-                // Iterate over transaction inputs
-                for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-
-                    // Get transaction outputs for transaction ID
-                    let outs: TXOutputs =
-                        bincode::deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-
-                    // Iterate over transaction outputs and add them to update_outputs except for the one that is being spent
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
-
-                    // If there are no more outputs for the transaction ID, remove it from UTXO set
-                    // Otherwise, update it with the new outputs
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), bincode::serialize(&update_outputs)?)?;
-                    }
-                }
-            }
+        let changes = connect_block(block, |txid| self.get_outputs(txid))?;
 
-            // Declare a new TXOutputs struct to store transaction outputs
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-
-            // Iterate over transaction outputs and add them to new_outputs
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+        let mut batch = sled::Batch::default();
+        for (txid, outs) in &changes {
+            match outs {
+                Some(outs) => batch.insert(txid.as_bytes(), bincode::serialize(outs)?),
+                None => batch.remove(txid.as_bytes()),
             }
+        }
+        self.db.apply_batch(batch)?;
 
-            // Add transaction ID and new_outputs to UTXO set
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
+        // Invalidate the cached outputs, they changed
+        let mut cache = self.cache.lock().unwrap();
+        for txid in changes.keys() {
+            cache.remove(txid);
         }
+        drop(cache);
 
         // Return Ok
         Ok(())
     }
 
+    // Take a snapshot of the UTXO set at the tip of the chain, e.g. for a peer to fast sync
+    pub fn snapshot(&self) -> Result<UTXOSnapshot> {
+        let mut entries = Vec::new();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            entries.push((String::from_utf8(k.to_vec())?, bincode::deserialize(&v)?));
+        }
+
+        Ok(UTXOSnapshot {
+            tip_hash: self.blockchain.get_tip_hash(),
+            entries,
+        })
+    }
+
+    // Replace the UTXO set with a snapshot taken at the tip of the chain
+    // The snapshot must match the UTXO set commitment of the tip, which the proof of work covers,
+    // otherwise the UTXO set is left untouched
+    // snapshot: the snapshot to adopt
+    pub fn adopt_snapshot(&self, snapshot: &UTXOSnapshot) -> Result<()> {
+        let tip = self
+            .blockchain
+            .get_block_header(&self.blockchain.get_tip_hash())?;
+        let entries = snapshot.verify(&tip)?;
+
+        self.db.clear()?;
+        let mut batch = sled::Batch::default();
+        for (txid, outs) in &entries {
+            batch.insert(txid.as_bytes(), bincode::serialize(outs)?);
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+
+        // Drop all cached outputs, they belong to the replaced UTXO set
+        self.clear_cache();
+
+        Ok(())
+    }
+
+    // Fast sync the chain: store the blocks without applying their transactions one by one and
+    // adopt the UTXO set of the last one from a snapshot
+    // The snapshot is checked against the last block before anything is stored, the blocks are
+    // checked against the consensus rules as they are imported
+    // blocks: the blocks extending the tip, from the oldest to the newest
+    // snapshot: the UTXO set at the last block
+    pub fn fast_sync(&mut self, blocks: Vec<Block>, snapshot: &UTXOSnapshot) -> Result<()> {
+        let last = match blocks.last() {
+            Some(block) => block.get_header(),
+            None => return Err(anyhow!("No block to fast sync")),
+        };
+        snapshot.verify(&last)?;
+
+        self.blockchain.import_without_utxos(blocks)?;
+        self.adopt_snapshot(snapshot)
+    }
+
     // Count the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
 
-        // Open the UTXO set database
-        let db = sled::open("data/utxos")?;
-
         // Iterate over all transactions in UTXO set
-        for kv in db.iter() {
+        for kv in self.db.iter() {
             kv?;
             counter += 1;
         }
@@ -182,3 +520,650 @@ impl UTXOSet {
         Ok(counter)
     }
 }
+
+// Compute the commitment to a UTXO set, the hash of its non-empty entries in txid order
+// Blocks commit to the UTXO set they leave, so a snapshot of it can be checked against a block
+// entries: the transaction outputs of the UTXO set, keyed by txid
+pub fn utxo_commitment(entries: &BTreeMap<String, TXOutputs>) -> Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    for (txid, outs) in entries {
+        if !outs.outputs.is_empty() {
+            hasher.input(&bincode::serialize(&(txid, outs))?);
+        }
+    }
+
+    let mut res = [0; 32];
+    hasher.result(&mut res);
+    Ok(res.to_vec())
+}
+
+// Compute the commitment to a UTXO set database once the changes of a block are applied
+// db: the UTXO set database before the block
+// changes: the changes the block makes, as returned by connect_block
+pub(crate) fn utxo_commitment_after(
+    db: &sled::Db,
+    changes: &HashMap<String, Option<TXOutputs>>,
+) -> Result<Vec<u8>> {
+    let mut entries = BTreeMap::new();
+    for kv in db.iter() {
+        let (k, v) = kv?;
+        entries.insert(String::from_utf8(k.to_vec())?, bincode::deserialize(&v)?);
+    }
+    for (txid, outs) in changes {
+        match outs {
+            Some(outs) => entries.insert(txid.clone(), outs.clone()),
+            None => entries.remove(txid),
+        };
+    }
+
+    utxo_commitment(&entries)
+}
+
+// Compute the changes a block makes to the UTXO set without writing them
+// Return the new outputs of every transaction the block touches, None for the fully spent ones
+// The block must start with its only coinbase transaction, every input must spend an unspent
+// output, created before or earlier in the same block, no transaction may replace the different
+// unspent outputs of another with the same ID, and the block must conserve value: no output is
+// negative, no transaction creates more than it spends and the coinbase mints at most the block
+// reward plus the fees
+// block: the block to connect
+// lookup: reads the unspent outputs of a transaction before the block
+pub(crate) fn connect_block<F>(
+    block: &Block,
+    lookup: F,
+) -> Result<HashMap<String, Option<TXOutputs>>>
+where
+    F: Fn(&str) -> Result<Option<TXOutputs>>,
+{
+    block.coinbase()?;
+
+    let mut changes: HashMap<String, Option<TXOutputs>> = HashMap::new();
+    let mut fees: i64 = 0;
+
+    for tx in block.get_transactions() {
+        // A negative output would cancel out part of another one in the sums below
+        if let Some(out) = tx.vout.iter().find(|out| out.value < 0) {
+            return Err(anyhow!(
+                "transaction {} has a negative output: {}",
+                tx.id,
+                out.value
+            ));
+        }
+
+        // Remove the outputs spent by the inputs of the transaction
+        let mut input_value: i64 = 0;
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                // Outputs created or spent earlier in the block are read from the changes
+                let outs = match changes.get(&vin.txid) {
+                    Some(outs) => outs.clone(),
+                    None => lookup(&vin.txid)?,
+                };
+                let mut outs = match outs {
+                    Some(outs) => outs,
+                    None => return Err(anyhow!("UTXO not found: {}", vin.txid)),
+                };
+
+                // Remove the spent output, it must not have been spent before
+                let spent = vin.vout.index().and_then(|idx| outs.spend(idx as u32));
+                match spent {
+                    Some(out) => input_value += out.value as i64,
+                    None => return Err(anyhow!("UTXO not found: {}:{}", vin.txid, vin.vout)),
+                }
+
+                // Fully spent transactions are removed from the UTXO set
+                let outs = if outs.outputs.is_empty() {
+                    None
+                } else {
+                    Some(outs)
+                };
+                changes.insert(vin.txid.clone(), outs);
+            }
+        }
+
+        // Transactions can't create more value than they spend, the difference is their fee
+        let output_value: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+        if !tx.is_coinbase() {
+            if output_value > input_value {
+                return Err(anyhow!(
+                    "transaction {} creates more value than it spends: {} > {}",
+                    tx.id,
+                    output_value,
+                    input_value
+                ));
+            }
+            fees += input_value - output_value;
+        }
+
+        // Never overwrite unspent outputs of a different transaction with the same ID
+        let new_outputs = TXOutputs::from_transaction(tx, block.get_height());
+        let data = bincode::serialize(&new_outputs)?;
+        let existing = match changes.get(&tx.id) {
+            Some(outs) => outs.clone(),
+            None => lookup(&tx.id)?,
+        };
+        if let Some(existing) = existing {
+            if bincode::serialize(&existing)? != data {
+                return Err(anyhow!(
+                    "UTXO set already has different outputs for txid: {}",
+                    tx.id
+                ));
+            }
+        }
+        changes.insert(tx.id.clone(), Some(new_outputs));
+    }
+
+    // The coinbase can only collect the reward of the block and the fees it includes
+    let minted: i64 = block
+        .coinbase()?
+        .vout
+        .iter()
+        .map(|out| out.value as i64)
+        .sum();
+    let allowed = coinbase_reward(block.get_height() as usize) as i64 + fees;
+    if minted > allowed {
+        return Err(anyhow!(
+            "block {} inflates the UTXO set: the coinbase mints {} > {} (reward and fees)",
+            block.get_hash(),
+            minted,
+            allowed
+        ));
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoinSelection, UTXOCache, UTXOSet, UTXOSnapshot};
+    use crate::block::Block;
+    use crate::blockchain::Blockchain;
+    use crate::errors::BlockchainError;
+    use crate::params::ChainParams;
+    use crate::test_utils::TestChain;
+    use crate::transaction::{OutPoint, TXOutputs, Transaction, COINBASE_MATURITY};
+    use crate::utils::{hash_pub_key, pub_key_hash_from_address};
+    use crate::wallet::Wallets;
+
+    #[test]
+    fn scans_match_outputs_by_public_key_hash() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let bob = chain.address();
+
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+        let tx = chain.send(&chain.miner, &bob, 20);
+        chain.mine(vec![tx]);
+
+        let alice_hash = pub_key_hash_from_address(&alice).unwrap();
+        let utxos = chain.utxo.find_utxo(&alice_hash).unwrap();
+        assert_eq!(utxos.outputs.len(), 1);
+        assert_eq!(utxos.outputs[0].value, 30);
+        assert!(utxos.outputs[0].is_locked_with_key(&alice_hash));
+
+        let (total, selected) = chain.utxo.find_spendable_outputs(&alice_hash, 10).unwrap();
+        assert_eq!(total, 30);
+        assert_eq!(selected.values().map(Vec::len).sum::<usize>(), 1);
+        assert_eq!(chain.utxo.get_balance(&bob).unwrap(), 20);
+
+        // The input of the payment to bob was signed by the miner's key
+        let miner = chain.wallets.get_wallet(&chain.miner).unwrap();
+        let mut miner_hash = miner.public_key.clone();
+        hash_pub_key(&mut miner_hash);
+        assert!(tx_input_uses(&chain, &bob, &miner_hash));
+        assert!(!tx_input_uses(&chain, &bob, &alice_hash));
+    }
+
+    // Check if the inputs of the transaction paying the address were signed by the key
+    fn tx_input_uses(chain: &TestChain, to: &str, pub_key_hash: &[u8]) -> bool {
+        let to_hash = pub_key_hash_from_address(to).unwrap();
+        let block = chain.bc().iter().next().unwrap();
+        let tx = block
+            .get_transactions()
+            .iter()
+            .find(|tx| tx.amount_to(&to_hash) > 0)
+            .unwrap();
+        tx.vin.iter().all(|vin| vin.uses_key(pub_key_hash))
+    }
+
+    #[test]
+    fn reindexes_a_missing_utxo_set_on_open() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+
+        let miner_balance = chain.utxo.get_balance(&chain.miner).unwrap();
+        let TestChain {
+            dir, utxo, miner, ..
+        } = chain;
+        drop(utxo);
+
+        // The blocks are kept but the UTXO set is lost
+        std::fs::remove_dir_all(dir.path().join("utxos")).unwrap();
+        let bc = Blockchain::open(dir.path(), ChainParams::devnet()).unwrap();
+        let utxo = UTXOSet::new(bc).unwrap();
+
+        assert_eq!(utxo.get_balance(&miner).unwrap(), miner_balance);
+        assert_eq!(utxo.get_balance(&alice).unwrap(), 30);
+    }
+
+    // Outputs told apart by their height only
+    fn outputs_at(height: u32) -> TXOutputs {
+        TXOutputs {
+            outputs: Vec::new(),
+            indices: Vec::new(),
+            height,
+            coinbase: false,
+        }
+    }
+
+    fn cached_height(cache: &mut UTXOCache, txid: &str) -> Option<u32> {
+        cache.get(txid).map(|outs| outs.height)
+    }
+
+    #[test]
+    fn cache_evicts_the_least_recently_used_entry() {
+        let mut cache = UTXOCache::new(2);
+        cache.insert("a", outputs_at(1));
+        cache.insert("b", outputs_at(2));
+        assert_eq!(cached_height(&mut cache, "a"), Some(1));
+
+        // b is now the least recently used
+        cache.insert("c", outputs_at(3));
+        assert_eq!(cached_height(&mut cache, "b"), None);
+        assert_eq!(cached_height(&mut cache, "a"), Some(1));
+        assert_eq!(cached_height(&mut cache, "c"), Some(3));
+
+        // Replacing an entry refreshes it, removing one frees its slot
+        cache.insert("a", outputs_at(4));
+        cache.remove("c");
+        cache.insert("d", outputs_at(5));
+        assert_eq!(cached_height(&mut cache, "a"), Some(4));
+        assert_eq!(cached_height(&mut cache, "d"), Some(5));
+        assert_eq!(cache.entries.len(), 2);
+
+        let mut disabled = UTXOCache::new(0);
+        disabled.insert("a", outputs_at(1));
+        assert_eq!(cached_height(&mut disabled, "a"), None);
+    }
+
+    #[test]
+    fn cache_matches_the_database_and_follows_updates() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let funding = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![funding.clone()]);
+
+        // The first read fills the cache, the second is served by it
+        let stored = chain.utxo.db.get(&funding.id).unwrap().unwrap();
+        for _ in 0..2 {
+            let outs = chain.utxo.get_outputs(&funding.id).unwrap().unwrap();
+            assert_eq!(bincode::serialize(&outs).unwrap(), stored.to_vec());
+        }
+        assert!(chain.utxo.cache.lock().unwrap().get(&funding.id).is_some());
+        assert!(chain
+            .utxo
+            .has_output(&funding.id, OutPoint::Index(0))
+            .unwrap());
+
+        // Spending the output replaces the cached entry
+        let tx = chain.spend(&funding, 0, &alice, &alice, 25);
+        chain.mine(vec![tx]);
+        assert!(!chain
+            .utxo
+            .has_output(&funding.id, OutPoint::Index(0))
+            .unwrap());
+        let outs = chain.utxo.get_outputs(&funding.id).unwrap();
+        let stored = chain.utxo.db.get(&funding.id).unwrap();
+        assert_eq!(
+            outs.map(|outs| bincode::serialize(&outs).unwrap()),
+            stored.map(|v| v.to_vec())
+        );
+    }
+
+    #[test]
+    fn rejects_txid_collisions_without_partial_writes() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let funding = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![funding.clone()]);
+
+        let tx = chain.spend(&funding, 0, &alice, &alice, 25);
+        let coinbase = chain.coinbase(&chain.miner);
+        let block = chain
+            .utxo
+            .blockchain
+            .mine_block(vec![coinbase, tx.clone()])
+            .unwrap();
+
+        // Another transaction's unspent outputs are already stored under the same ID
+        let other = TXOutputs::from_transaction(&funding, 1);
+        let other = bincode::serialize(&other).unwrap();
+        chain
+            .utxo
+            .db
+            .insert(tx.id.as_bytes(), other.clone())
+            .unwrap();
+
+        let err = chain.utxo.update(&block).unwrap_err();
+        assert!(err.to_string().contains("different outputs"), "{}", err);
+
+        // Nothing of the block was applied
+        assert!(chain
+            .utxo
+            .has_output(&funding.id, OutPoint::Index(0))
+            .unwrap());
+        assert!(chain
+            .utxo
+            .get_outputs(&block.get_transactions()[0].id)
+            .unwrap()
+            .is_none());
+        assert_eq!(chain.utxo.db.get(&tx.id).unwrap().unwrap().to_vec(), other);
+    }
+
+    // Build a block on the tip of the chain without checking its transactions
+    fn unchecked_block(chain: &TestChain, txs: Vec<crate::transaction::Transaction>) -> Block {
+        let bc = chain.bc();
+        Block::new_block(
+            txs,
+            bc.get_tip_hash(),
+            bc.get_best_height().unwrap() + 1,
+            bc.get_params().difficulty,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_blocks_conserving_value() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        // The coinbase collects the reward and the fee of the payment
+        let funding = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![funding.clone()]);
+        let tx = chain.spend(&funding, 0, &alice, &alice, 25);
+        let block = chain.mine(vec![tx]);
+
+        let coinbase = &block.get_transactions()[0];
+        let reward = crate::transaction::coinbase_reward(block.get_height() as usize);
+        assert_eq!(coinbase.vout[0].value, reward + 5);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 25);
+    }
+
+    #[test]
+    fn rejects_blocks_inflating_the_utxo_set() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let count = chain.utxo.count_transactions().unwrap();
+
+        // A coinbase minting more than the reward
+        let mut coinbase = chain.coinbase(&chain.miner);
+        coinbase.vout[0].value += 1;
+        coinbase.id = coinbase.hash().unwrap();
+        let block = unchecked_block(&chain, vec![coinbase]);
+        let err = chain.utxo.update(&block).unwrap_err();
+        assert!(err.to_string().contains("inflates"), "{}", err);
+        assert_eq!(chain.utxo.count_transactions().unwrap(), count);
+
+        // A payment creating more than it spends
+        let funding = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![funding.clone()]);
+        let mut tx = chain.spend(&funding, 0, &alice, &alice, 25);
+        tx.vout[0].value = 35;
+        chain.resign(&alice, &mut tx);
+        let block = unchecked_block(&chain, vec![chain.coinbase(&chain.miner), tx]);
+        let err = chain.utxo.update(&block).unwrap_err();
+        assert!(err.to_string().contains("more value"), "{}", err);
+
+        // The payment's input is still unspent
+        assert!(chain
+            .utxo
+            .has_output(&funding.id, OutPoint::Index(0))
+            .unwrap());
+        assert!(chain
+            .utxo
+            .get_outputs(&block.get_transactions()[1].id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_coinbases_hiding_inflation_behind_a_negative_output() {
+        let mut chain = TestChain::new();
+        let balance = chain.utxo.get_balance(&chain.miner).unwrap();
+        let tip = chain.bc().get_tip_hash();
+
+        // The outputs sum up to the reward, the first one alone exceeds it
+        let mut coinbase = chain.coinbase(&chain.miner);
+        let mut negative = coinbase.vout[0].clone();
+        negative.value = -50;
+        coinbase.vout[0].value += 50;
+        coinbase.vout.push(negative);
+        coinbase.id = coinbase.hash().unwrap();
+        let block = unchecked_block(&chain, vec![coinbase]);
+
+        let err = chain.utxo.blockchain.add_block(block.clone()).unwrap_err();
+        assert!(err.to_string().contains("negative output"), "{}", err);
+        assert_eq!(chain.bc().get_tip_hash(), tip);
+        let err = chain.utxo.update(&block).unwrap_err();
+        assert!(err.to_string().contains("negative output"), "{}", err);
+        assert_eq!(chain.utxo.get_balance(&chain.miner).unwrap(), balance);
+    }
+
+    #[test]
+    fn skips_coinbase_outputs_until_they_mature() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let bob = chain.address();
+        let reward = chain.coinbase(&alice).vout[0].value;
+        chain.mine_to(&alice, Vec::new());
+
+        // One block later the reward is in the UTXO set but not spendable
+        chain.mine(Vec::new());
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), reward);
+        let wallet = chain.wallets.get_wallet(&alice).unwrap();
+        let err = Transaction::new_utxo(wallet, &bob, 10, &chain.utxo).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(BlockchainError::InsufficientBalance { have: 0, need: 10 })
+        ));
+
+        chain.mine_empty(COINBASE_MATURITY as usize - 2);
+        let tx = chain.send(&alice, &bob, 10);
+        chain.mine(vec![tx]);
+        assert_eq!(chain.utxo.get_balance(&bob).unwrap(), 10);
+    }
+
+    #[test]
+    fn selects_inputs_according_to_the_strategy() {
+        let params = ChainParams {
+            dust_threshold: 0,
+            ..ChainParams::devnet()
+        };
+        let mut chain = TestChain::with_params(params);
+        chain.mature();
+        let alice = chain.address();
+
+        let miner = chain.wallets.get_wallet(&chain.miner).unwrap();
+        let recipients: Vec<(String, i32)> = [1, 5, 50]
+            .iter()
+            .map(|amount| (alice.clone(), *amount))
+            .collect();
+        let funding = Transaction::new_utxo_multi(miner, &recipients, &chain.utxo).unwrap();
+        chain.mine(vec![funding.clone()]);
+
+        let pub_key_hash = pub_key_hash_from_address(&alice).unwrap();
+        let select = |strategy| {
+            let (accumulated, selected) = chain
+                .utxo
+                .find_spendable_outputs_with(&pub_key_hash, 6, strategy)
+                .unwrap();
+            let mut values: Vec<i32> = selected[&funding.id]
+                .iter()
+                .map(|idx| funding.vout[*idx as usize].value)
+                .collect();
+            values.sort();
+            assert_eq!(accumulated, values.iter().sum::<i32>());
+            values
+        };
+
+        assert_eq!(select(CoinSelection::LargestFirst), vec![50]);
+        assert_eq!(select(CoinSelection::SmallestFirst), vec![1, 5]);
+        assert_eq!(select(CoinSelection::All), vec![1, 5, 50]);
+        assert!(select(CoinSelection::Arbitrary).iter().sum::<i32>() >= 6);
+    }
+
+    #[test]
+    fn sums_the_balance_of_an_address() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 0);
+
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+        let tx = chain.send(&chain.miner, &alice, 12);
+        chain.mine(vec![tx]);
+        assert_eq!(chain.utxo.get_balance(&alice).unwrap(), 42);
+
+        assert!(chain.utxo.get_balance("not-an-address").is_err());
+    }
+
+    #[test]
+    fn discovers_the_funds_of_an_imported_key() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let first = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![first.clone()]);
+        let second = chain.send(&chain.miner, &alice, 12);
+        chain.mine(vec![second.clone()]);
+
+        // Only the secret key is carried over to another wallet database
+        let secret = chain.wallets.get_wallet(&alice).unwrap().secret_key.clone();
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_with_path(dir.path()).unwrap();
+        let imported = wallets.import(&secret).unwrap();
+        let funds = chain
+            .utxo
+            .discover_funds(wallets.get_wallet(&imported).unwrap())
+            .unwrap();
+
+        assert_eq!(funds.address, alice);
+        assert_eq!(funds.balance, 42);
+        let mut outpoints = funds.outpoints;
+        outpoints.sort();
+        let mut expected = vec![(first.id, 0), (second.id, 0)];
+        expected.sort();
+        assert_eq!(outpoints, expected);
+
+        // A key that never received anything has no funds
+        let fresh = wallets.create_wallet();
+        let funds = chain
+            .utxo
+            .discover_funds(wallets.get_wallet(&fresh).unwrap())
+            .unwrap();
+        assert_eq!(funds.balance, 0);
+        assert!(funds.outpoints.is_empty());
+    }
+
+    // Open an empty chain in its own directory, e.g. for a node about to sync
+    fn empty_utxo_set(dir: &tempfile::TempDir) -> UTXOSet {
+        UTXOSet::new(Blockchain::open(dir.path(), ChainParams::devnet()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fast_syncs_from_a_snapshot_matching_a_full_replay() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let bob = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+        let tx = chain.send(&alice, &bob, 12);
+        chain.mine_to(&bob, vec![tx]);
+
+        let best_height = chain.bc().get_best_height().unwrap() as usize;
+        let blocks: Vec<Block> = (0..=best_height)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+        let reward = blocks[best_height].coinbase().unwrap().vout[0].value;
+        let snapshot = chain.utxo.snapshot().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut synced = empty_utxo_set(&dir);
+        synced.fast_sync(blocks, &snapshot).unwrap();
+        assert_eq!(synced.blockchain.get_tip_hash(), chain.bc().get_tip_hash());
+
+        // A full replay of the synced blocks ends with the same balances and UTXO set
+        let balances = |utxo: &UTXOSet| -> Vec<i32> {
+            [&chain.miner, &alice, &bob]
+                .iter()
+                .map(|a| utxo.get_balance(a).unwrap())
+                .collect()
+        };
+        let expected = balances(&chain.utxo);
+        assert_eq!(expected[1..], [18, 12 + reward]);
+        assert_eq!(balances(&synced), expected);
+        let entries = bincode::serialize(&synced.snapshot().unwrap().entries).unwrap();
+        synced.reindex().unwrap();
+        assert_eq!(balances(&synced), expected);
+        assert_eq!(
+            bincode::serialize(&synced.snapshot().unwrap().entries).unwrap(),
+            entries
+        );
+    }
+
+    #[test]
+    fn rejects_snapshots_not_matching_the_commitment_of_their_block() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+        let tx = chain.send(&chain.miner, &alice, 30);
+        chain.mine(vec![tx]);
+
+        let best_height = chain.bc().get_best_height().unwrap() as usize;
+        let blocks: Vec<Block> = (0..=best_height)
+            .map(|height| chain.bc().get_block_by_height(height).unwrap())
+            .collect();
+        let snapshot = chain.utxo.snapshot().unwrap();
+
+        let mut inflated = snapshot.clone();
+        inflated.entries[0].1.outputs[0].value += 1;
+        let mut duplicated = snapshot.clone();
+        duplicated.entries.push(snapshot.entries[0].clone());
+        let stale = UTXOSnapshot {
+            tip_hash: blocks[best_height - 1].get_hash(),
+            entries: snapshot.entries.clone(),
+        };
+
+        // Nothing is stored when the snapshot doesn't match the last block
+        for bad in [inflated, duplicated, stale] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut synced = empty_utxo_set(&dir);
+            assert!(synced.fast_sync(blocks.clone(), &bad).is_err());
+            assert!(synced.blockchain.get_tip_hash().is_empty());
+            assert_eq!(synced.count_transactions().unwrap(), 0);
+        }
+
+        // A snapshot of the tip is adopted over a corrupt UTXO set, a tampered one isn't
+        let mut tampered = snapshot.clone();
+        tampered.entries.remove(0);
+        let dir = tempfile::tempdir().unwrap();
+        let mut synced = empty_utxo_set(&dir);
+        synced.fast_sync(blocks, &snapshot).unwrap();
+        assert!(synced.adopt_snapshot(&tampered).is_err());
+        assert_eq!(synced.get_balance(&alice).unwrap(), 30);
+        synced.blockchain.utxos_db().clear().unwrap();
+        synced.adopt_snapshot(&snapshot).unwrap();
+        assert_eq!(synced.get_balance(&alice).unwrap(), 30);
+    }
+}
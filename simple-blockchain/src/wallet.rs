@@ -1,16 +1,75 @@
-use crate::{errors::Result, utils::hash_pub_key};
-use bitcoincash_addr::{Address, HashType, Scheme};
+use crate::{
+    blockchain::{open_db, DATA_DIR},
+    errors::{Result, StorageError},
+    mnemonic::{from_mnemonic, to_mnemonic},
+    utils::{address_from_pub_key_hash, address_from_pub_key_hash_with_scheme, hash_pub_key},
+};
+use anyhow::anyhow;
+use bitcoincash_addr::Scheme;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
 use crypto::ed25519;
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use log::warn;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sled::transaction::ConflictableTransactionResult;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const SECRET_KEY_LENGTH: usize = 64; // length of an ed25519 secret key of the crypto crate
+const SAVE_ATTEMPTS: usize = 3; // default number of attempts to save the wallets
+const SAVE_RETRY_DELAY: Duration = Duration::from_millis(100); // delay between two attempts
+const LOCK_ATTEMPTS: usize = 5; // number of attempts to open a wallet database locked by another process
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(50); // first delay between two attempts, doubled each time
+const SENDS_TREE: &str = "sends"; // tree of the payments sent from each address
+const ENCRYPTION_TREE: &str = "encryption"; // tree of the encryption parameters of the database
+const SALT_KEY: &str = "salt"; // key of the salt of the key derivation
+const SALT_LENGTH: usize = 16;
+const KEY_CHECK_KEY: &str = "check"; // key of a known value encrypted with the key of the database
+const KEY_CHECK_VALUE: &[u8] = b"wallets"; // known value proving the passphrase is right
+const KDF_ROUNDS: u32 = 100_000; // PBKDF2 rounds deriving the key from the passphrase
+const NONCE_LENGTH: usize = 8; // nonce length of ChaCha20-Poly1305
+const TAG_LENGTH: usize = 16; // authentication tag length of ChaCha20-Poly1305
+pub const DUPLICATE_SEND_WINDOW: u128 = 10 * 60 * 1000; // window in milliseconds in which an identical payment looks duplicate
+
+// SendRecord struct remembers a payment sent from a wallet, to catch accidental double-sends
+// It is only kept locally and has no effect on consensus
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SendRecord {
+    pub sequence: u64, // Sequence number of the payment among the payments of the sender
+    pub to: String,    // Address of the recipient
+    pub amount: i32,   // Amount sent
+    pub time: u128,    // Time of the payment in milliseconds since the Unix Epoch
+}
 
 // Wallet struct contains secret_key and public_key of ed25519
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "WalletKeys")]
 pub struct Wallet {
     pub secret_key: Vec<u8>,
     pub public_key: Vec<u8>,
+    #[serde(skip)]
+    address: String, // Address derived from public_key, cached
+}
+
+// WalletKeys struct is the stored form of a Wallet, the address is derived when loading it
+#[derive(Deserialize)]
+struct WalletKeys {
+    secret_key: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+impl From<WalletKeys> for Wallet {
+    fn from(keys: WalletKeys) -> Self {
+        Self::from_keys(keys.secret_key, keys.public_key)
+    }
 }
 
 impl Wallet {
@@ -20,60 +79,155 @@ impl Wallet {
         let mut key: [u8; 32] = [0; 32];
         OsRng.fill_bytes(&mut key);
 
+        Self::from_seed(&key)
+    }
+
+    // Create a new wallet along with the mnemonic phrase backing up its seed
+    pub fn new_with_mnemonic() -> (Self, String) {
+        let wallet = Self::new();
+        let phrase = wallet.mnemonic();
+        (wallet, phrase)
+    }
+
+    // Restore a wallet from the mnemonic phrase of its seed
+    // phrase: the 24-word BIP39 phrase
+    pub fn from_mnemonic(phrase: &str) -> Result<Self> {
+        Ok(Self::from_seed(&from_mnemonic(phrase)?))
+    }
+
+    // Get the mnemonic phrase of the seed of the wallet
+    pub fn mnemonic(&self) -> String {
+        let mut seed = [0; 32];
+        seed.copy_from_slice(&self.secret_key[..32]);
+        to_mnemonic(&seed)
+    }
+
+    // Create a wallet from a 32-byte seed
+    fn from_seed(seed: &[u8; 32]) -> Self {
         // Generate a pair of secret_key and public_key
-        let (secrect_key, public_key) = ed25519::keypair(&key);
+        let (secrect_key, public_key) = ed25519::keypair(seed);
 
         let secret_key = secrect_key.to_vec();
         let public_key = public_key.to_vec();
 
         // Return a new wallet
+        Self::from_keys(secret_key, public_key)
+    }
+
+    // Create a wallet from its key pair and derive its address
+    fn from_keys(secret_key: Vec<u8>, public_key: Vec<u8>) -> Self {
+        // Hash public_key
+        let mut pub_hash = public_key.clone();
+        hash_pub_key(&mut pub_hash);
+
+        // Encode address (base58 encoding)
+        let address = address_from_pub_key_hash(pub_hash).unwrap();
+
         Self {
             secret_key,
             public_key,
+            address,
         }
     }
 
-    // Get address from public_key
-    pub(crate) fn get_address(&self) -> String {
-        // Hash public_key
+    // Restore a wallet from its secret key
+    // The secret key is the 64-byte key of the crypto crate: the 32-byte seed followed by the public key
+    // secret: the secret key of the wallet
+    pub fn from_secret_key(secret: &[u8]) -> Result<Self> {
+        if secret.len() != SECRET_KEY_LENGTH {
+            return Err(anyhow!(
+                "invalid secret key length: {} (expected {})",
+                secret.len(),
+                SECRET_KEY_LENGTH
+            ));
+        }
+
+        // Derive the key pair from the seed and check it matches the embedded public key
+        let (secret_key, public_key) = ed25519::keypair(&secret[..32]);
+        if secret_key[..] != secret[..] {
+            return Err(anyhow!("secret key doesn't match its public key"));
+        }
+
+        Ok(Self::from_keys(secret_key.to_vec(), public_key.to_vec()))
+    }
+
+    // Get the address of the wallet, derived from public_key when the wallet was created or loaded
+    pub fn get_address(&self) -> String {
+        self.address.clone()
+    }
+
+    // Get the address of the wallet encoded in the given scheme
+    // scheme: Base58 (the default address) or CashAddr
+    pub fn get_address_with_scheme(&self, scheme: Scheme) -> String {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
 
-        // Encode address (base58 encoding)
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
+        address_from_pub_key_hash_with_scheme(pub_hash, scheme).unwrap()
+    }
+}
+
+// Signer trait abstracts producing signatures for transactions
+// It allows the secret key to live outside of the process (e.g. a hardware signer)
+pub trait Signer {
+    // Sign the message and return the signature
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+}
 
-        // Return address
-        address.encode().unwrap()
+// Wallet signs in-process with its ed25519 secret key
+impl Signer for Wallet {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(ed25519::signature(message, &self.secret_key).to_vec())
     }
 }
 
 // Wallets struct contains a HashMap of Wallet
 pub struct Wallets {
     wallets: HashMap<String, Wallet>, // address -> wallet mapping
+    path: PathBuf,                    // path of the wallets database
+    passphrase: Option<String>,       // passphrase of the database, None if it's not encrypted
 }
 
 impl Wallets {
     // Create a new Wallets
     pub fn new() -> Result<Self> {
+        Self::new_with_path(DATA_DIR)
+    }
+
+    // Create a new Wallets keeping its database in the given directory
+    // path: the base directory of the databases
+    pub fn new_with_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_passphrase(path, None)
+    }
+
+    // Create a new Wallets from a database encrypted with a passphrase
+    // passphrase: the passphrase the wallets were saved with
+    pub fn new_encrypted(passphrase: &str) -> Result<Self> {
+        Self::new_encrypted_with_path(DATA_DIR, passphrase)
+    }
+
+    // Create a new Wallets from an encrypted database in the given directory
+    // path: the base directory of the databases
+    // passphrase: the passphrase the wallets were saved with
+    pub fn new_encrypted_with_path<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self> {
+        Self::open_with_passphrase(path, Some(passphrase))
+    }
+
+    // Create a new Wallets from a database in the given directory, encrypted or not
+    // The passphrase is kept, so save_all encrypts the wallets created later with it
+    // path: the base directory of the databases
+    // passphrase: the passphrase of the database, None if it's not encrypted
+    pub fn open_with_passphrase<P: AsRef<Path>>(path: P, passphrase: Option<&str>) -> Result<Self> {
         // Create a new Wallets
         let mut w: Wallets = Self {
             wallets: HashMap::<String, Wallet>::new(),
+            path: path.as_ref().join("wallets"),
+            passphrase: passphrase.map(String::from),
         };
 
         // Load wallets from database
-        let db = sled::open("data/wallets")?;
-
-        for item in db.into_iter() {
-            let i = item?;
-            let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
-            w.wallets.insert(address, wallet);
-        }
+        let db = open_wallet_db(&w.path)?;
+        let key = encryption_key(&db, passphrase)?;
+        w.load(&db, key.as_ref())?;
 
         // Drop database
         drop(db);
@@ -82,6 +236,24 @@ impl Wallets {
         Ok(w)
     }
 
+    // Load the wallets in the database that are not known yet
+    // key: the key the wallets are encrypted with, if the database is encrypted
+    fn load(&mut self, db: &sled::Db, key: Option<&[u8; 32]>) -> Result<()> {
+        for item in db.into_iter() {
+            let i = item?;
+            let address = String::from_utf8(i.0.to_vec())?;
+            if let Entry::Vacant(entry) = self.wallets.entry(address) {
+                let data = match key {
+                    Some(key) => decrypt(key, entry.key(), &i.1)?,
+                    None => i.1.to_vec(),
+                };
+                entry.insert(bincode::deserialize(&data)?);
+            }
+        }
+
+        Ok(())
+    }
+
     // Create a new wallet and return its address
     pub fn create_wallet(&mut self) -> String {
         // Create a new wallet
@@ -95,6 +267,19 @@ impl Wallets {
         address
     }
 
+    // Import a wallet from its secret key and return its address
+    // The wallet still has to be saved with save_all
+    // secret: the secret key of the wallet
+    pub fn import(&mut self, secret: &[u8]) -> Result<String> {
+        let wallet = Wallet::from_secret_key(secret)?;
+        let address = wallet.get_address();
+
+        // Insert the wallet into wallets
+        self.wallets.insert(address.clone(), wallet);
+
+        Ok(address)
+    }
+
     // Get all addresses in wallets
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::new();
@@ -112,19 +297,508 @@ impl Wallets {
         self.wallets.get(address)
     }
 
-    // Save all wallets into database
-    pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+    // Find a payment with the same recipient and amount sent recently from an address
+    // from: the address of the sender
+    // to: the address of the recipient
+    // amount: the amount to send
+    pub fn find_duplicate_send(
+        &self,
+        from: &str,
+        to: &str,
+        amount: i32,
+    ) -> Result<Option<SendRecord>> {
+        let now = now_millis()?;
+        let duplicate = self
+            .load_sends(from)?
+            .into_iter()
+            .rev()
+            .find(|r| r.to == to && r.amount == amount && now - r.time < DUPLICATE_SEND_WINDOW);
+
+        Ok(duplicate)
+    }
+
+    // Remember a payment sent from an address and return its sequence number
+    // Records older than the duplicate window are dropped
+    // from: the address of the sender
+    // to: the address of the recipient
+    // amount: the amount sent
+    pub fn record_send(&self, from: &str, to: &str, amount: i32) -> Result<u64> {
+        let now = now_millis()?;
+        let mut sends = self.load_sends(from)?;
+        let sequence = sends.last().map_or(0, |r| r.sequence + 1);
+
+        sends.retain(|r| now - r.time < DUPLICATE_SEND_WINDOW);
+        sends.push(SendRecord {
+            sequence,
+            to: to.to_string(),
+            amount,
+            time: now,
+        });
+
+        let db = open_wallet_db(&self.path)?;
+        db.open_tree(SENDS_TREE)?
+            .insert(from, bincode::serialize(&sends)?)?;
+        db.flush()?;
+
+        Ok(sequence)
+    }
+
+    // Load the recent payments sent from an address
+    fn load_sends(&self, from: &str) -> Result<Vec<SendRecord>> {
+        let db = open_wallet_db(&self.path)?;
+        let sends = match db.open_tree(SENDS_TREE)?.get(from)? {
+            Some(data) => bincode::deserialize(&data)?,
+            None => Vec::new(),
+        };
+
+        Ok(sends)
+    }
+
+    // Save all wallets into database and return the addresses that were newly saved
+    // Wallets saved by other instances are kept and merged into this one
+    // The wallets are encrypted with the passphrase the database was opened with, if any
+    pub fn save_all(&mut self) -> Result<Vec<String>> {
+        self.save_all_with_retry(SAVE_ATTEMPTS)
+    }
+
+    // Save all wallets into database encrypted with a passphrase and return the addresses
+    // that were newly saved
+    // The key is derived from the passphrase with PBKDF2, and each wallet is encrypted
+    // with ChaCha20-Poly1305 bound to its address
+    // The passphrase is kept for the next calls of save_all
+    // passphrase: the passphrase to encrypt the wallets with, the same for the whole database
+    pub fn save_all_encrypted(&mut self, passphrase: &str) -> Result<Vec<String>> {
+        let saved = self.save_with_retry(SAVE_ATTEMPTS, Some(passphrase))?;
+        self.passphrase = Some(passphrase.to_string());
+        Ok(saved)
+    }
+
+    // Save all wallets into database, retrying when an attempt fails
+    // Each attempt is all-or-nothing and wallets already in the database are skipped,
+    // so a failed save can be re-run safely
+    // attempts: the maximum number of attempts, at least one is made
+    pub fn save_all_with_retry(&mut self, attempts: usize) -> Result<Vec<String>> {
+        let passphrase = self.passphrase.clone();
+        self.save_with_retry(attempts, passphrase.as_deref())
+    }
+
+    fn save_with_retry(
+        &mut self,
+        attempts: usize,
+        passphrase: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let mut attempt = 1;
+        loop {
+            match self.try_save_all(passphrase) {
+                Ok(saved) => return Ok(saved),
+                Err(e) if attempt < attempts => {
+                    warn!(
+                        "failed to save wallets (attempt {}/{}): {}",
+                        attempt, attempts, e
+                    );
+                    thread::sleep(SAVE_RETRY_DELAY);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Save all wallets into database in a single attempt
+    fn try_save_all(&mut self, passphrase: Option<&str>) -> Result<Vec<String>> {
+        let db = open_wallet_db(&self.path)?;
+        let key = encryption_key(&db, passphrase)?;
 
+        // Check the passphrase against the saved wallets before adding new ones
+        self.load(&db, key.as_ref())?;
+
+        let mut entries = Vec::new();
         for (address, wallet) in &self.wallets {
             let data = bincode::serialize(wallet)?;
-            db.insert(address, data)?;
+            let data = match &key {
+                Some(key) => encrypt(key, address, &data),
+                None => data,
+            };
+            entries.push((address.clone(), data));
         }
 
+        // Insert the wallets in a single transaction without overwriting existing entries
+        let saved = db.transaction(
+            |tx_db| -> ConflictableTransactionResult<Vec<String>, sled::Error> {
+                let mut saved = Vec::new();
+                for (address, data) in &entries {
+                    if tx_db.get(address)?.is_none() {
+                        tx_db.insert(address.as_bytes(), data.clone())?;
+                        saved.push(address.clone());
+                    }
+                }
+                Ok(saved)
+            },
+        )?;
+
+        // Merge the wallets saved by other instances
+        self.load(&db, key.as_ref())?;
+
         // Flush and drop database
         db.flush()?;
         drop(db);
 
-        Ok(())
+        Ok(saved)
+    }
+}
+
+// Open the wallet database, waiting with a backoff while another process holds its lock
+// Other instances only keep the database open briefly, so the lock is usually released soon
+// path: the path of the wallet database
+fn open_wallet_db(path: &Path) -> Result<sled::Db> {
+    let mut delay = LOCK_RETRY_DELAY;
+    for attempt in 1..=LOCK_ATTEMPTS {
+        match open_db(path) {
+            Err(e) if matches!(e.downcast_ref(), Some(StorageError::Locked(_))) => {
+                if attempt < LOCK_ATTEMPTS {
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+            result => return result,
+        }
+    }
+
+    Err(StorageError::WalletLocked(path.display().to_string()).into())
+}
+
+// Get the current time in milliseconds since the Unix Epoch
+fn now_millis() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis())
+}
+
+// Get the key the wallets of a database are encrypted with
+// A salt is created when an empty database is encrypted for the first time, along with a known
+// value encrypted with the key, so a wrong passphrase is rejected even before any wallet is saved
+// db: the wallets database
+// passphrase: the passphrase of the database, None if it's not encrypted
+fn encryption_key(db: &sled::Db, passphrase: Option<&str>) -> Result<Option<[u8; 32]>> {
+    let tree = db.open_tree(ENCRYPTION_TREE)?;
+    let salt = tree.get(SALT_KEY)?;
+
+    let passphrase = match (passphrase, &salt) {
+        (None, None) => return Ok(None),
+        (None, Some(_)) => {
+            return Err(anyhow!(
+                "wallet database is encrypted, a passphrase is required"
+            ))
+        }
+        (Some(_), None) if !db.is_empty() => {
+            return Err(anyhow!(
+                "wallet database is not encrypted, it can't be opened with a passphrase"
+            ))
+        }
+        (Some(passphrase), _) => passphrase,
+    };
+
+    let salt = match salt {
+        Some(salt) => salt.to_vec(),
+        None => {
+            let mut salt = vec![0; SALT_LENGTH];
+            OsRng.fill_bytes(&mut salt);
+            tree.insert(SALT_KEY, salt.clone())?;
+            salt
+        }
+    };
+
+    let mut mac = Hmac::new(Sha256::new(), passphrase.as_bytes());
+    let mut key = [0; 32];
+    pbkdf2(&mut mac, &salt, KDF_ROUNDS, &mut key);
+
+    match tree.get(KEY_CHECK_KEY)? {
+        Some(check) => {
+            if decrypt(&key, KEY_CHECK_KEY, &check)? != KEY_CHECK_VALUE {
+                return Err(anyhow!("wrong passphrase for the wallet database"));
+            }
+        }
+        None => {
+            // Databases encrypted before the check existed are checked against a wallet first
+            if let Some(item) = db.iter().next() {
+                let (address, data) = item?;
+                decrypt(&key, &String::from_utf8(address.to_vec())?, &data)?;
+            }
+            tree.insert(KEY_CHECK_KEY, encrypt(&key, KEY_CHECK_KEY, KEY_CHECK_VALUE))?;
+        }
+    }
+
+    Ok(Some(key))
+}
+
+// Encrypt a serialized wallet, the result is nonce || tag || ciphertext
+// key: the key of the database
+// address: the address of the wallet, authenticated along with the wallet
+// data: the serialized wallet
+fn encrypt(key: &[u8; 32], address: &str, data: &[u8]) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut tag = [0; TAG_LENGTH];
+    let mut ciphertext = vec![0; data.len()];
+    ChaCha20Poly1305::new(key, &nonce, address.as_bytes()).encrypt(data, &mut ciphertext, &mut tag);
+
+    [&nonce[..], &tag[..], &ciphertext[..]].concat()
+}
+
+// Decrypt a wallet encrypted with encrypt
+// key: the key of the database
+// address: the address of the wallet
+// data: nonce || tag || ciphertext
+fn decrypt(key: &[u8; 32], address: &str, data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < NONCE_LENGTH + TAG_LENGTH {
+        return Err(anyhow!("encrypted wallet {} is truncated", address));
+    }
+
+    let (nonce, rest) = data.split_at(NONCE_LENGTH);
+    let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+
+    let mut plaintext = vec![0; ciphertext.len()];
+    if !ChaCha20Poly1305::new(key, nonce, address.as_bytes()).decrypt(
+        ciphertext,
+        &mut plaintext,
+        tag,
+    ) {
+        return Err(anyhow!("wrong passphrase for the wallet database"));
+    }
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_wallet_db, Signer, Wallet, Wallets, ENCRYPTION_TREE};
+    use crate::errors::{Result, StorageError};
+    use crate::test_utils::TestChain;
+    use crate::utils::{address_from_pub_key_hash, hash_pub_key};
+    use std::cell::RefCell;
+
+    // MockSigner signs with a wallet and records every message it was asked to sign
+    struct MockSigner<'a> {
+        wallet: &'a Wallet,
+        messages: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl Signer for MockSigner<'_> {
+        fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+            self.messages.borrow_mut().push(message.to_vec());
+            self.wallet.sign(message)
+        }
+    }
+
+    #[test]
+    fn signs_every_input_through_the_signer() {
+        let mut chain = TestChain::new();
+        chain.mature();
+        let alice = chain.address();
+
+        // Two coinbase outputs are needed to pay more than one reward
+        let mut tx = chain.send(&chain.miner, &alice, 150);
+        assert_eq!(tx.vin.len(), 2);
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+        }
+
+        let signer = MockSigner {
+            wallet: chain.wallets.get_wallet(&chain.miner).unwrap(),
+            messages: RefCell::new(Vec::new()),
+        };
+        chain.bc().sign_transaction(&mut tx, &signer).unwrap();
+
+        // One hash per input, each committing to a different input
+        let messages = signer.messages.into_inner();
+        assert_eq!(messages.len(), 2);
+        assert_ne!(messages[0], messages[1]);
+        assert!(chain.bc().verify_transaction(&tx).unwrap());
+    }
+
+    #[test]
+    fn concurrent_instances_keep_each_others_wallets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = Wallets::new_with_path(dir.path()).unwrap();
+        let mut second = Wallets::new_with_path(dir.path()).unwrap();
+
+        let a = first.create_wallet();
+        let b = second.create_wallet();
+        assert_eq!(first.save_all().unwrap(), vec![a.clone()]);
+        assert_eq!(second.save_all().unwrap(), vec![b.clone()]);
+
+        // The second save merged the wallet saved by the first instance
+        assert!(second.get_wallet(&a).is_some());
+        let c = first.create_wallet();
+        first.save_all().unwrap();
+
+        let mut addresses = Wallets::new_with_path(dir.path())
+            .unwrap()
+            .get_all_address();
+        addresses.sort();
+        let mut expected = vec![a, b, c];
+        expected.sort();
+        assert_eq!(addresses, expected);
+    }
+
+    #[test]
+    fn reports_a_wallet_db_held_by_another_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = open_wallet_db(&dir.path().join("wallets")).unwrap();
+
+        let err = Wallets::new_with_path(dir.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(StorageError::WalletLocked(_))
+        ));
+        assert!(
+            err.to_string()
+                .starts_with("wallet db is locked by another process"),
+            "{}",
+            err
+        );
+
+        drop(lock);
+        assert!(Wallets::new_with_path(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn retries_a_failed_save_without_duplicating_wallets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_with_path(dir.path()).unwrap();
+        let a = wallets.create_wallet();
+        assert_eq!(wallets.save_all().unwrap(), vec![a.clone()]);
+
+        // Another process holds the database while two more wallets are saved
+        let mut added = vec![wallets.create_wallet(), wallets.create_wallet()];
+        added.sort();
+        // The handle of the last save may take a moment to release the lock
+        let lock = open_wallet_db(&dir.path().join("wallets")).unwrap();
+        let err = wallets.save_all_with_retry(1).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(StorageError::WalletLocked(_))
+        ));
+        drop(lock);
+        let stored = Wallets::new_with_path(dir.path()).unwrap();
+        assert_eq!(stored.get_all_address(), vec![a]);
+
+        // The retry saves the remaining wallets, and saving again changes nothing
+        let mut saved = wallets.save_all().unwrap();
+        saved.sort();
+        assert_eq!(saved, added);
+        assert!(wallets.save_all().unwrap().is_empty());
+        let stored = Wallets::new_with_path(dir.path()).unwrap();
+        assert_eq!(stored.get_all_address().len(), 3);
+    }
+
+    #[test]
+    fn restores_a_wallet_from_its_secret_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_with_path(dir.path()).unwrap();
+        let address = wallets.create_wallet();
+        let secret = wallets.get_wallet(&address).unwrap().secret_key.clone();
+
+        // Another wallet database imports the same key under the same address
+        let other = tempfile::tempdir().unwrap();
+        let mut restored = Wallets::new_with_path(other.path()).unwrap();
+        assert_eq!(restored.import(&secret).unwrap(), address);
+        let wallet = restored.get_wallet(&address).unwrap();
+        assert_eq!(
+            wallet.public_key,
+            wallets.get_wallet(&address).unwrap().public_key
+        );
+
+        // Bad keys are errors, not panics
+        assert!(Wallet::from_secret_key(&secret[..32]).is_err());
+        assert!(Wallet::from_secret_key(&[]).is_err());
+        let mut mismatched = secret.clone();
+        mismatched[63] ^= 1;
+        assert!(restored.import(&mismatched).is_err());
+    }
+
+    #[test]
+    fn restores_a_wallet_from_its_mnemonic() {
+        let (wallet, phrase) = Wallet::new_with_mnemonic();
+        assert_eq!(phrase.split_whitespace().count(), 24);
+
+        let restored = Wallet::from_mnemonic(&phrase).unwrap();
+        assert_eq!(restored.get_address(), wallet.get_address());
+        assert_eq!(restored.secret_key, wallet.secret_key);
+
+        // Unknown words and missing words are reported
+        let mut words: Vec<&str> = phrase.split_whitespace().collect();
+        words[0] = "blockchain";
+        assert!(Wallet::from_mnemonic(&words.join(" ")).is_err());
+        assert!(Wallet::from_mnemonic(&words[1..].join(" ")).is_err());
+    }
+
+    #[test]
+    fn caches_the_address_derived_from_the_public_key() {
+        let wallet = Wallet::new();
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let computed = address_from_pub_key_hash(pub_key_hash).unwrap();
+        assert_eq!(wallet.get_address(), computed);
+
+        // The address isn't stored, it is derived again when a wallet is loaded
+        let data = bincode::serialize(&wallet).unwrap();
+        let loaded: Wallet = bincode::deserialize(&data).unwrap();
+        assert_eq!(loaded.get_address(), computed);
+        assert_eq!(loaded, wallet);
+        assert!(!data
+            .windows(computed.len())
+            .any(|w| w == computed.as_bytes()));
+    }
+
+    #[test]
+    fn rejects_a_wrong_passphrase_before_any_wallet_is_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        Wallets::new_encrypted_with_path(dir.path(), "right").unwrap();
+
+        // Only the salt and the key check are stored, yet the wrong passphrase is caught
+        let err = Wallets::new_encrypted_with_path(dir.path(), "wrong")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("wrong passphrase"), "{}", err);
+
+        // Saving with the wrong passphrase fails, so the owner isn't locked out
+        let mut wallets = Wallets::new_encrypted_with_path(dir.path(), "right").unwrap();
+        let address = wallets.create_wallet();
+        assert!(wallets.save_all_encrypted("wrong").is_err());
+        wallets.save_all().unwrap();
+        let reopened = Wallets::new_encrypted_with_path(dir.path(), "right").unwrap();
+        assert!(reopened.get_wallet(&address).is_some());
+        assert!(Wallets::new_encrypted_with_path(dir.path(), "wrong").is_err());
+    }
+
+    // Check if any value stored in the wallet database contains the bytes
+    fn stored_values_contain(path: &std::path::Path, bytes: &[u8]) -> bool {
+        let db = open_wallet_db(&path.join("wallets")).unwrap();
+        let trees = [(*db).clone(), db.open_tree(ENCRYPTION_TREE).unwrap()];
+        trees.iter().flat_map(|tree| tree.iter()).any(|item| {
+            let (_, value) = item.unwrap();
+            value.windows(bytes.len()).any(|w| w == bytes)
+        })
+    }
+
+    #[test]
+    fn keeps_secret_keys_out_of_the_encrypted_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_encrypted_with_path(dir.path(), "secret").unwrap();
+        let address = wallets.create_wallet();
+        wallets.save_all().unwrap();
+        let secret_key = wallets.get_wallet(&address).unwrap().secret_key.clone();
+        drop(wallets);
+        assert!(!stored_values_contain(dir.path(), &secret_key));
+
+        // The same wallet saved without a passphrase is stored in the clear
+        let plain = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new_with_path(plain.path()).unwrap();
+        wallets.import(&secret_key).unwrap();
+        wallets.save_all().unwrap();
+        drop(wallets);
+        assert!(stored_values_contain(plain.path(), &secret_key));
     }
 }